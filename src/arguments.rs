@@ -1,11 +1,22 @@
 use clap::Parser;
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 pub struct Arguments {
+    /// Where to listen, unless the config file declares listeners.
     #[clap(default_value = "127.0.0.1")]
     pub ip_address: IpAddr,
 
+    /// The plaintext port, unless the config file declares listeners.
     #[clap(default_value = "6991")]
     pub port: u16,
+
+    /// Path to a TOML configuration file.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Read a password from stdin, print its hash for use in the config, and exit.
+    #[clap(long)]
+    pub mkpasswd: bool,
 }
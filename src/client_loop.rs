@@ -1,14 +1,34 @@
 use iris_lib::{
     connect::{ConnectionError, ConnectionManager, ConnectionRead, ConnectionWrite},
-    ircs::{client::Client, IrcCommand, IrcMessage, IrcServer, WriteMessage},
+    ircs::{bans::BanKind, client::Client, password_job::PasswordJob, IrcServer},
+    types::{Command, Message},
 };
 use std::sync::mpsc::Receiver;
+use std::time::Duration;
 use tokio::task::JoinHandle;
 
 use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
+/// Runs the password checks and hashes a handler returned without holding
+/// the server lock, then lets the server act on each result.
+async fn run_password_jobs(
+    jobs: impl IntoIterator<Item = PasswordJob>,
+    irc_server: &Arc<Mutex<IrcServer>>,
+    conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+    id: &str,
+) {
+    for job in jobs {
+        let finished = job.run().await;
+        irc_server
+            .lock()
+            .await
+            .finish_password_job(finished, conn_write, id)
+            .await;
+    }
+}
+
 async fn handle_client(
     mut conn_read: ConnectionRead,
     conn_write: ConnectionWrite,
@@ -16,22 +36,31 @@ async fn handle_client(
 ) {
     println!("New connection from {}", conn_read.id());
 
-    let mut client = Client {
-        nick: String::new(),
-        realname: None,
-        channels: Vec::new(),
-    };
+    let id = conn_read.id();
+    let info = conn_read.info();
+    let mut client = Client::new(info.socket_addr.ip(), info.host());
+    client.modes.secure = info.secure;
+    client.certfp = info.certfp.clone();
+    client.purpose = info.purpose;
+    client.uid = info.uid;
+    irc_server.lock().await.add_client(id.clone(), client);
     let mut registered = false;
 
     let conn_write = Arc::new(Mutex::new(conn_write));
 
-    loop {
+    let quit_reason = loop {
         println!("Waiting for message...");
         let message = match conn_read.read_message().await {
             Ok(message) => message,
             Err(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
                 println!("Lost connection.");
-                break;
+                break "Connection closed";
+            }
+            Err(ConnectionError::MessageTooLong) => {
+                let mut conn_write = Arc::clone(&conn_write);
+                let irc_server = irc_server.lock().await;
+                irc_server.handle_input_too_long(&mut conn_write, &id).await;
+                continue;
             }
             Err(_) => {
                 println!("Invalid message received... ignoring message.");
@@ -44,80 +73,238 @@ async fn handle_client(
             conn_read.id(),
             message
         );
-        let irc_message = IrcMessage::parse(&message);
-        if let Some(irc_message) = irc_message {
-            let mut conn_write = Arc::clone(&conn_write);
-            match irc_message.command {
-                IrcCommand::NICK => {
-                    if !registered {
-                        let mut irc_server = irc_server.lock().await;
-                        irc_server
-                            .handle_nick_command(irc_message, &mut conn_write, &mut client)
-                            .await;
-                    }
+        let mut conn_write = Arc::clone(&conn_write);
+        let parsed = Message::parse(&message);
+        let command_name = match &parsed {
+            Ok(message) => message.command.name().to_string(),
+            Err(error) => match error.command() {
+                Some(command) => command.to_string(),
+                None => {
+                    println!("Invalid IRC message: {error}");
+                    continue;
                 }
-
-                IrcCommand::USER => {
-                    if !registered && !client.nick.is_empty() {
-                        let mut irc_server = irc_server.lock().await;
-                        irc_server
-                            .handle_user_command(irc_message, &mut conn_write, &mut client)
-                            .await;
-                        if !client.nick.is_empty() && client.realname.is_some() {
-                            registered = true;
-                            irc_server.welcome_client(&client, &mut conn_write).await;
-                            irc_server.add_connection(client.nick.clone(), conn_write);
-                        }
-                    }
-                }
-                IrcCommand::PING => {
-                    let irc_server = irc_server.lock().await;
+            },
+        };
+        if !registered && !Command::allowed_before_registration(&command_name) {
+            let irc_server = irc_server.lock().await;
+            match &parsed {
+                Ok(Message {
+                    command: Command::Unknown { command, .. },
+                    ..
+                }) => {
                     irc_server
-                        .handle_ping_command(irc_message, &mut conn_write)
+                        .handle_unknown_command(command, &mut conn_write, &id)
                         .await;
                 }
-                // handle privmsg
-                IrcCommand::PRIVMSG => {
-                    if registered {
-                        let mut irc_server = irc_server.lock().await;
-                        let from_nick = client.nick.clone();
-                        irc_server
-                            .handle_privmsg_command(irc_message, &mut conn_write, &from_nick)
-                            .await;
-                        print!("PRIVMSG is be handled well: ");
-                        drop(irc_server);
-                    }
-                }
-                IrcCommand::QUIT => {
-                    let mut irc_server = irc_server.lock().await;
+                _ => {
                     irc_server
-                        .handle_quit_command(irc_message, &mut conn_write)
+                        .handle_unregistered_command(&mut conn_write, &id)
                         .await;
-                    break;
-                }
-                IrcCommand::JOIN => {
-                    if registered {
-                        let mut irc_server = irc_server.lock().await;
-                        irc_server
-                            .handle_join_command(irc_message, &mut conn_write, &mut client)
-                            .await;
-                    }
                 }
-                IrcCommand::PART => {
-                    if registered {
-                        let mut irc_server = irc_server.lock().await;
-                        irc_server
-                            .handle_part_command(irc_message, &mut conn_write, &mut client)
-                            .await;
-                    }
+            }
+            continue;
+        }
+        let message = match parsed {
+            Ok(message) => message,
+            Err(error) => {
+                let irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_message_error(error, &mut conn_write, &id)
+                    .await;
+                continue;
+            }
+        };
+        match message.command {
+            Command::Nick(nick_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_nick_command(nick_msg.nick, &mut conn_write, &id)
+                    .await;
+            }
+
+            Command::User(user_msg) => {
+                let has_nick = irc_server
+                    .lock()
+                    .await
+                    .client(&id)
+                    .is_some_and(|client| !client.nick.is_empty());
+                if !registered && has_nick {
+                    let mut irc_server = irc_server.lock().await;
+                    irc_server.handle_user_command(user_msg, &id).await;
+                    registered = irc_server.try_register(&id, &mut conn_write).await;
                 }
-                _ => {
-                    println!("Unhandled command");
+            }
+            Command::Cap(cap_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_cap_command(cap_msg, &mut conn_write, &id)
+                    .await;
+                // CAP END may be what registration was waiting for.
+                if !registered {
+                    registered = irc_server.try_register(&id, &mut conn_write).await;
                 }
             }
-        } else {
-            println!("Invalid IRC message");
+            Command::Authenticate(param) => {
+                let job = irc_server
+                    .lock()
+                    .await
+                    .handle_authenticate_command(param, &mut conn_write, &id)
+                    .await;
+                run_password_jobs(job, &irc_server, &mut conn_write, &id).await;
+            }
+            Command::Webirc(webirc_msg) => {
+                let job = irc_server
+                    .lock()
+                    .await
+                    .handle_webirc_command(webirc_msg, &mut conn_write, &id)
+                    .await;
+                run_password_jobs(job, &irc_server, &mut conn_write, &id).await;
+            }
+            Command::Ping(origin) => {
+                let irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_ping_command(origin, &mut conn_write)
+                    .await;
+            }
+            Command::Pong(_) => {}
+            // handle privmsg
+            Command::PrivMsg(_) | Command::Notice(_) | Command::TagMsg(_) => {
+                let jobs = irc_server
+                    .lock()
+                    .await
+                    .handle_privmsg_command(message, &mut conn_write, &id)
+                    .await;
+                print!("PRIVMSG is be handled well: ");
+                run_password_jobs(jobs, &irc_server, &mut conn_write, &id).await;
+            }
+            Command::Quit(quit_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_quit_command(quit_msg, &mut conn_write, &id)
+                    .await;
+                return;
+            }
+            Command::Join(join_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_join_command(join_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Part(part_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_part_command(part_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Oper(oper_msg) => {
+                let job = irc_server
+                    .lock()
+                    .await
+                    .handle_oper_command(oper_msg, &mut conn_write, &id)
+                    .await;
+                run_password_jobs(job, &irc_server, &mut conn_write, &id).await;
+            }
+            Command::Rehash => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server.handle_rehash_command(&mut conn_write, &id).await;
+            }
+            Command::Kill(kill_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_kill_command(kill_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Wallops(text) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_wallops_command(text, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Globops(text) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_globops_command(text, &mut conn_write, &id)
+                    .await;
+            }
+            Command::KLine(ban_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_ban_command(BanKind::KLine, ban_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::DLine(ban_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_ban_command(BanKind::DLine, ban_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::UnKLine(mask) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_unban_command(BanKind::KLine, mask, &mut conn_write, &id)
+                    .await;
+            }
+            Command::UnDLine(mask) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_unban_command(BanKind::DLine, mask, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Mode(mode_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_mode_command(mode_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Topic(topic_msg) => {
+                let mut irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_topic_command(topic_msg, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Who(mask) => {
+                let irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_who_command(mask, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Whois(target) => {
+                let irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_whois_command(target, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Names(channel) => {
+                let irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_names_command(channel, &mut conn_write, &id)
+                    .await;
+            }
+            Command::Unknown { command, .. } => {
+                let irc_server = irc_server.lock().await;
+                irc_server
+                    .handle_unknown_command(&command, &mut conn_write, &id)
+                    .await;
+            }
+            _ => {
+                println!("Unhandled command");
+            }
         }
+    };
+
+    // Covers dropped connections; a client removed by QUIT or KILL is already gone.
+    irc_server
+        .lock()
+        .await
+        .disconnect(&id, quit_reason, &conn_write)
+        .await;
+}
+/// Renames clients that did not identify to their registered nick in time.
+async fn enforce_registered_nicks(irc_server: Arc<Mutex<IrcServer>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        irc_server.lock().await.enforce_nick_deadlines().await;
     }
 }
 
@@ -127,6 +314,7 @@ pub async fn handle_client_loop(
     rx: &Receiver<String>,
 ) {
     let mut tasks = Vec::<JoinHandle<()>>::new();
+    tokio::spawn(enforce_registered_nicks(irc_server.clone()));
 
     loop {
         let (conn_read, conn_write) = connection_manager
@@ -0,0 +1,321 @@
+// src/lib/config.rs
+//! Server configuration, read from a TOML file given on the command line.
+use crate::ircs::oper::Privilege;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Everything the server reads from its configuration file.
+/// Every section is optional, so an empty file is a valid configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(rename = "oper_class")]
+    pub oper_classes: Vec<OperClass>,
+    #[serde(rename = "oper")]
+    pub opers: Vec<OperBlock>,
+    /// Where bans and other state that must survive a restart are kept.
+    /// Without it that state only lives in memory.
+    pub data_dir: Option<PathBuf>,
+    pub services: ServicesConfig,
+    /// Without it the server only accepts plaintext connections.
+    pub tls: Option<TlsConfig>,
+    /// Where clients connect. Without any, the server listens on the
+    /// address and port given on the command line, and on the TLS port if
+    /// TLS is configured.
+    #[serde(rename = "listen")]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(rename = "listen_unix")]
+    pub unix_listeners: Vec<UnixListenerConfig>,
+    /// Local users who are logged in to an account as soon as they register
+    /// over a Unix socket.
+    #[serde(rename = "local_account")]
+    pub local_accounts: Vec<LocalAccount>,
+    #[serde(rename = "webirc")]
+    pub webirc_gateways: Vec<WebircGateway>,
+}
+
+/// A web chat gateway allowed to use WEBIRC, which makes the users it
+/// connects for show up with their own host and IP. The password is a hash
+/// produced by `iris --mkpasswd`.
+/// For example:
+/// ```toml
+/// [[webirc]]
+/// name = "kiwiirc"
+/// password = "$pbkdf2-sha256$100000$...$..."
+/// hosts = ["192.0.2.10", "2001:db8::/64"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebircGateway {
+    pub name: String,
+    pub password: String,
+    /// The addresses or CIDR ranges the gateway connects from.
+    pub hosts: Vec<String>,
+}
+
+/// One address and port to accept connections on. An IPv6 address also
+/// accepts IPv4 clients, so `::` listens on every address of both kinds.
+/// For example:
+/// ```toml
+/// [[listen]]
+/// address = "::"
+/// port = 6697
+/// tls = true
+///
+/// [[listen]]
+/// address = "127.0.0.1"
+/// port = 7000
+/// only = "webirc"
+///
+/// [[listen]]
+/// address = "::"
+/// port = 8097
+/// tls = true
+/// websocket = true
+///
+/// [[listen]]
+/// address = "10.0.0.5"
+/// port = 6667
+/// proxy = true
+/// trusted_proxies = ["10.0.0.0/24"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ListenerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Whether clients must use TLS, with the certificate from `[tls]`.
+    #[serde(default)]
+    pub tls: bool,
+    /// Whether clients speak IRC over WebSocket, as web clients do.
+    #[serde(default)]
+    pub websocket: bool,
+    /// Whether connections come through a load balancer that sends a PROXY
+    /// protocol header with the client's real address.
+    #[serde(default)]
+    pub proxy: bool,
+    /// The addresses or CIDR ranges a PROXY header is believed from. Other
+    /// connections to a `proxy` listener are dropped.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Reserves the listener for one kind of connection.
+    #[serde(default)]
+    pub only: Option<ListenerPurpose>,
+}
+
+impl ListenerConfig {
+    pub fn new(address: IpAddr, port: u16, tls: bool) -> Self {
+        Self {
+            address,
+            port,
+            tls,
+            websocket: false,
+            proxy: false,
+            trusted_proxies: Vec::new(),
+            only: None,
+        }
+    }
+}
+
+/// A Unix socket for clients on the same host, such as bots.
+/// For example:
+/// ```toml
+/// [[listen_unix]]
+/// path = "/run/iris/iris.sock"
+/// mode = 0o660
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct UnixListenerConfig {
+    pub path: PathBuf,
+    /// The permissions of the socket file, which decide who may connect.
+    #[serde(default = "UnixListenerConfig::default_mode")]
+    pub mode: u32,
+}
+
+impl UnixListenerConfig {
+    fn default_mode() -> u32 {
+        0o660
+    }
+}
+
+/// Logs the local user `uid` in to `account` when it connects over a Unix
+/// socket, without any password. The account must already be registered.
+/// For example:
+/// ```toml
+/// [[local_account]]
+/// uid = 1001
+/// account = "deploybot"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LocalAccount {
+    pub uid: u32,
+    pub account: String,
+}
+
+/// What a reserved listener is for. Clients that connect to it anyway are
+/// turned away when they try to register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerPurpose {
+    /// Web gateways, which identify themselves with WEBIRC.
+    Webirc,
+    /// Links from other servers.
+    Servers,
+}
+
+/// The certificate TLS listeners present. REHASH reads the certificate and
+/// key again. The port is only used when no `[[listen]]` blocks are given.
+/// For example:
+/// ```toml
+/// [tls]
+/// cert = "/etc/iris/fullchain.pem"
+/// key = "/etc/iris/privkey.pem"
+/// port = 6697
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+    #[serde(default = "TlsConfig::default_port")]
+    pub port: u16,
+}
+
+impl TlsConfig {
+    fn default_port() -> u16 {
+        6697
+    }
+}
+
+/// Settings for NickServ and the other services.
+/// For example:
+/// ```toml
+/// [services]
+/// nick_grace_seconds = 60
+/// memo_quota = 20
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServicesConfig {
+    /// How long a client using a registered nick has to identify before
+    /// its nick is changed.
+    pub nick_grace_seconds: u64,
+    /// The most memos MemoServ keeps for one account.
+    pub memo_quota: usize,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            nick_grace_seconds: 60,
+            memo_quota: 20,
+        }
+    }
+}
+
+/// A named set of privileges shared by several operator accounts.
+/// For example:
+/// ```toml
+/// [[oper_class]]
+/// name = "netadmin"
+/// privileges = ["kill", "rehash", "override"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperClass {
+    pub name: String,
+    #[serde(default)]
+    pub privileges: Vec<Privilege>,
+}
+
+/// Credentials for one operator account. The password is a hash produced by
+/// `iris --mkpasswd`, never the plain text.
+/// For example:
+/// ```toml
+/// [[oper]]
+/// name = "alice"
+/// password = "$pbkdf2-sha256$100000$...$..."
+/// class = "netadmin"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperBlock {
+    pub name: String,
+    pub password: String,
+    pub class: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read config: {err}"),
+            ConfigError::Parse(err) => write!(f, "could not parse config: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        toml::from_str(&contents).map_err(ConfigError::Parse)
+    }
+
+    pub fn oper_class(&self, name: &str) -> Option<&OperClass> {
+        self.oper_classes.iter().find(|class| class.name == name)
+    }
+
+    /// The listeners to open. `address` and `port` are the plaintext one to
+    /// use when the configuration declares none.
+    pub fn listeners(&self, address: IpAddr, port: u16) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let mut listeners = vec![ListenerConfig::new(address, port, false)];
+        if let Some(tls) = &self.tls {
+            listeners.push(ListenerConfig::new(address, tls.port, true));
+        }
+        listeners
+    }
+
+    /// The account the local user `uid` is logged in to, if any.
+    pub fn local_account(&self, uid: u32) -> Option<&str> {
+        self.local_accounts
+            .iter()
+            .find(|local| local.uid == uid)
+            .map(|local| local.account.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners() {
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let config: Config =
+            toml::from_str("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\n").unwrap();
+        assert_eq!(
+            config.listeners(localhost, 6667),
+            [
+                ListenerConfig::new(localhost, 6667, false),
+                ListenerConfig::new(localhost, 6697, true),
+            ]
+        );
+
+        let config: Config =
+            toml::from_str("[[listen]]\naddress = \"::\"\nport = 7000\nonly = \"webirc\"\n")
+                .unwrap();
+        let listeners = config.listeners(localhost, 6667);
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address, IpAddr::from([0u16; 8]));
+        assert_eq!(listeners[0].only, Some(ListenerPurpose::Webirc));
+    }
+}
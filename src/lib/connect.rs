@@ -1,70 +1,421 @@
+use crate::config::{ListenerConfig, ListenerPurpose, UnixListenerConfig};
+use crate::ircs::bans::{cidr_contains, BanList};
+use crate::proxy;
+use crate::tls::{certfp, TlsContext};
+use crate::types::{ErrorType, NumericReply};
+use crate::websocket;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     error::Error,
     fmt::{Debug, Display},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
     sync::Arc,
+    time::Duration,
 };
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpListener,
+    sync::{mpsc, Mutex, Notify},
 };
 
+/// The longest line RFC 1459 allows, including its `\r\n` but not any
+/// message tags.
+const MAX_LINE_LEN: usize = 512;
+/// The longest tag section IRCv3 allows, including the `@` and the space
+/// after it.
+const MAX_TAGS_LEN: usize = 8191;
+/// How long a client has to send its PROXY header and finish the TLS and
+/// WebSocket handshakes, each.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Anything a client can be connected over, such as a `TcpStream` or a TLS
+/// stream wrapping one.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+/// A stream of whichever kind the listener it came from accepts.
+pub type BoxedStream = Box<dyn Stream>;
+
+/// What is known about a connection's transport once it is accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Identifies the connection, and the client on it, to the server.
+    pub id: String,
+    /// The address bans and password throttles apply to. Clients on a Unix
+    /// socket have the loopback address.
+    pub socket_addr: SocketAddr,
+    /// Whether the connection is over TLS.
+    pub secure: bool,
+    /// The SHA-256 fingerprint of the certificate the client presented, if
+    /// any.
+    pub certfp: Option<String>,
+    /// What the listener the client connected to is reserved for, if
+    /// anything.
+    pub purpose: Option<ListenerPurpose>,
+    /// The user id of a client on a Unix socket, as the kernel reports it.
+    pub uid: Option<u32>,
+}
+
+impl ConnectionInfo {
+    pub fn plaintext(socket_addr: SocketAddr) -> Self {
+        Self {
+            id: socket_addr.to_string(),
+            socket_addr,
+            secure: false,
+            certfp: None,
+            purpose: None,
+            uid: None,
+        }
+    }
+
+    /// A client on a Unix socket, run by the user `uid`.
+    pub fn local(uid: u32) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Self {
+            id: format!("unix:{id}"),
+            uid: Some(uid),
+            ..Self::plaintext(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        }
+    }
+
+    /// The client's host as other users see it.
+    pub fn host(&self) -> String {
+        if self.uid.is_some() {
+            return "localhost".to_string();
+        }
+        ip_host(self.socket_addr.ip())
+    }
+}
+
+/// `ip` written as a host.
+pub fn ip_host(ip: IpAddr) -> String {
+    let ip = ip.to_string();
+    // A parameter starting with ':' would swallow the rest of the line.
+    if ip.starts_with(':') {
+        format!("0{ip}")
+    } else {
+        ip
+    }
+}
+
+type Connection = (ConnectionRead, ConnectionWrite);
+
+/// Accepts clients on every listener. Each listener runs in a task of its
+/// own, so a slow handshake never holds up other clients.
 pub struct ConnectionManager {
-    listener: TcpListener,
-    reader: Option<ReadHalf<TcpStream>>,
-    writer: Option<WriteHalf<TcpStream>>,
+    /// Consulted for D-lines before a connection is handed to the server.
+    bans: Arc<Mutex<BanList>>,
+    incoming: mpsc::Receiver<Connection>,
+    sender: mpsc::Sender<Connection>,
 }
 
 impl ConnectionManager {
-    pub async fn launch(address: impl Into<IpAddr>, port: u16) -> Self {
-        let address = address.into();
-        let listener = TcpListener::bind((address, port))
-            .await
-            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
-
+    /// A manager with no listeners yet.
+    pub fn new(bans: Arc<Mutex<BanList>>) -> Self {
+        let (sender, incoming) = mpsc::channel(16);
         Self {
-            listener,
-            reader: None,
-            writer: None,
+            bans,
+            incoming,
+            sender,
+        }
+    }
+
+    /// Starts accepting connections as `config` describes. TLS listeners
+    /// present the certificate `tls` holds at the time of each handshake.
+    pub fn listen(
+        &mut self,
+        config: &ListenerConfig,
+        tls: Option<Arc<TlsContext>>,
+    ) -> std::io::Result<()> {
+        let socket = bind(SocketAddr::new(config.address, config.port))?;
+        let listener = Arc::new(Listener {
+            tls,
+            websocket: config.websocket,
+            trusted_proxies: config.proxy.then(|| config.trusted_proxies.clone()),
+            purpose: config.only,
+            bans: Arc::clone(&self.bans),
+        });
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            loop {
+                match socket.accept().await {
+                    Ok((socket, addr)) => {
+                        // IPv4 clients of a dual-stack listener show up as
+                        // `::ffff:a.b.c.d`.
+                        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                        let info = ConnectionInfo::plaintext(addr);
+                        listener.spawn_accept(Box::new(socket), info, sender.clone());
+                    }
+                    Err(err) => {
+                        eprintln!("[WARN] failed to connect to client: {err}");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Starts accepting connections on the Unix socket `config` describes,
+    /// replacing any socket left at its path by an earlier run.
+    #[cfg(unix)]
+    pub fn listen_unix(&mut self, config: &UnixListenerConfig) -> std::io::Result<()> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+        let path = &config.path;
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
         }
+        // Clients are logged in by uid, so no one may connect before the
+        // socket has its mode. It is bound in a directory only this process
+        // can enter, and only linked into place once the mode is set.
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => std::path::Path::new("."),
+        };
+        let staging = parent.join(format!(".iris-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&staging);
+        std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+        let staged = staging.join("s");
+        let bound = UnixListener::bind(&staged).and_then(|socket| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(config.mode))?;
+            // Unlike a rename, this fails rather than replace another file.
+            std::fs::hard_link(&staged, path)?;
+            Ok(socket)
+        });
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&staging);
+        let socket = bound?;
+        let listener = Arc::new(Listener {
+            tls: None,
+            websocket: false,
+            trusted_proxies: None,
+            purpose: None,
+            bans: Arc::clone(&self.bans),
+        });
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            loop {
+                let accepted = socket
+                    .accept()
+                    .await
+                    .and_then(|(socket, _)| Ok((socket.peer_cred()?, socket)));
+                match accepted {
+                    Ok((credentials, socket)) => {
+                        let info = ConnectionInfo::local(credentials.uid());
+                        listener.spawn_accept(Box::new(socket), info, sender.clone());
+                    }
+                    Err(err) => {
+                        eprintln!("[WARN] failed to connect to local client: {err}");
+                    }
+                }
+            }
+        });
+        Ok(())
     }
 
     pub async fn accept_new_connection(&mut self) -> (ConnectionRead, ConnectionWrite) {
-        loop {
-            match self.listener.accept().await {
-                Ok((socket, addr)) => {
-                    let (reader, writer) = split(socket);
-                    self.reader = Some(reader);
-                    self.writer = Some(writer);
-
-                    let shared_reader = Arc::new(Mutex::new(self.reader.take().unwrap()));
-                    let shared_writer = Arc::new(Mutex::new(self.writer.take().unwrap()));
-
-                    return (
-                        ConnectionRead::from_reader(shared_reader.clone(), addr),
-                        ConnectionWrite::from_writer(shared_writer, addr),
-                    );
+        self.incoming
+            .recv()
+            .await
+            .expect("the manager keeps a sender, so the channel never closes")
+    }
+}
+
+/// A listening socket on `addr`. IPv6 sockets also accept IPv4 clients.
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// How the connections accepted on one socket are set up.
+struct Listener {
+    tls: Option<Arc<TlsContext>>,
+    websocket: bool,
+    /// Where PROXY headers are accepted from, if the listener expects them.
+    trusted_proxies: Option<Vec<String>>,
+    purpose: Option<ListenerPurpose>,
+    bans: Arc<Mutex<BanList>>,
+}
+
+impl Listener {
+    /// Sets up a newly accepted socket in a task of its own, and hands it to
+    /// the server through `sender` unless it is turned away.
+    fn spawn_accept(
+        self: &Arc<Self>,
+        stream: BoxedStream,
+        info: ConnectionInfo,
+        sender: mpsc::Sender<Connection>,
+    ) {
+        let listener = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Some(connection) = listener.accept(stream, info).await {
+                let _ = sender.send(connection).await;
+            }
+        });
+    }
+
+    async fn accept(
+        &self,
+        mut stream: BoxedStream,
+        mut info: ConnectionInfo,
+    ) -> Option<Connection> {
+        if let Some(trusted) = &self.trusted_proxies {
+            let proxy = info.socket_addr;
+            if !trusted.iter().any(|mask| cidr_contains(mask, proxy.ip())) {
+                eprintln!("[WARN] rejected connection from untrusted proxy {proxy}");
+                return None;
+            }
+            let header = proxy::read_header(&mut stream);
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, header).await {
+                Ok(Ok(client)) => {
+                    // D-lines and K-lines apply to the client, not the proxy.
+                    // The id stays that of the proxy's connection, which is
+                    // unique.
+                    if let Some(client) = client {
+                        info.socket_addr = client;
+                    }
+                }
+                Ok(Err(err)) => {
+                    eprintln!("[WARN] connection from proxy {proxy}: {err}");
+                    return None;
+                }
+                Err(_) => {
+                    eprintln!("[WARN] PROXY header from {proxy} timed out");
+                    return None;
                 }
-                Err(err) => {
-                    eprintln!("[WARN] failed to connect to client: {err}");
+            }
+        }
+        let addr = info.socket_addr;
+        info.purpose = self.purpose;
+
+        // D-lines are checked before any handshake, so a banned address
+        // costs no TLS or WebSocket work. The client is only told why when
+        // it speaks plain IRC; anything else could not read the reply yet.
+        let dline = self.bans.lock().await.find_dline(addr.ip());
+        if let Some(ban) = dline {
+            eprintln!(
+                "[WARN] rejected D-lined connection from {addr}: {}",
+                ban.reason
+            );
+            if self.tls.is_none() && !self.websocket {
+                let text = format!("{} ({})", ErrorType::YoureBannedCreep, ban.reason);
+                let reply = NumericReply::new(ErrorType::YoureBannedCreep as u16, "", &[], &text);
+                let error = format!(
+                    "ERROR :Closing Link: {} (D-lined: {})\r\n",
+                    addr.ip(),
+                    ban.reason
+                );
+                let _ = stream.write_all(format!("{reply}{error}").as_bytes()).await;
+            }
+            let _ = stream.shutdown().await;
+            return None;
+        }
+
+        let mut stream: BoxedStream = match &self.tls {
+            None => stream,
+            Some(tls) => {
+                let handshake = tls.acceptor().accept(stream);
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                    Ok(Ok(stream)) => {
+                        info.secure = true;
+                        info.certfp = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .map(|cert| certfp(cert));
+                        Box::new(stream)
+                    }
+                    Ok(Err(err)) => {
+                        eprintln!("[WARN] TLS handshake with {addr} failed: {err}");
+                        return None;
+                    }
+                    Err(_) => {
+                        eprintln!("[WARN] TLS handshake with {addr} timed out");
+                        return None;
+                    }
                 }
             }
+        };
+        if self.websocket {
+            let handshake = websocket::accept(stream);
+            stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(stream)) => Box::new(stream),
+                Ok(Err(err)) => {
+                    eprintln!("[WARN] WebSocket handshake with {addr} failed: {err}");
+                    return None;
+                }
+                Err(_) => {
+                    eprintln!("[WARN] WebSocket handshake with {addr} timed out");
+                    return None;
+                }
+            };
         }
+
+        Some(connection(stream, info))
     }
 }
 
-pub struct ConnectionRead {
-    reader: Arc<Mutex<ReadHalf<TcpStream>>>,
-    socket_addr: SocketAddr,
-    buffer: Box<[u8; 512]>,
+/// Splits `stream` into the halves a client's task reads from and the
+/// server writes to.
+pub fn connection<S: Stream>(
+    stream: S,
+    info: ConnectionInfo,
+) -> (ConnectionRead<S>, ConnectionWrite<S>) {
+    let (reader, writer) = split(stream);
+    let closed = Arc::new(Notify::new());
+    let info = Arc::new(info);
+    (
+        ConnectionRead::from_reader(Arc::new(Mutex::new(reader)), info.clone(), closed.clone()),
+        ConnectionWrite::from_writer(Arc::new(Mutex::new(writer)), info, closed),
+    )
+}
+
+pub struct ConnectionRead<S = BoxedStream> {
+    reader: Arc<Mutex<ReadHalf<S>>>,
+    info: Arc<ConnectionInfo>,
+    /// Signalled by `ConnectionWrite::close`, so a pending read gives up.
+    closed: Arc<Notify>,
+    buffer: Box<[u8; MAX_TAGS_LEN + MAX_LINE_LEN]>,
     buflen: usize,
+    /// Set after a line was too long, until the end of that line is skipped.
+    discarding: bool,
+}
+
+pub struct ConnectionWrite<S = BoxedStream> {
+    writer: Arc<Mutex<WriteHalf<S>>>,
+    info: Arc<ConnectionInfo>,
+    closed: Arc<Notify>,
+}
+
+impl<S> Clone for ConnectionWrite<S> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: Arc::clone(&self.writer),
+            info: Arc::clone(&self.info),
+            closed: Arc::clone(&self.closed),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct ConnectionWrite {
-    writer: Arc<Mutex<WriteHalf<TcpStream>>>,
-    socket_addr: SocketAddr,
+impl<S> Debug for ConnectionWrite<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionWrite")
+            .field("info", &self.info)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -83,13 +434,19 @@ impl Display for ConnectionError {
 
 impl Error for ConnectionError {}
 
-impl ConnectionRead {
-    fn from_reader(reader: Arc<Mutex<ReadHalf<TcpStream>>>, socket_addr: SocketAddr) -> Self {
+impl<S: Stream> ConnectionRead<S> {
+    fn from_reader(
+        reader: Arc<Mutex<ReadHalf<S>>>,
+        info: Arc<ConnectionInfo>,
+        closed: Arc<Notify>,
+    ) -> Self {
         Self {
             reader,
-            socket_addr,
-            buffer: Box::from([0; 512]),
+            info,
+            closed,
+            buffer: Box::new([0; MAX_TAGS_LEN + MAX_LINE_LEN]),
             buflen: 0,
+            discarding: false,
         }
     }
 
@@ -101,13 +458,68 @@ impl ConnectionRead {
             .map(|(index, _)| index)
     }
 
+    /// How long the line at the start of the buffer may be. Only a line that
+    /// starts with message tags may pass 512 bytes, and only by the length
+    /// of its tag section.
+    fn line_limit(&self) -> usize {
+        if self.buffer[..self.buflen].first() != Some(&b'@') {
+            return MAX_LINE_LEN;
+        }
+        let tag_section = &self.buffer[..self.buflen.min(MAX_TAGS_LEN)];
+        match tag_section.iter().position(|&byte| byte == b' ') {
+            Some(space) => space + 1 + MAX_LINE_LEN,
+            None => MAX_TAGS_LEN,
+        }
+    }
+
+    /// Drops the first `len` bytes of the buffer.
+    fn consume(&mut self, len: usize) {
+        self.buffer.copy_within(len..self.buflen, 0);
+        self.buflen -= len;
+    }
+
     pub async fn read_message(&mut self) -> Result<String, ConnectionError> {
         use std::io::ErrorKind;
 
-        if self.buffer_crlf().is_none() {
+        loop {
+            if let Some(end) = self.buffer_crlf() {
+                // end + '\r' + '\n'
+                let after_crlf = end + 2;
+                if std::mem::take(&mut self.discarding) {
+                    // The tail of a line that was already rejected.
+                    self.consume(after_crlf);
+                    continue;
+                }
+                if after_crlf > self.line_limit() {
+                    self.consume(after_crlf);
+                    return Err(ConnectionError::MessageTooLong);
+                }
+
+                let bytes = Vec::from(&self.buffer[0..end]);
+                self.consume(after_crlf);
+                let message =
+                    String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
+                return Ok(message);
+            }
+
+            if self.discarding {
+                // Keep a trailing '\r' in case its '\n' is in the next read.
+                let keep = usize::from(self.buffer[..self.buflen].last() == Some(&b'\r'));
+                self.consume(self.buflen - keep);
+            } else if self.buflen >= self.line_limit() {
+                // Clear out their data, and the rest of the line when it arrives.
+                self.buflen = 0;
+                self.discarding = true;
+                return Err(ConnectionError::MessageTooLong);
+            }
+
             let n_bytes = loop {
                 let mut locked_reader = self.reader.lock().await;
-                break match locked_reader.read(&mut self.buffer[self.buflen..]).await {
+                let read = tokio::select! {
+                    read = locked_reader.read(&mut self.buffer[self.buflen..]) => read,
+                    _ = self.closed.notified() => return Err(ConnectionError::ConnectionClosed),
+                };
+                break match read {
                     Ok(0) => return Err(ConnectionError::ConnectionClosed),
                     Ok(n_bytes) => n_bytes,
                     Err(err) => {
@@ -122,41 +534,36 @@ impl ConnectionRead {
 
             self.buflen += n_bytes;
         }
-
-        let end = self.buffer_crlf().ok_or_else(|| {
-            // Clear out their data...
-            self.buflen = 0;
-            ConnectionError::MessageTooLong
-        })?;
-
-        let bytes = Vec::from(&self.buffer[0..end]);
-
-        // end + '\r' + '\n'
-        let after_crlf = end + 2;
-
-        self.buffer.copy_within(after_crlf..self.buflen, 0);
-        self.buflen -= after_crlf;
-
-        let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
-
-        Ok(message)
     }
 
     pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+        self.info.id.clone()
+    }
+
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
     }
 }
 
-impl ConnectionWrite {
-    fn from_writer(writer: Arc<Mutex<WriteHalf<TcpStream>>>, socket_addr: SocketAddr) -> Self {
+impl<S: Stream> ConnectionWrite<S> {
+    fn from_writer(
+        writer: Arc<Mutex<WriteHalf<S>>>,
+        info: Arc<ConnectionInfo>,
+        closed: Arc<Notify>,
+    ) -> Self {
         Self {
             writer,
-            socket_addr,
+            info,
+            closed,
         }
     }
 
     pub fn socket_addr(&self) -> std::net::SocketAddr {
-        self.socket_addr
+        self.info.socket_addr
+    }
+
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
     }
     pub async fn write_message(&mut self, message: &str) -> Result<(), ConnectionError> {
         let mut locked_writer = self.writer.lock().await;
@@ -171,6 +578,89 @@ impl ConnectionWrite {
     }
 
     pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+        self.info.id.clone()
+    }
+
+    /// Shuts the connection down from the server's side. The matching
+    /// `ConnectionRead` stops waiting and reports `ConnectionClosed`.
+    pub async fn close(&mut self) {
+        let _ = self.writer.lock().await.shutdown().await;
+        self.closed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircs::bans::{Ban, BanKind};
+
+    #[tokio::test]
+    async fn test_read_over_any_stream() {
+        let (client, server) = tokio::io::duplex(64);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6667));
+        let (mut conn_read, mut conn_write) = connection(server, ConnectionInfo::plaintext(addr));
+        let (mut client_read, mut client_write) = split(client);
+
+        client_write.write_all(b"NICK a\r\nNICK").await.unwrap();
+        client_write.write_all(b" b\r\n").await.unwrap();
+        assert_eq!(conn_read.read_message().await, Ok("NICK a".to_string()));
+        assert_eq!(conn_read.read_message().await, Ok("NICK b".to_string()));
+
+        conn_write.write_message("PING :x\r\n").await.unwrap();
+        let mut reply = [0; 9];
+        client_read.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"PING :x\r\n");
+
+        conn_write.close().await;
+        assert_eq!(
+            conn_read.read_message().await,
+            Err(ConnectionError::ConnectionClosed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dline_before_handshake() {
+        let ban = Ban {
+            kind: BanKind::DLine,
+            mask: "192.0.2.0/24".to_string(),
+            reason: "spam".to_string(),
+            set_by: "alice".to_string(),
+            expires_at: None,
+        };
+        let bans = Arc::new(Mutex::new(BanList::new(vec![ban], None)));
+        let addr = SocketAddr::from(([192, 0, 2, 1], 50000));
+        for websocket in [false, true] {
+            let listener = Listener {
+                tls: None,
+                websocket,
+                trusted_proxies: None,
+                purpose: None,
+                bans: Arc::clone(&bans),
+            };
+            let (mut client, server) = tokio::io::duplex(1024);
+            // The client never starts a handshake, so only a check made
+            // before it can finish in time.
+            let accept = listener.accept(Box::new(server), ConnectionInfo::plaintext(addr));
+            let accepted = tokio::time::timeout(Duration::from_secs(1), accept).await;
+            assert!(matches!(accepted, Ok(None)));
+
+            let mut sent = String::new();
+            client.read_to_string(&mut sent).await.unwrap();
+            if websocket {
+                assert_eq!(sent, "");
+            } else {
+                assert!(sent.starts_with(":iris-server 465 "));
+                assert!(sent.ends_with("ERROR :Closing Link: 192.0.2.1 (D-lined: spam)\r\n"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hosts() {
+        let v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 6667));
+        assert_eq!(ConnectionInfo::plaintext(v6).host(), "0::1");
+        let local = ConnectionInfo::local(1000);
+        assert_eq!(local.host(), "localhost");
+        assert_ne!(local.id, ConnectionInfo::local(1000).id);
     }
 }
@@ -0,0 +1,214 @@
+// src/lib/ircs/accounts.rs
+//! Registered accounts that clients log in to with SASL.
+use crate::ircs::casemap::irc_eq;
+use crate::password::{PasswordHashes, ScramCredentials};
+use crate::storage::{record, Change, SharedStorage, StorageError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub name: String,
+    /// A hash from `password::hash_password`.
+    pub password: String,
+    /// `ScramCredentials` for SASL SCRAM-SHA-256. Accounts saved before
+    /// these were kept get them the next time they log in with their
+    /// password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scram: Option<String>,
+    /// SHA-256 fingerprints of TLS client certificates that may log in to
+    /// this account with SASL EXTERNAL, as lowercase hex.
+    #[serde(default)]
+    pub certfps: Vec<String>,
+    /// Unix timestamp of when the account was registered.
+    pub registered_at: i64,
+}
+
+impl Account {
+    /// A new account with the password behind `hashes`, registered now.
+    pub fn new(name: &str, hashes: PasswordHashes) -> Self {
+        Self {
+            name: name.to_string(),
+            password: hashes.hash,
+            scram: Some(hashes.scram.to_string()),
+            certfps: Vec::new(),
+            registered_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    pub fn scram_credentials(&self) -> Option<ScramCredentials> {
+        self.scram.as_deref().and_then(ScramCredentials::parse)
+    }
+}
+
+/// Every registered account, kept in `storage` as it changes. Account
+/// names compare with the same casemapping as nicknames.
+#[derive(Debug, Default)]
+pub struct AccountStore {
+    accounts: Vec<Account>,
+    storage: Option<SharedStorage>,
+}
+
+impl AccountStore {
+    /// A store holding `accounts`, as loaded from `storage`. Without
+    /// storage, accounts only live in memory.
+    pub fn new(accounts: Vec<Account>, storage: Option<SharedStorage>) -> Self {
+        Self { accounts, storage }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| irc_eq(&account.name, name))
+    }
+
+    /// Adds `account`, returning false if its name is already taken.
+    pub fn add(&mut self, account: Account) -> Result<bool, StorageError> {
+        if self.get(&account.name).is_some() {
+            return Ok(false);
+        }
+        record(self.storage.as_ref(), Change::PutAccount(account.clone()))?;
+        self.accounts.push(account);
+        Ok(true)
+    }
+
+    /// Deletes the account `name`, returning whether there was one.
+    pub fn remove(&mut self, name: &str) -> Result<bool, StorageError> {
+        if self.get(name).is_none() {
+            return Ok(false);
+        }
+        let change = Change::RemoveAccount {
+            name: name.to_string(),
+        };
+        record(self.storage.as_ref(), change)?;
+        self.accounts.retain(|account| !irc_eq(&account.name, name));
+        Ok(true)
+    }
+
+    /// Replaces the password of the account `name` with the one behind
+    /// `hashes`, returning whether there is such an account.
+    pub fn set_password(
+        &mut self,
+        name: &str,
+        hashes: PasswordHashes,
+    ) -> Result<bool, StorageError> {
+        let Some(account) = self
+            .accounts
+            .iter_mut()
+            .find(|account| irc_eq(&account.name, name))
+        else {
+            return Ok(false);
+        };
+        let changed = Account {
+            password: hashes.hash,
+            scram: Some(hashes.scram.to_string()),
+            ..account.clone()
+        };
+        record(self.storage.as_ref(), Change::PutAccount(changed.clone()))?;
+        *account = changed;
+        Ok(true)
+    }
+
+    /// The account `name`, if its password hash is still `hash`, the one a
+    /// password was just checked against. An account without SCRAM keys
+    /// gets `scram`, derived from that password, since logging in is the
+    /// only time the server sees it. If they cannot be saved, the account
+    /// goes without them until the next login.
+    pub fn logged_in(
+        &mut self,
+        name: &str,
+        hash: &str,
+        scram: Option<ScramCredentials>,
+    ) -> Option<&Account> {
+        let account = self
+            .accounts
+            .iter_mut()
+            .find(|account| irc_eq(&account.name, name))
+            .filter(|account| account.password == hash)?;
+        if let (None, Some(scram)) = (&account.scram, scram) {
+            let upgraded = Account {
+                scram: Some(scram.to_string()),
+                ..account.clone()
+            };
+            if record(self.storage.as_ref(), Change::PutAccount(upgraded.clone())).is_ok() {
+                *account = upgraded;
+            }
+        }
+        Some(account)
+    }
+
+    /// The account that trusts the client certificate with fingerprint
+    /// `certfp`.
+    pub fn find_by_certfp(&self, certfp: &str) -> Option<&Account> {
+        self.accounts.iter().find(|account| {
+            account
+                .certfps
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(certfp))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::verify_password;
+
+    #[test]
+    fn test_account_lookup() {
+        let mut store = AccountStore::default();
+        assert!(store
+            .add(Account {
+                certfps: vec!["ABCD".to_string()],
+                ..Account::new("Bot[1]", PasswordHashes::new("hunter2"))
+            })
+            .unwrap());
+        assert!(!store
+            .add(Account::new("bot{1}", PasswordHashes::new("hunter3")))
+            .unwrap());
+
+        let hash = store.get("BOT{1}").unwrap().password.clone();
+        assert!(verify_password("hunter2", &hash));
+        assert!(store.logged_in("bot{1}", &hash, None).is_some());
+        assert!(store
+            .logged_in("bot[1]", "$pbkdf2-sha256$1$AA==$AA==", None)
+            .is_none());
+        assert!(store.logged_in("nobody", &hash, None).is_none());
+        assert_eq!(
+            store
+                .find_by_certfp("abcd")
+                .map(|account| account.name.as_str()),
+            Some("Bot[1]")
+        );
+    }
+
+    #[test]
+    fn test_scram_upgrade() {
+        let mut store = AccountStore::default();
+        let hashes = PasswordHashes::new("hunter2");
+        store
+            .add(Account {
+                scram: None,
+                ..Account::new("old", hashes.clone())
+            })
+            .unwrap();
+        assert_eq!(store.get("old").unwrap().scram_credentials(), None);
+
+        let other = PasswordHashes::new("hunter3");
+        assert!(store
+            .logged_in("old", &other.hash, Some(other.scram))
+            .is_none());
+        assert_eq!(store.get("old").unwrap().scram, None);
+        let scram = hashes.scram.clone();
+        assert!(store.logged_in("old", &hashes.hash, Some(scram)).is_some());
+        assert_eq!(
+            store.get("old").unwrap().scram_credentials(),
+            Some(hashes.scram)
+        );
+
+        // Keys that are already there stay.
+        let kept = store.get("old").unwrap().scram.clone();
+        let again = ScramCredentials::new("hunter2");
+        store.logged_in("old", &hashes.hash, Some(again));
+        assert_eq!(store.get("old").unwrap().scram, kept);
+    }
+}
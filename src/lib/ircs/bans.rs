@@ -0,0 +1,214 @@
+// src/lib/ircs/bans.rs
+//! Server-wide bans: K-lines match `user@host` masks at registration,
+//! D-lines match IP addresses or CIDR ranges as soon as a connection arrives.
+use crate::ircs::casemap::{irc_eq, irc_lowercase};
+use crate::storage::{record, Change, SharedStorage, StorageError};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BanKind {
+    KLine,
+    DLine,
+}
+
+impl std::fmt::Display for BanKind {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            BanKind::KLine => write!(fmt, "K-line"),
+            BanKind::DLine => write!(fmt, "D-line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub kind: BanKind,
+    /// `user@host` for a K-line, an address or CIDR range for a D-line.
+    pub mask: String,
+    pub reason: String,
+    pub set_by: String,
+    /// Unix timestamp after which the ban no longer applies.
+    pub expires_at: Option<i64>,
+}
+
+impl Ban {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether this K-line covers a client with the given username and host.
+    pub fn matches_user(&self, username: &str, host: &str) -> bool {
+        self.kind == BanKind::KLine && wildcard_match(&self.mask, &format!("{username}@{host}"))
+    }
+
+    /// Whether this D-line covers `ip`.
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        self.kind == BanKind::DLine && cidr_contains(&self.mask, ip)
+    }
+}
+
+/// Every active K-line and D-line, kept in `storage` as they change.
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: Vec<Ban>,
+    storage: Option<SharedStorage>,
+}
+
+impl BanList {
+    /// A list holding `bans`, as loaded from `storage`. Without storage,
+    /// bans only live in memory.
+    pub fn new(bans: Vec<Ban>, storage: Option<SharedStorage>) -> Self {
+        Self { bans, storage }
+    }
+
+    /// Forgets expired bans. One that cannot be removed from storage is
+    /// forgotten anyway, since it no longer applies; it is pruned again
+    /// after the next restart.
+    fn prune_expired(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        let (expired, active) = std::mem::take(&mut self.bans)
+            .into_iter()
+            .partition::<Vec<_>, _>(|ban| ban.is_expired(now));
+        self.bans = active;
+        for ban in expired {
+            let change = Change::RemoveBan {
+                kind: ban.kind,
+                mask: ban.mask,
+            };
+            let _ = record(self.storage.as_ref(), change);
+        }
+    }
+
+    /// Adds `ban`, replacing any existing ban of the same kind on the same mask.
+    pub fn add(&mut self, ban: Ban) -> Result<(), StorageError> {
+        record(self.storage.as_ref(), Change::PutBan(ban.clone()))?;
+        self.bans
+            .retain(|existing| existing.kind != ban.kind || !irc_eq(&existing.mask, &ban.mask));
+        self.bans.push(ban);
+        Ok(())
+    }
+
+    /// Removes the ban of `kind` on `mask`, returning whether there was one.
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> Result<bool, StorageError> {
+        let matches = |ban: &Ban| ban.kind == kind && irc_eq(&ban.mask, mask);
+        if !self.bans.iter().any(matches) {
+            return Ok(false);
+        }
+        let change = Change::RemoveBan {
+            kind,
+            mask: mask.to_string(),
+        };
+        record(self.storage.as_ref(), change)?;
+        self.bans.retain(|ban| !matches(ban));
+        Ok(true)
+    }
+
+    pub fn find_kline(&mut self, username: &str, host: &str) -> Option<Ban> {
+        self.prune_expired();
+        self.bans
+            .iter()
+            .find(|ban| ban.matches_user(username, host))
+            .cloned()
+    }
+
+    pub fn find_dline(&mut self, ip: IpAddr) -> Option<Ban> {
+        self.prune_expired();
+        self.bans.iter().find(|ban| ban.matches_ip(ip)).cloned()
+    }
+}
+
+/// Matches `text` against a glob `pattern` where `*` is any run of characters
+/// and `?` is any single character. Comparison ignores case under the
+/// RFC 1459 casemapping, as nicks do.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().map(irc_lowercase).collect::<Vec<_>>();
+    let text = text.chars().map(irc_lowercase).collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether `ip` falls inside `mask`, which is either a single address or a
+/// CIDR range such as `192.0.2.0/24`.
+pub fn cidr_contains(mask: &str, ip: IpAddr) -> bool {
+    let (address, prefix) = match mask.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse::<u32>().ok()),
+        None => (mask, None),
+    };
+    let Ok(network) = address.parse::<IpAddr>() else {
+        return false;
+    };
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & netmask == u32::from(ip) & netmask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let netmask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & netmask == u128::from(ip) & netmask
+        }
+        _ => false,
+    }
+}
+
+/// Whether `mask` is something a D-line can hold.
+pub fn valid_cidr(mask: &str) -> bool {
+    let (address, prefix) = match mask.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (mask, None),
+    };
+    let Ok(address) = address.parse::<IpAddr>() else {
+        return false;
+    };
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    prefix.is_none_or(|prefix| prefix.parse::<u32>().is_ok_and(|prefix| prefix <= max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*@192.0.2.*", "bob@192.0.2.7"));
+        assert!(wildcard_match("B?b@*", "bob@example.com"));
+        assert!(!wildcard_match("alice@*", "bob@example.com"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("nick[1]!*@*", "NICK{1}!u@h"));
+        assert!(wildcard_match("*\\away", "bob|AWAY"));
+        assert!(!wildcard_match("nick[1]", "nick(1)"));
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let ip = "192.0.2.7".parse().unwrap();
+        assert!(cidr_contains("192.0.2.0/24", ip));
+        assert!(cidr_contains("192.0.2.7", ip));
+        assert!(!cidr_contains("192.0.3.0/24", ip));
+        assert!(cidr_contains("0.0.0.0/0", ip));
+        assert!(cidr_contains(
+            "2001:db8::/32",
+            "2001:db8::1".parse().unwrap()
+        ));
+        assert!(!valid_cidr("192.0.2.0/33"));
+    }
+}
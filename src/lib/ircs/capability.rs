@@ -0,0 +1,197 @@
+// src/lib/ircs/capability.rs
+//! IRCv3 capabilities the server offers, and how far each connection has
+//! got through `CAP` negotiation.
+use std::collections::HashSet;
+
+/// The `CAP LS` version that added capability values, multi-line replies
+/// and implicit `cap-notify`.
+pub const CAP_VERSION_302: u32 = 302;
+
+/// One capability the server offers, with the value it shows in
+/// `CAP LS 302`, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capability {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Capability {
+    /// The capability as a `CAP LS` token, with its value only for clients
+    /// that asked for version 302 or later.
+    pub fn token(&self, version: u32) -> String {
+        match &self.value {
+            Some(value) if version >= CAP_VERSION_302 => format!("{}={value}", self.name),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+/// Where a connection is in capability negotiation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CapState {
+    /// The client has not sent `CAP LS` or `CAP REQ` before registering.
+    #[default]
+    None,
+    /// Registration is held until the client sends `CAP END`.
+    Negotiating,
+    /// The client sent `CAP END`.
+    Ended,
+}
+
+/// Every capability the server currently offers, in the order `CAP LS`
+/// lists them. Each IRCv3 feature registers its capability here.
+#[derive(Debug, Clone)]
+pub struct CapabilityRegistry {
+    capabilities: Vec<Capability>,
+}
+
+impl Default for CapabilityRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            capabilities: Vec::new(),
+        };
+        registry.add("cap-notify", None);
+        registry.add("message-tags", None);
+        registry
+    }
+}
+
+impl CapabilityRegistry {
+    pub fn get(&self, name: &str) -> Option<&Capability> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.name == name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Offers `name`, or changes its value. Returns whether clients need to
+    /// be told, which is when the capability is new or its value changed.
+    pub fn add(&mut self, name: &str, value: Option<&str>) -> bool {
+        let value = value.map(str::to_string);
+        match self
+            .capabilities
+            .iter_mut()
+            .find(|capability| capability.name == name)
+        {
+            Some(capability) if capability.value == value => false,
+            Some(capability) => {
+                capability.value = value;
+                true
+            }
+            None => {
+                self.capabilities.push(Capability {
+                    name: name.to_string(),
+                    value,
+                });
+                true
+            }
+        }
+    }
+
+    /// Stops offering `name`. Returns whether it was offered.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.capabilities.len();
+        self.capabilities
+            .retain(|capability| capability.name != name);
+        self.capabilities.len() != before
+    }
+
+    /// The `CAP LS` tokens for a client that asked for `version`.
+    pub fn ls_tokens(&self, version: u32) -> Vec<String> {
+        self.capabilities
+            .iter()
+            .map(|capability| capability.token(version))
+            .collect()
+    }
+
+    /// Applies a `CAP REQ` to the capabilities a client has `enabled`.
+    /// The request is all or nothing: if any capability in it is not
+    /// offered, nothing changes and this returns false.
+    pub fn apply_request(&self, enabled: &mut HashSet<String>, request: &[String]) -> bool {
+        let changes = request
+            .iter()
+            .map(|token| match token.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (token.as_str(), true),
+            })
+            .collect::<Vec<_>>();
+        if changes.is_empty() || changes.iter().any(|(name, _)| !self.contains(name)) {
+            return false;
+        }
+        for (name, enable) in changes {
+            if enable {
+                enabled.insert(name.to_string());
+            } else {
+                enabled.remove(name);
+            }
+        }
+        true
+    }
+}
+
+/// Packs `tokens` into space-separated lines of at most `max_len` bytes,
+/// for replies that have to be split over several `CAP` lines.
+pub fn cap_lines(tokens: &[String], max_len: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + 1 + token.len() > max_len {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(token);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ls_tokens() {
+        let mut registry = CapabilityRegistry::default();
+        assert!(registry.add("sasl", Some("PLAIN")));
+        assert!(!registry.add("sasl", Some("PLAIN")));
+        assert_eq!(
+            registry.ls_tokens(CAP_VERSION_302),
+            vec!["cap-notify", "message-tags", "sasl=PLAIN"]
+        );
+        assert_eq!(
+            registry.ls_tokens(0),
+            vec!["cap-notify", "message-tags", "sasl"]
+        );
+        assert!(registry.remove("sasl"));
+        assert!(!registry.remove("sasl"));
+    }
+
+    #[test]
+    fn test_apply_request() {
+        let registry = CapabilityRegistry::default();
+        let mut enabled = HashSet::new();
+        let request = |caps: &str| caps.split(' ').map(str::to_string).collect::<Vec<_>>();
+
+        assert!(registry.apply_request(&mut enabled, &request("message-tags cap-notify")));
+        assert_eq!(enabled.len(), 2);
+        assert!(!registry.apply_request(&mut enabled, &request("-message-tags unknown")));
+        assert_eq!(enabled.len(), 2);
+        assert!(registry.apply_request(&mut enabled, &request("-message-tags")));
+        assert!(!enabled.contains("message-tags"));
+    }
+
+    #[test]
+    fn test_cap_lines() {
+        let tokens = ["aaaa", "bbbb", "cc", "dddddd"].map(str::to_string);
+        assert_eq!(cap_lines(&tokens, 9), vec!["aaaa bbbb", "cc dddddd"]);
+        assert_eq!(cap_lines(&tokens, 100), vec!["aaaa bbbb cc dddddd"]);
+        assert_eq!(cap_lines(&[], 100), vec![""]);
+    }
+}
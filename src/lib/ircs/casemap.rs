@@ -0,0 +1,70 @@
+// src/lib/ircs/casemap.rs
+//! Case-insensitive comparison of nicks and channel names.
+//!
+//! IRC uses the RFC 1459 casemapping, where `[]\~` are the uppercase forms
+//! of `{}|^` in addition to the ASCII letters.
+use std::fmt;
+
+/// The casemapping advertised in ISUPPORT.
+pub const CASEMAPPING: &str = "rfc1459";
+
+/// Lowercases a single character under RFC 1459 rules.
+pub fn irc_lowercase(c: char) -> char {
+    match c {
+        '[' => '{',
+        ']' => '}',
+        '\\' => '|',
+        '~' => '^',
+        _ => c.to_ascii_lowercase(),
+    }
+}
+
+/// Whether two nicks or channel names are the same under RFC 1459 rules.
+pub fn irc_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.chars()
+            .zip(b.chars())
+            .all(|(x, y)| irc_lowercase(x) == irc_lowercase(y))
+}
+
+/// A casefolded nick or channel name, used as a lookup key. The display
+/// form is kept separately wherever the name is shown to users.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IrcKey(String);
+
+impl IrcKey {
+    pub fn new(name: &str) -> Self {
+        Self(name.chars().map(irc_lowercase).collect())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for IrcKey {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+impl fmt::Display for IrcKey {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc1459_casemapping() {
+        assert!(irc_eq("Alice", "alice"));
+        assert!(irc_eq("#Rust", "#rust"));
+        assert!(irc_eq("nick[away]", "NICK{AWAY}"));
+        assert!(irc_eq("a\\b~", "A|B^"));
+        assert!(!irc_eq("alice", "alicia"));
+        assert_eq!(IrcKey::new("Foo[]"), IrcKey::new("foo{}"));
+    }
+}
@@ -1,22 +1,134 @@
-use crate::connect::{ConnectionError, ConnectionManager, ConnectionWrite};
+use crate::connect::ConnectionWrite;
+use crate::ircs::bans::wildcard_match;
+use crate::ircs::casemap::{irc_eq, IrcKey};
 use crate::ircs::client::Client;
-use std::collections::HashMap;
+use crate::ircs::oper::Privilege;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A channel's topic and who last set it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Topic {
+    pub text: String,
+    /// The nick (or `nick!user@host`) of whoever set the topic.
+    pub set_by: String,
+    /// Unix timestamp of when the topic was set.
+    pub set_at: i64,
+}
+
+/// The channel modes that are simple on/off flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelModes {
+    /// +t: only channel operators may change the topic.
+    pub topic_locked: bool,
+    /// +m: only channel operators and voiced members may speak.
+    pub moderated: bool,
+}
+
+/// One change within a MODE line, such as `+o alice`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub arg: Option<String>,
+}
+
+impl ModeChange {
+    pub fn new(adding: bool, mode: char, arg: Option<&str>) -> Self {
+        Self {
+            adding,
+            mode,
+            arg: arg.map(str::to_string),
+        }
+    }
+}
+
+/// Joins mode changes into a modestring and its arguments, as in
+/// `+ov-b alice alice *!*@spam`.
+pub fn format_mode_changes(changes: &[ModeChange]) -> (String, Vec<String>) {
+    let mut modestring = String::new();
+    let mut last_sign = None;
+    for change in changes {
+        let sign = if change.adding { '+' } else { '-' };
+        if last_sign != Some(sign) {
+            modestring.push(sign);
+            last_sign = Some(sign);
+        }
+        modestring.push(change.mode);
+    }
+    let args = changes
+        .iter()
+        .filter_map(|change| change.arg.clone())
+        .collect();
+    (modestring, args)
+}
+
+/// Expands a ban mask to `nick!user@host` form, so that `spam` bans
+/// `spam!*@*` and `*@example.com` bans `*!*@example.com`.
+pub fn normalize_ban_mask(mask: &str) -> String {
+    match (mask.contains('!'), mask.contains('@')) {
+        (false, false) => format!("{mask}!*@*"),
+        (false, true) => format!("*!{mask}"),
+        (true, false) => format!("{mask}@*"),
+        (true, true) => mask.to_string(),
+    }
+}
 
 #[derive(Clone)]
 pub struct Channel {
     pub name: String,
     pub clients: Vec<String>,
+    /// The key (+k) members must give to JOIN, if one is set.
+    pub key: Option<String>,
+    pub topic: Option<Topic>,
+    pub modes: ChannelModes,
+    /// `nick!user@host` masks (+b) that may not join or speak.
+    pub bans: Vec<String>,
+    /// Members with channel operator status (+o).
+    ops: HashSet<IrcKey>,
+    /// Members with voice (+v).
+    voices: HashSet<IrcKey>,
 }
 
 impl Channel {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            clients: Vec::new(),
+            key: None,
+            topic: None,
+            modes: ChannelModes::default(),
+            bans: Vec::new(),
+            ops: HashSet::new(),
+            voices: HashSet::new(),
+        }
+    }
+
+    pub fn has_member(&self, nick: &str) -> bool {
+        self.clients.iter().any(|member| irc_eq(member, nick))
+    }
+
     pub fn join(&mut self, client: &Client) {
+        if self.has_member(&client.nick) {
+            return;
+        }
         self.clients.push(client.nick.to_string());
         // Send a join message to the client or other clients in the channel if needed
     }
 
     pub fn part(&mut self, client: &Client) -> bool {
-        if let Some(client_index) = self.clients.iter().position(|nick| nick == &client.nick) {
+        if let Some(client_index) = self
+            .clients
+            .iter()
+            .position(|nick| irc_eq(nick, &client.nick))
+        {
             self.clients.remove(client_index);
+            let key = IrcKey::new(&client.nick);
+            self.ops.remove(&key);
+            self.voices.remove(&key);
             // Send a part message to the client or other clients in the channel if needed
             true
         } else {
@@ -24,15 +136,139 @@ impl Channel {
         }
     }
 
+    /// Follows a member's nick change, keeping its status.
+    pub fn rename_member(&mut self, old_nick: &str, new_nick: &str) -> bool {
+        let Some(member) = self
+            .clients
+            .iter_mut()
+            .find(|member| irc_eq(member, old_nick))
+        else {
+            return false;
+        };
+        *member = new_nick.to_string();
+        let (old_key, new_key) = (IrcKey::new(old_nick), IrcKey::new(new_nick));
+        if self.ops.remove(&old_key) {
+            self.ops.insert(new_key.clone());
+        }
+        if self.voices.remove(&old_key) {
+            self.voices.insert(new_key);
+        }
+        true
+    }
+
+    pub fn is_op(&self, nick: &str) -> bool {
+        self.ops.contains(&IrcKey::new(nick))
+    }
+
+    pub fn is_voiced(&self, nick: &str) -> bool {
+        self.voices.contains(&IrcKey::new(nick))
+    }
+
+    /// Grants or removes +o, returning whether anything changed.
+    pub fn set_op(&mut self, nick: &str, op: bool) -> bool {
+        let key = IrcKey::new(nick);
+        match op {
+            true => self.ops.insert(key),
+            false => self.ops.remove(&key),
+        }
+    }
+
+    /// Grants or removes +v, returning whether anything changed.
+    pub fn set_voice(&mut self, nick: &str, voice: bool) -> bool {
+        let key = IrcKey::new(nick);
+        match voice {
+            true => self.voices.insert(key),
+            false => self.voices.remove(&key),
+        }
+    }
+
+    /// The highest status prefix of a member in NAMES and WHO: `@` or `+`.
+    pub fn status_prefix(&self, nick: &str) -> &'static str {
+        if self.is_op(nick) {
+            "@"
+        } else if self.is_voiced(nick) {
+            "+"
+        } else {
+            ""
+        }
+    }
+
+    /// Whether a client with source `prefix` matches one of the bans.
+    pub fn is_banned(&self, prefix: &str) -> bool {
+        self.bans.iter().any(|mask| wildcard_match(mask, prefix))
+    }
+
+    /// Whether `client` may speak: bans and +m silence anyone without status
+    /// or the override privilege.
+    pub fn can_speak(&self, client: &Client) -> bool {
+        self.is_op(&client.nick)
+            || self.is_voiced(&client.nick)
+            || client.has_privilege(Privilege::Override)
+            || !(self.modes.moderated || self.is_banned(&client.prefix()))
+    }
+
+    /// The RPL_CHANNELMODEIS modestring and arguments. The key is only
+    /// shown to members.
+    pub fn mode_params(&self, show_key: bool) -> (String, Vec<String>) {
+        let mut modestring = String::from("+");
+        let mut args = Vec::new();
+        if self.modes.moderated {
+            modestring.push('m');
+        }
+        if self.modes.topic_locked {
+            modestring.push('t');
+        }
+        if let Some(key) = &self.key {
+            modestring.push('k');
+            if show_key {
+                args.push(key.clone());
+            }
+        }
+        (modestring, args)
+    }
+
     pub async fn broadcast_message(
         &self,
         message: &str,
-        connection_map: &mut HashMap<String, ConnectionWrite>,
+        connection_map: &HashMap<IrcKey, Arc<Mutex<ConnectionWrite>>>,
     ) {
         for client_nick in &self.clients {
-            if let Some(conn_write) = connection_map.get_mut(client_nick) {
-                conn_write.write_message(message).await.unwrap();
+            if let Some(conn_write) = connection_map.get(&IrcKey::new(client_nick)) {
+                let _ = conn_write.lock().await.write_message(message).await;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_mode_changes() {
+        let changes = [
+            ModeChange::new(true, 'o', Some("alice")),
+            ModeChange::new(true, 't', None),
+            ModeChange::new(false, 'b', Some("*!*@spam")),
+        ];
+        assert_eq!(
+            format_mode_changes(&changes),
+            (
+                "+ot-b".to_string(),
+                vec!["alice".to_string(), "*!*@spam".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn test_member_status() {
+        let mut channel = Channel::new("#team".to_string());
+        channel.clients.push("Alice".to_string());
+        assert!(channel.set_op("alice", true));
+        assert!(!channel.set_op("ALICE", true));
+        assert_eq!(channel.status_prefix("Alice"), "@");
+        assert!(channel.rename_member("alice", "Alice[1]"));
+        assert!(channel.is_op("alice{1}"));
+        assert!(!channel.is_op("alice"));
+    }
+}
@@ -0,0 +1,233 @@
+// src/lib/ircs/channel_registry.rs
+//! Channels registered with ChanServ: who founded them, who gets status on
+//! JOIN, and the settings restored whenever the channel is recreated.
+use crate::ircs::casemap::irc_eq;
+use crate::ircs::channel::{Channel, ChannelModes, Topic};
+use crate::storage::{record, Change, SharedStorage, StorageError};
+use serde::{Deserialize, Serialize};
+
+/// The status an account is given when it joins a registered channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLevel {
+    Voice,
+    Op,
+    /// Only the founder, who may also change the access list.
+    Founder,
+}
+
+impl std::fmt::Display for AccessLevel {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            AccessLevel::Voice => write!(fmt, "VOICE"),
+            AccessLevel::Op => write!(fmt, "OP"),
+            AccessLevel::Founder => write!(fmt, "FOUNDER"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessEntry {
+    pub account: String,
+    pub level: AccessLevel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisteredChannel {
+    pub name: String,
+    /// The account that registered the channel.
+    pub founder: String,
+    #[serde(default)]
+    pub access: Vec<AccessEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<Topic>,
+    #[serde(default)]
+    pub modes: ChannelModes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub bans: Vec<String>,
+    /// Unix timestamp of when the channel was registered.
+    pub registered_at: i64,
+}
+
+impl RegisteredChannel {
+    /// Registers `channel` to `founder`, keeping its current settings.
+    pub fn new(channel: &Channel, founder: &str) -> Self {
+        let mut registered = Self {
+            name: channel.name.clone(),
+            founder: founder.to_string(),
+            access: Vec::new(),
+            topic: None,
+            modes: ChannelModes::default(),
+            key: None,
+            bans: Vec::new(),
+            registered_at: chrono::Utc::now().timestamp(),
+        };
+        registered.remember(channel);
+        registered
+    }
+
+    /// The status `account` is entitled to in this channel.
+    pub fn access_level(&self, account: &str) -> Option<AccessLevel> {
+        if irc_eq(&self.founder, account) {
+            return Some(AccessLevel::Founder);
+        }
+        self.access
+            .iter()
+            .find(|entry| irc_eq(&entry.account, account))
+            .map(|entry| entry.level)
+    }
+
+    /// Copies the topic, modes, key and bans of the live channel.
+    pub fn remember(&mut self, channel: &Channel) {
+        self.topic = channel.topic.clone();
+        self.modes = channel.modes;
+        self.key = channel.key.clone();
+        self.bans = channel.bans.clone();
+    }
+
+    /// A new, empty channel with the saved settings.
+    pub fn restore(&self) -> Channel {
+        let mut channel = Channel::new(self.name.clone());
+        channel.topic = self.topic.clone();
+        channel.modes = self.modes;
+        channel.key = self.key.clone();
+        channel.bans = self.bans.clone();
+        channel
+    }
+}
+
+/// Every registered channel, kept in `storage` as it changes.
+#[derive(Debug, Default)]
+pub struct ChannelRegistry {
+    channels: Vec<RegisteredChannel>,
+    storage: Option<SharedStorage>,
+}
+
+impl ChannelRegistry {
+    /// A registry holding `channels`, as loaded from `storage`. Without
+    /// storage, registrations only live in memory.
+    pub fn new(channels: Vec<RegisteredChannel>, storage: Option<SharedStorage>) -> Self {
+        Self { channels, storage }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisteredChannel> {
+        self.channels
+            .iter()
+            .find(|channel| irc_eq(&channel.name, name))
+    }
+
+    /// Adds `channel`, returning false if it is already registered.
+    pub fn add(&mut self, channel: RegisteredChannel) -> Result<bool, StorageError> {
+        if self.get(&channel.name).is_some() {
+            return Ok(false);
+        }
+        record(self.storage.as_ref(), Change::PutChannel(channel.clone()))?;
+        self.channels.push(channel);
+        Ok(true)
+    }
+
+    /// Unregisters `name`, returning whether it was registered.
+    pub fn remove(&mut self, name: &str) -> Result<bool, StorageError> {
+        if self.get(name).is_none() {
+            return Ok(false);
+        }
+        let change = Change::RemoveChannel {
+            name: name.to_string(),
+        };
+        record(self.storage.as_ref(), change)?;
+        self.channels.retain(|channel| !irc_eq(&channel.name, name));
+        Ok(true)
+    }
+
+    /// Changes the registered channel `name` and saves it, returning
+    /// whether it is registered.
+    pub fn update(
+        &mut self,
+        name: &str,
+        change: impl FnOnce(&mut RegisteredChannel),
+    ) -> Result<bool, StorageError> {
+        let Some(channel) = self
+            .channels
+            .iter_mut()
+            .find(|channel| irc_eq(&channel.name, name))
+        else {
+            return Ok(false);
+        };
+        let mut changed = channel.clone();
+        change(&mut changed);
+        record(self.storage.as_ref(), Change::PutChannel(changed.clone()))?;
+        *channel = changed;
+        Ok(true)
+    }
+
+    /// Forgets a dropped account: its channels are unregistered and it
+    /// loses its access everywhere else. Stops at the first change that
+    /// cannot be saved, leaving the rest as they were.
+    pub fn forget_account(&mut self, account: &str) -> Result<(), StorageError> {
+        let founded = self
+            .channels
+            .iter()
+            .filter(|channel| irc_eq(&channel.founder, account))
+            .map(|channel| channel.name.clone())
+            .collect::<Vec<_>>();
+        for name in founded {
+            self.remove(&name)?;
+        }
+        let with_access = self
+            .channels
+            .iter()
+            .filter(|channel| {
+                channel
+                    .access
+                    .iter()
+                    .any(|entry| irc_eq(&entry.account, account))
+            })
+            .map(|channel| channel.name.clone())
+            .collect::<Vec<_>>();
+        for name in with_access {
+            self.update(&name, |channel| {
+                channel
+                    .access
+                    .retain(|entry| !irc_eq(&entry.account, account));
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_levels() {
+        let mut channel = Channel::new("#Team".to_string());
+        channel.modes.topic_locked = true;
+        let mut registry = ChannelRegistry::default();
+        assert!(registry
+            .add(RegisteredChannel::new(&channel, "alice"))
+            .unwrap());
+        assert!(!registry
+            .add(RegisteredChannel::new(&channel, "bob"))
+            .unwrap());
+        assert!(registry
+            .update("#team", |channel| {
+                channel.access.push(AccessEntry {
+                    account: "Bob".to_string(),
+                    level: AccessLevel::Voice,
+                })
+            })
+            .unwrap());
+
+        let registered = registry.get("#TEAM").unwrap();
+        assert_eq!(registered.access_level("ALICE"), Some(AccessLevel::Founder));
+        assert_eq!(registered.access_level("bob"), Some(AccessLevel::Voice));
+        assert_eq!(registered.access_level("carol"), None);
+        assert!(registered.restore().modes.topic_locked);
+
+        registry.forget_account("alice").unwrap();
+        assert!(registry.get("#team").is_none());
+    }
+}
@@ -1,6 +1,130 @@
+use crate::config::ListenerPurpose;
+use crate::ircs::capability::CapState;
+use crate::ircs::oper::{Operator, Privilege};
+use crate::ircs::sasl::SaslSession;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Instant;
+
 #[derive(Clone)]
 pub struct Client {
     pub nick: String,
+    pub username: Option<String>,
     pub realname: Option<String>,
+    /// The host other users see. For a client that came through a WebIRC
+    /// gateway, the one the gateway gave.
+    pub host: String,
+    /// The address bans and limits apply to.
+    pub ip: IpAddr,
     pub channels: Vec<String>,
+    /// Set once the client has authenticated with OPER (user mode +o).
+    pub oper: Option<Operator>,
+    pub modes: UserModes,
+    /// Whether NICK and USER have both been accepted.
+    pub registered: bool,
+    /// IRCv3 capabilities the client has enabled, such as `message-tags`.
+    pub capabilities: HashSet<String>,
+    pub cap_state: CapState,
+    /// The version the client gave with `CAP LS`, or 0 if it gave none.
+    pub cap_version: u32,
+    /// The account the client logged in to with SASL.
+    pub account: Option<String>,
+    /// The SHA-256 fingerprint of the client's TLS certificate, as
+    /// lowercase hex. Always `None` for plaintext connections.
+    pub certfp: Option<String>,
+    /// What the listener the client connected to is reserved for, if
+    /// anything.
+    pub purpose: Option<ListenerPurpose>,
+    /// The user id of a client on a Unix socket, as the kernel reports it.
+    pub uid: Option<u32>,
+    /// The name of the WebIRC gateway the client connected through.
+    pub gateway: Option<String>,
+    /// The AUTHENTICATE exchange in progress, if any.
+    pub sasl: Option<SaslSession>,
+    /// When the client's nick will be changed unless it identifies, set
+    /// while it uses a registered nick without being logged in to it.
+    pub nick_deadline: Option<Instant>,
+}
+
+/// User modes that are stored as plain flags. Operator status (+o) lives in
+/// `Client::oper` instead, since it carries the operator's privileges.
+#[derive(Clone, Default)]
+pub struct UserModes {
+    /// +i: hidden from WHO and NAMES for users who share no channel with it.
+    pub invisible: bool,
+    /// +w: receives WALLOPS.
+    pub wallops: bool,
+    /// +B: marked as a bot in WHOIS.
+    pub bot: bool,
+    /// +Z: connected over TLS. Only the server sets this.
+    pub secure: bool,
+    /// +r: identified to an account. Only the server sets this.
+    pub identified: bool,
+}
+
+impl Client {
+    pub fn new(ip: IpAddr, host: String) -> Self {
+        Self {
+            nick: String::new(),
+            username: None,
+            realname: None,
+            host,
+            ip,
+            channels: Vec::new(),
+            oper: None,
+            modes: UserModes::default(),
+            registered: false,
+            capabilities: HashSet::new(),
+            cap_state: CapState::None,
+            cap_version: 0,
+            account: None,
+            certfp: None,
+            purpose: None,
+            uid: None,
+            gateway: None,
+            sasl: None,
+            nick_deadline: None,
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// The target of CAP replies, which is `*` until the client has a nick.
+    pub fn cap_target(&self) -> &str {
+        if self.nick.is_empty() {
+            "*"
+        } else {
+            &self.nick
+        }
+    }
+
+    /// The client's user modes as a mode string, such as `+iw`.
+    pub fn mode_string(&self) -> String {
+        let flags = [
+            (self.modes.invisible, 'i'),
+            (self.oper.is_some(), 'o'),
+            (self.modes.wallops, 'w'),
+            (self.modes.identified, 'r'),
+            (self.modes.bot, 'B'),
+            (self.modes.secure, 'Z'),
+        ];
+        let mut modes = String::from("+");
+        modes.extend(flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag));
+        modes
+    }
+
+    /// Whether the client is an operator whose class grants `privilege`.
+    pub fn has_privilege(&self, privilege: Privilege) -> bool {
+        self.oper
+            .as_ref()
+            .is_some_and(|oper| oper.has_privilege(privilege))
+    }
+
+    /// The `nick!user@host` source used when relaying this client's messages.
+    pub fn prefix(&self) -> String {
+        let username = self.username.as_deref().unwrap_or(&self.nick);
+        format!("{}!{}@{}", self.nick, username, self.host)
+    }
 }
@@ -1,276 +1,3716 @@
 // src/lib/ircs/irc_server.rs
-use crate::connect::ConnectionWrite;
-use std::collections::hash_map::Entry;
+use crate::config::{Config, ListenerPurpose};
+use crate::connect::{ip_host, ConnectionWrite};
+use crate::password::{PasswordHashes, ScramCredentials};
 
-use crate::ircs::channel::Channel;
+use crate::ircs::accounts::{Account, AccountStore};
+use crate::ircs::bans::{cidr_contains, valid_cidr, wildcard_match, Ban, BanKind, BanList};
+use crate::ircs::capability::{cap_lines, CapState, CapabilityRegistry, CAP_VERSION_302};
+use crate::ircs::casemap::{irc_eq, IrcKey, CASEMAPPING};
+use crate::ircs::channel::{format_mode_changes, normalize_ban_mask, Channel, ModeChange, Topic};
+use crate::ircs::channel_registry::{AccessEntry, AccessLevel, ChannelRegistry, RegisteredChannel};
 use crate::ircs::client::Client;
-use crate::ircs::irc_message::IrcMessage;
+use crate::ircs::memos::{Memo, MemoStore};
+use crate::ircs::oper::{Operator, Privilege};
+use crate::ircs::password_job::{FinishedJob, Outcome, PasswordJob, Then};
+use crate::ircs::sasl::{self, encode_chunks, Chunk, Mechanism, PlainCredentials, SaslSession};
+use crate::ircs::scram::{self, ScramStep};
+use crate::ircs::services::nickserv::{self, MIN_PASSWORD_LEN};
+use crate::ircs::services::{
+    chanserv, is_service, memoserv, service_prefix, ChanServCommand, MemoServCommand,
+    NickServCommand, CHANSERV, MEMOSERV, NICKSERV,
+};
+use crate::ircs::throttle::PasswordThrottle;
+use crate::parser::Tag;
+use crate::storage::{Settings, SharedStorage, State, StorageError};
+use crate::tls::TlsContext;
+use crate::types::{
+    BanMsg, CapMsg, Channel as TypedChannel, Command, ErrorType, JoinMsg, KillMsg, Message,
+    MessageError, ModeMsg, Nick, NickMsg, NumericReply, OperMsg, PartMsg, PrivMsg, QuitMsg,
+    ReplyType, Target, TopicMsg, UserMsg, WebircMsg, SERVER_NAME,
+};
+use rand::Rng;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 pub struct IrcServer {
-    clients: Vec<Client>,
+    /// Every connected client, registered or not, keyed by connection id.
+    clients: HashMap<String, Client>,
     channels: Vec<Channel>,
-    connection_map: HashMap<String, Arc<Mutex<ConnectionWrite>>>,
+    /// Writers for registered clients, keyed by casefolded nick.
+    connection_map: HashMap<IrcKey, Arc<Mutex<ConnectionWrite>>>,
+    /// Writers for clients that sent CAP before registering, keyed by
+    /// connection id, so capability changes reach them too.
+    negotiating: HashMap<String, Arc<Mutex<ConnectionWrite>>>,
+    config: Config,
+    /// Where `config` was loaded from, so REHASH can read it again.
+    config_path: Option<PathBuf>,
+    oper_throttle: PasswordThrottle,
+    /// Failed account and WebIRC gateway passwords.
+    login_throttle: PasswordThrottle,
+    /// Shared with the `ConnectionManager`, which applies D-lines on accept.
+    bans: Arc<Mutex<BanList>>,
+    /// The IRCv3 capabilities offered to clients in `CAP LS`.
+    capabilities: CapabilityRegistry,
+    /// Accounts that clients log in to with SASL.
+    accounts: AccountStore,
+    /// Channels registered with ChanServ.
+    channel_registry: ChannelRegistry,
+    /// Memos left with MemoServ.
+    memos: MemoStore,
+    /// Settings changed at runtime and kept across restarts.
+    settings: Settings,
+    /// Shared with the TLS listener. REHASH reloads its certificate.
+    tls: Option<Arc<TlsContext>>,
 }
-fn valid_nickname(nick: &str) -> bool {
-    // Check for length and allowed characters
-    nick.len() <= 9
-        && nick.chars().all(|c| c.is_ascii_alphanumeric())
-        && !nick.chars().nth(0).unwrap().is_ascii_digit()
+/// The most channels a single JOIN may name.
+const MAX_JOIN_TARGETS: usize = 10;
+/// The most channels a single PART may name.
+const MAX_PART_TARGETS: usize = 10;
+/// The most recipients a single PRIVMSG may name.
+const MAX_PRIVMSG_TARGETS: usize = 4;
+/// The most bytes of client-only tags a client may send on one message,
+/// not counting the leading `@` and the space after the tags.
+const MAX_CLIENT_TAGS_LEN: usize = 4094;
+/// What NickServ says to an address with too many failed passwords.
+const TOO_MANY_FAILURES: &str = "Too many failed attempts, try again later.";
+/// What services say when a change could not be written to storage.
+const NOT_SAVED: &str = "That could not be saved, try again later.";
+
+/// The tags of a client's message that may be passed on to other clients.
+/// Only client-only (`+`) tags are kept; the server does not act on any
+/// other tag, so those are dropped.
+fn client_tags(tags: Vec<Tag>) -> Vec<Tag> {
+    tags.into_iter()
+        .filter(|tag| tag.key.starts_with('+'))
+        .collect()
+}
+
+/// Whether a WebIRC gateway's `host` can be shown as it is: at most 253
+/// bytes, in labels of 1 to 63.
+fn valid_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && !host.starts_with([':', '-'])
+        && host.split('.').all(|label| (1..=63).contains(&label.len()))
+        && host
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-.:".contains(&byte))
+}
+
+/// A Unix timestamp as services show it.
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default()
 }
+
 impl IrcServer {
-    fn send_err_needmoreparams(&mut self, command: &str) -> String {
-        format!(":{} 461  :Need more parameters\r\n", command)
+    /// Writes a numeric reply to a single connection.
+    async fn send_reply(conn_write: &Arc<Mutex<ConnectionWrite>>, reply: NumericReply) {
+        let mut conn_write = conn_write.lock().await;
+        let _ = conn_write.write_message(&reply.to_string()).await;
     }
 
-    fn send_err_nosuchchannel(&mut self, channel_name: &str) -> String {
-        format!(" 403  {} :No such channel\r\n", channel_name)
+    /// Writes a server NOTICE to a single connection.
+    async fn send_notice(conn_write: &Arc<Mutex<ConnectionWrite>>, nick: &str, text: &str) {
+        let notice = PrivMsg {
+            targets: vec![Target::from(nick.to_string())],
+            message: text.to_string(),
+        };
+        let message = Message::with_prefix(SERVER_NAME, Command::Notice(notice)).to_string();
+        let mut conn_write = conn_write.lock().await;
+        let _ = conn_write.write_message(&message).await;
     }
+
     pub fn new() -> Self {
+        Self::with_config(Config::default(), None)
+    }
+
+    /// A server whose accounts, bans and other kept state only live in
+    /// memory.
+    pub fn with_config(config: Config, config_path: Option<PathBuf>) -> Self {
+        Self::with_storage(config, config_path, State::default(), None)
+    }
+
+    /// A server starting from `state`, which was loaded from `storage`, and
+    /// keeping its changes there.
+    pub fn with_storage(
+        config: Config,
+        config_path: Option<PathBuf>,
+        state: State,
+        storage: Option<SharedStorage>,
+    ) -> Self {
+        let bans = BanList::new(state.bans, storage.clone());
+        let accounts = AccountStore::new(state.accounts, storage.clone());
+        let channel_registry = ChannelRegistry::new(state.channels, storage.clone());
+        let memos = MemoStore::new(state.memos, storage.clone());
+        let settings = Settings::new(state.settings, storage);
+        let mut capabilities = CapabilityRegistry::default();
+        capabilities.add("sasl", Some(&sasl::mechanisms(config.tls.is_some())));
         Self {
-            clients: Vec::new(),
+            clients: HashMap::new(),
             channels: Vec::new(),
             connection_map: HashMap::new(),
+            negotiating: HashMap::new(),
+            config,
+            config_path,
+            oper_throttle: PasswordThrottle::default(),
+            login_throttle: PasswordThrottle::default(),
+            bans: Arc::new(Mutex::new(bans)),
+            capabilities,
+            accounts,
+            channel_registry,
+            memos,
+            settings,
+            tls: None,
         }
     }
 
+    /// Has REHASH reload the certificate of the TLS listener using `tls`.
+    pub fn set_tls(&mut self, tls: Arc<TlsContext>) {
+        self.tls = Some(tls);
+    }
+
+    /// A setting kept across restarts, if it has been set.
+    pub fn setting(&self, key: &str) -> Option<&str> {
+        self.settings.get(key)
+    }
+
+    /// Changes a setting that is kept across restarts.
+    pub fn set_setting(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.settings.set(key, value)
+    }
+
+    /// The server's K-lines and D-lines.
+    pub fn bans(&self) -> Arc<Mutex<BanList>> {
+        Arc::clone(&self.bans)
+    }
+
+    /// Starts tracking a newly accepted connection.
+    pub fn add_client(&mut self, id: String, client: Client) {
+        self.clients.insert(id, client);
+    }
+
+    /// The client on connection `id`, if it is still connected.
+    pub fn client(&self, id: &str) -> Option<&Client> {
+        self.clients.get(id)
+    }
+
+    fn client_nick(&self, id: &str) -> String {
+        self.clients
+            .get(id)
+            .map(|client| client.nick.clone())
+            .unwrap_or_default()
+    }
+
+    /// Finds the connection id of the client using `nick`.
+    fn find_client_id(&self, nick: &str) -> Option<String> {
+        self.clients
+            .iter()
+            .find(|(_, client)| irc_eq(&client.nick, nick))
+            .map(|(id, _)| id.clone())
+    }
+
+    fn has_privilege(&self, id: &str, privilege: Privilege) -> bool {
+        self.clients
+            .get(id)
+            .is_some_and(|client| client.has_privilege(privilege))
+    }
+
     // Add this function to the `impl IrcServer`
     pub fn add_connection(&mut self, client_nick: String, conn_write: Arc<Mutex<ConnectionWrite>>) {
-        self.connection_map.insert(client_nick, conn_write);
+        self.connection_map
+            .insert(IrcKey::new(&client_nick), conn_write);
     }
 
     pub fn remove_connection(&mut self, client_nick: &str) {
-        self.connection_map.remove(client_nick);
+        self.connection_map.remove(&IrcKey::new(client_nick));
     }
 
-    pub async fn send_privmsg_from_server(&mut self, target: &str, message: &str) {
-        let sender_nick = "server";
-        let formatted_message = format!(":{} PRIVMSG {} :{}\r\n", sender_nick, target, message);
+    /// The writer for `client` on connection `id`, if the server holds one.
+    fn writer(&self, id: &str, client: &Client) -> Option<&Arc<Mutex<ConnectionWrite>>> {
+        if client.registered {
+            self.connection_map.get(&IrcKey::new(&client.nick))
+        } else {
+            self.negotiating.get(id)
+        }
+    }
+
+    /// Sends a PRIVMSG to the registered client `target` from `sender`, a
+    /// source that is not a real client, such as a service.
+    pub async fn send_privmsg_from_server(&self, sender: &str, target: &str, message: &str) {
+        let privmsg = PrivMsg {
+            targets: vec![Target::from(target.to_string())],
+            message: message.to_string(),
+        };
+        let formatted_message = Message::with_prefix(sender, Command::PrivMsg(privmsg)).to_string();
 
-        if let Some(conn_write) = self.connection_map.get_mut(target) {
+        if let Some(conn_write) = self.connection_map.get(&IrcKey::new(target)) {
             let mut conn_write = conn_write.lock().await;
-            conn_write.write_message(&formatted_message).await.unwrap();
+            let _ = conn_write.write_message(&formatted_message).await;
         } else {
             println!("Target client not found");
         }
     }
-    pub async fn welcome_client(
+
+    /// Completes registration once both NICK and USER have been accepted,
+    /// returning whether the client on `id` is now registered.
+    pub async fn try_register(
+        &mut self,
+        id: &str,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+    ) -> bool {
+        let Some(client) = self.clients.get_mut(id) else {
+            return false;
+        };
+        if client.registered {
+            return true;
+        }
+        if client.nick.is_empty()
+            || client.realname.is_none()
+            || client.cap_state == CapState::Negotiating
+        {
+            return false;
+        }
+
+        let refusal = match client.purpose {
+            None => None,
+            Some(ListenerPurpose::Webirc) if client.gateway.is_some() => None,
+            Some(ListenerPurpose::Webirc) => Some("This port only accepts WebIRC gateways"),
+            Some(ListenerPurpose::Servers) => Some("This port only accepts server links"),
+        };
+        if let Some(reason) = refusal {
+            self.disconnect(id, reason, conn_write).await;
+            return false;
+        }
+
+        let username = client.username.clone().unwrap_or_default();
+        let host = client.host.clone();
+        let ip = client.ip;
+        let ban = {
+            let mut bans = self.bans.lock().await;
+            bans.find_dline(ip)
+                .or_else(|| bans.find_kline(&username, &host))
+        };
+        if let Some(ban) = ban {
+            self.reject_banned(id, &ban, conn_write).await;
+            return false;
+        }
+
+        let local_account = client
+            .uid
+            .filter(|_| client.account.is_none())
+            .and_then(|uid| self.config.local_account(uid))
+            .and_then(|account| self.accounts.get(account))
+            .map(|account| account.name.clone());
+        if let Some(account) = local_account {
+            self.log_in(id, account, conn_write).await;
+        }
+
+        let Some(client) = self.clients.get_mut(id) else {
+            return false;
+        };
+        client.registered = true;
+        let nick = client.nick.clone();
+        self.welcome_client(id, conn_write).await;
+        self.negotiating.remove(id);
+        self.add_connection(nick, Arc::clone(conn_write));
+        self.check_nick_ownership(id).await;
+        self.announce_memos(id).await;
+        true
+    }
+
+    pub async fn welcome_client(&self, id: &str, conn_write: &mut Arc<Mutex<ConnectionWrite>>) {
+        let Some(client) = self.clients.get(id) else {
+            return;
+        };
+        let realname = client.realname.as_ref().unwrap_or(&client.nick);
+        let text = format!("Hi {}, welcome to IRC", realname);
+        let reply = NumericReply::new(ReplyType::Welcome as u16, &client.nick, &[], &text);
+        Self::send_reply(conn_write, reply).await;
+
+        let tokens = Self::isupport_tokens();
+        let tokens = tokens.iter().map(String::as_str).collect::<Vec<_>>();
+        let reply = NumericReply::new(
+            ReplyType::ISupport as u16,
+            &client.nick,
+            &tokens,
+            "are supported by this server",
+        );
+        Self::send_reply(conn_write, reply).await;
+    }
+
+    /// The RPL_ISUPPORT tokens describing this server's behaviour.
+    fn isupport_tokens() -> Vec<String> {
+        vec![
+            format!("CASEMAPPING={CASEMAPPING}"),
+            "CHANMODES=b,k,,mt".to_string(),
+            "PREFIX=(ov)@+".to_string(),
+            format!(
+                "TARGMAX=JOIN:{MAX_JOIN_TARGETS},PART:{MAX_PART_TARGETS},PRIVMSG:{MAX_PRIVMSG_TARGETS}"
+            ),
+        ]
+    }
+    /// Tells the client that the verb it sent is not one this server knows.
+    pub async fn handle_unknown_command(
         &self,
-        client: &Client,
+        command: &str,
         conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
     ) {
-        let realname = client.realname.as_ref().unwrap_or(&client.nick);
-        let message = format!(
-            ":iris-server 001 {} :Hi {}, welcome to IRC\r\n",
-            client.nick, realname
+        let nick = self.client_nick(id);
+        let reply = NumericReply::error(ErrorType::UnknownCommand, &nick, &[command]);
+        Self::send_reply(conn_write, reply).await;
+    }
+
+    /// Tells the client that its last line was over the length limit.
+    pub async fn handle_input_too_long(
+        &self,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let reply = NumericReply::error(ErrorType::InputTooLong, &self.client_nick(id), &[]);
+        Self::send_reply(conn_write, reply).await;
+    }
+
+    /// Tells the client why its last line could not be parsed.
+    pub async fn handle_message_error(
+        &self,
+        error: MessageError,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        if let Some(reply) = error.reply(&self.client_nick(id)) {
+            Self::send_reply(conn_write, reply).await;
+        }
+    }
+
+    /// Rejects a command that requires the client to have finished registering.
+    pub async fn handle_unregistered_command(
+        &self,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        let reply = NumericReply::error(ErrorType::NotRegistered, &nick, &[]);
+        Self::send_reply(conn_write, reply).await;
+    }
+
+    /// The common path for every way a client can leave: QUIT, KILL or a
+    /// dropped connection. Tells the client's channels, forgets the client,
+    /// and closes its connection. Does nothing if `id` is already gone.
+    pub async fn disconnect(
+        &mut self,
+        id: &str,
+        reason: &str,
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+    ) {
+        self.negotiating.remove(id);
+        let Some(client) = self.clients.remove(id) else {
+            return;
+        };
+        println!(
+            "[{}] {} disconnected: {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            id,
+            reason
         );
+
+        if client.registered {
+            self.remove_connection(&client.nick);
+
+            let quit = QuitMsg {
+                message: Some(reason.to_string()),
+            };
+            let quit = Message::with_prefix(&client.prefix(), Command::Quit(quit)).to_string();
+            let mut notified = Vec::new();
+            for channel in &mut self.channels {
+                if !channel.part(&client) {
+                    continue;
+                }
+                for nick in &channel.clients {
+                    if notified.contains(nick) {
+                        continue;
+                    }
+                    if let Some(peer) = self.connection_map.get(&IrcKey::new(nick)) {
+                        let _ = peer.lock().await.write_message(&quit).await;
+                    }
+                    notified.push(nick.clone());
+                }
+            }
+            self.channels.retain(|channel| !channel.clients.is_empty());
+        }
+
         let mut conn_write = conn_write.lock().await;
-        conn_write.write_message(&message).await.unwrap();
+        let error = Command::Error(format!("Closing Link: {} ({})", client.host, reason));
+        let error = Message::new(error).to_string();
+        let _ = conn_write.write_message(&error).await;
+        conn_write.close().await;
     }
+
+    /// Sends ERR_YOUREBANNEDCREEP for `ban` and disconnects the client.
+    async fn reject_banned(
+        &mut self,
+        id: &str,
+        ban: &Ban,
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+    ) {
+        let nick = self.client_nick(id);
+        let text = format!("{} ({})", ErrorType::YoureBannedCreep, ban.reason);
+        let reply = NumericReply::new(ErrorType::YoureBannedCreep as u16, &nick, &[], &text);
+        Self::send_reply(conn_write, reply).await;
+        let reason = format!("{}: {}", ban.kind, ban.reason);
+        self.disconnect(id, &reason, conn_write).await;
+    }
+
     pub async fn handle_quit_command(
         &mut self,
-        irc_message: IrcMessage,
+        quit: QuitMsg,
         conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
     ) {
-        let message = if !irc_message.params.is_empty() {
-            irc_message.params[0].clone()
-        } else {
-            format!("{} has quit", irc_message.from_nick.unwrap())
-        };
-
-        let mut conn_write = conn_write.lock().await;
-        conn_write
-            .write_message(&format!("QUIT :{}\r\n", message))
-            .await
-            .unwrap();
+        let message = quit
+            .message
+            .unwrap_or_else(|| format!("{} has quit", self.client_nick(id)));
+        self.disconnect(id, &format!("Quit: {}", message), conn_write)
+            .await;
     }
 
     fn get_channel(&self, channel_name: &str) -> Option<usize> {
         for (i, channel) in self.channels.iter().enumerate() {
-            if channel.name == channel_name {
+            if irc_eq(&channel.name, channel_name) {
                 return Some(i);
             }
         }
         None
     }
 
-    /// join a channel
-
+    /// JOIN <channel>{,<channel>} [<key>{,<key>}]
+    /// JOIN 0
     pub async fn handle_join_command(
         &mut self,
-        irc_message: IrcMessage,
+        join: JoinMsg,
         conn_write: &mut Arc<Mutex<ConnectionWrite>>,
-        client: &Client,
+        id: &str,
     ) {
-        let mut response = String::new();
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
 
-        if irc_message.params.len() < 1 {
-            response = self.send_err_needmoreparams("JOIN");
-        } else {
-            let channel_name = &irc_message.params[0];
-            if !channel_name.starts_with('#') {
-                response = self.send_err_nosuchchannel(channel_name);
-            } else if let Some(channel_index) = self.get_channel(channel_name) {
-                self.channels[channel_index].join(client);
-            } else {
-                // Handle the case where the channel does not exist, if necessary
+        let JoinMsg::Channels { channels, keys } = join else {
+            let joined = self
+                .channels
+                .iter()
+                .filter(|channel| channel.has_member(&client.nick))
+                .map(|channel| channel.name.clone())
+                .collect::<Vec<_>>();
+            for channel_name in joined {
+                self.part_channel(id, &channel_name, None).await;
             }
-        }
+            return;
+        };
 
-        if !response.is_empty() {
-            let mut conn_write = conn_write.lock().await;
-            conn_write.write_message(&response).await.unwrap();
+        let mut keys = keys.iter();
+        for (count, channel) in channels.into_iter().enumerate() {
+            let key = keys.next().map(String::as_str);
+            if count >= MAX_JOIN_TARGETS {
+                let reply =
+                    NumericReply::error(ErrorType::TooManyTargets, &client.nick, &[&channel.0]);
+                Self::send_reply(conn_write, reply).await;
+                continue;
+            }
+            self.join_channel(&client, channel, key, conn_write, id)
+                .await;
         }
     }
 
-    pub async fn handle_part_command(
+    /// Adds `client` to one channel, creating it if needed, and sends the
+    /// JOIN and NAMES replies. Errors are sent per channel.
+    async fn join_channel(
         &mut self,
-        irc_message: IrcMessage,
-        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
         client: &Client,
+        channel: TypedChannel,
+        key: Option<&str>,
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+        id: &str,
     ) {
-        let mut response = String::new();
-
-        if irc_message.params.len() < 1 {
-            response = self.send_err_needmoreparams("PART");
+        let channel_name = match TypedChannel::try_from(channel.0.clone()) {
+            Ok(channel) => channel.0,
+            Err(error) => {
+                let reply = NumericReply::error(error, &client.nick, &[&channel.0]);
+                Self::send_reply(conn_write, reply).await;
+                return;
+            }
+        };
+        // Anyone on the access list of a registered channel gets their status
+        // on JOIN, and operators get past its bans and key, as do server
+        // operators with the override privilege.
+        let access = client.account.as_deref().and_then(|account| {
+            self.channel_registry
+                .get(&channel_name)?
+                .access_level(account)
+        });
+        let channel_index = match self.get_channel(&channel_name) {
+            Some(channel_index) => channel_index,
+            None => {
+                // A registered channel comes back with its saved settings;
+                // anyone else who creates a channel is its operator.
+                let channel = match self.channel_registry.get(&channel_name) {
+                    Some(registered) => registered.restore(),
+                    None => {
+                        let mut channel = Channel::new(channel_name.to_string());
+                        channel.set_op(&client.nick, true);
+                        channel
+                    }
+                };
+                self.channels.push(channel);
+                self.channels.len() - 1
+            }
+        };
+        let channel = &mut self.channels[channel_index];
+        if channel.has_member(&client.nick) {
+            return;
+        }
+        let exempt = access.is_some_and(|level| level >= AccessLevel::Op)
+            || client.has_privilege(Privilege::Override);
+        let error = if exempt {
+            None
+        } else if channel.is_banned(&client.prefix()) {
+            Some(ErrorType::BannedFromChan)
+        } else if channel
+            .key
+            .as_deref()
+            .is_some_and(|expected| Some(expected) != key)
+        {
+            Some(ErrorType::BadChannelKey)
         } else {
-            let channel_name = &irc_message.params[0];
-            if !channel_name.starts_with('#') {
-                response = self.send_err_nosuchchannel(channel_name);
-            } else if let Some(channel_index) = self.get_channel(channel_name) {
-                if !self.channels[channel_index].part(client) {
-                    response = self.send_err_nosuchchannel(channel_name);
-                }
-            } else {
-                // Handle the case where the channel does not exist, if necessary
+            None
+        };
+        if let Some(error) = error {
+            let reply = NumericReply::error(error, &client.nick, &[&channel.name]);
+            if channel.clients.is_empty() {
+                self.channels.remove(channel_index);
             }
+            Self::send_reply(conn_write, reply).await;
+            return;
         }
+        channel.join(client);
 
-        if !response.is_empty() {
-            let mut conn_write = conn_write.lock().await;
-            conn_write.write_message(&response).await.unwrap();
+        // Echo the channel's own spelling, not the requested one.
+        let channel_name = channel.name.clone();
+        let join = JoinMsg::Channels {
+            channels: vec![TypedChannel(channel_name.clone())],
+            keys: Vec::new(),
+        };
+        let join = Message::with_prefix(&client.prefix(), Command::Join(join)).to_string();
+        channel.broadcast_message(&join, &self.connection_map).await;
+        let status = match access {
+            Some(AccessLevel::Voice) => channel
+                .set_voice(&client.nick, true)
+                .then(|| ModeChange::new(true, 'v', Some(&client.nick))),
+            Some(_) => channel
+                .set_op(&client.nick, true)
+                .then(|| ModeChange::new(true, 'o', Some(&client.nick))),
+            None => None,
+        };
+        if let Some(status) = status {
+            self.broadcast_modes(channel_index, &service_prefix(CHANSERV), &[status])
+                .await;
+        }
+        if let Some(client) = self.clients.get_mut(id) {
+            client.channels.push(channel_name.clone());
         }
+        if self.channels[channel_index].topic.is_some() {
+            for reply in Self::topic_replies(&self.channels[channel_index], &client.nick) {
+                Self::send_reply(conn_write, reply).await;
+            }
+        }
+        self.send_names(&channel_name, conn_write, id).await;
     }
 
-    pub async fn handle_privmsg_command(
-        &mut self,
-        irc_message: IrcMessage,
-        from_conn_write: &mut Arc<Mutex<ConnectionWrite>>,
-        from_nick: &str,
-    ) {
-        let target = irc_message.to_nick.unwrap_or("No target".to_string());
-        let mut message = irc_message.params[1].clone();
+    /// Tells every member of a channel about mode changes made by `source`.
+    async fn broadcast_modes(&self, channel_index: usize, source: &str, changes: &[ModeChange]) {
+        let channel = &self.channels[channel_index];
+        let (modestring, args) = format_mode_changes(changes);
+        let mode = ModeMsg {
+            target: channel.name.clone(),
+            modestring: Some(modestring),
+            args,
+        };
+        let mode = Message::with_prefix(source, Command::Mode(mode)).to_string();
+        channel.broadcast_message(&mode, &self.connection_map).await;
+    }
 
-        if !message.ends_with("\r\n") {
-            message.push_str("\r\n");
+    /// Saves the topic, modes, key and bans of a channel if it is registered,
+    /// so that they come back when it is recreated. The live channel has
+    /// already changed, so if this cannot be saved, the registration just
+    /// keeps what it had.
+    fn remember_channel(&mut self, channel_index: usize) {
+        let channel = &self.channels[channel_index];
+        let _ = self
+            .channel_registry
+            .update(&channel.name, |registered| registered.remember(channel));
+    }
+
+    /// RPL_NOTOPIC, or RPL_TOPIC and RPL_TOPICWHOTIME.
+    fn topic_replies(channel: &Channel, nick: &str) -> Vec<NumericReply> {
+        match &channel.topic {
+            None => vec![NumericReply::new(
+                ReplyType::NoTopic as u16,
+                nick,
+                &[&channel.name],
+                "No topic is set",
+            )],
+            Some(topic) => vec![
+                NumericReply::new(ReplyType::Topic as u16, nick, &[&channel.name], &topic.text),
+                NumericReply::new(
+                    ReplyType::TopicWhoTime as u16,
+                    nick,
+                    &[&channel.name, &topic.set_by],
+                    &topic.set_at.to_string(),
+                ),
+            ],
         }
+    }
 
-        println!(
-            "[{}] About to send PRIVMSG from {} to {}: {}",
-            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-            from_nick,
-            target,
-            message
+    /// Removes the client on `id` from `channel_name`, telling every member
+    /// including the client itself. Empty channels are forgotten. Returns
+    /// false if the client was not a member.
+    async fn part_channel(&mut self, id: &str, channel_name: &str, reason: Option<&str>) -> bool {
+        let (Some(client), Some(channel_index)) =
+            (self.clients.get(id), self.get_channel(channel_name))
+        else {
+            return false;
+        };
+        let channel = &mut self.channels[channel_index];
+        if !channel.has_member(&client.nick) {
+            return false;
+        }
+
+        let part = PartMsg {
+            channels: vec![TypedChannel(channel.name.clone())],
+            reason: reason.map(str::to_string),
+        };
+        let part = Message::with_prefix(&client.prefix(), Command::Part(part)).to_string();
+        channel.broadcast_message(&part, &self.connection_map).await;
+        channel.part(client);
+        if channel.clients.is_empty() {
+            self.channels.remove(channel_index);
+        }
+        if let Some(client) = self.clients.get_mut(id) {
+            client
+                .channels
+                .retain(|joined| !irc_eq(joined, channel_name));
+        }
+        true
+    }
+
+    /// Whether two clients are both members of at least one channel.
+    fn shares_channel(&self, nick: &str, other_nick: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|channel| channel.has_member(nick) && channel.has_member(other_nick))
+    }
+
+    /// Whether the client on `id` may see `target` in WHO and NAMES.
+    /// Invisible (+i) users are only seen by themselves and by users who
+    /// share a channel with them.
+    fn can_see(&self, id: &str, target: &Client) -> bool {
+        let nick = self.client_nick(id);
+        !target.modes.invisible
+            || irc_eq(&target.nick, &nick)
+            || self.shares_channel(&nick, &target.nick)
+    }
+
+    fn client_by_nick(&self, nick: &str) -> Option<&Client> {
+        self.clients
+            .values()
+            .find(|client| client.registered && irc_eq(&client.nick, nick))
+    }
+
+    /// Sends RPL_NAMREPLY and RPL_ENDOFNAMES for `channel_name`.
+    async fn send_names(
+        &self,
+        channel_name: &str,
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        if let Some(channel_index) = self.get_channel(channel_name) {
+            let channel = &self.channels[channel_index];
+            let is_member = channel.has_member(&nick);
+            let names = channel
+                .clients
+                .iter()
+                .filter_map(|member| self.client_by_nick(member))
+                .filter(|member| is_member || self.can_see(id, member))
+                .map(|member| format!("{}{}", channel.status_prefix(&member.nick), member.nick))
+                .collect::<Vec<_>>();
+            let reply = NumericReply::new(
+                ReplyType::NamReply as u16,
+                &nick,
+                &["=", channel_name],
+                &names.join(" "),
+            );
+            Self::send_reply(conn_write, reply).await;
+        }
+        let reply = NumericReply::new(
+            ReplyType::EndOfNames as u16,
+            &nick,
+            &[channel_name],
+            "End of /NAMES list",
         );
+        Self::send_reply(conn_write, reply).await;
+    }
 
-        match self.connection_map.entry(target.clone()) {
-            Entry::Occupied(mut target_conn_write) => {
-                let mut target_conn_write = target_conn_write.get_mut().lock().await;
-                target_conn_write.write_message(&message).await.unwrap();
-                dbg!("Message sent to target");
-                println!(
-                    "[{}] PRIVMSG sent from {} to {}: {}",
-                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                    from_nick,
-                    target,
-                    message
-                );
+    /// NAMES <channel>
+    pub async fn handle_names_command(
+        &self,
+        channel_name: Option<String>,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let channel_name = channel_name.as_deref().unwrap_or("*");
+        self.send_names(channel_name, conn_write, id).await;
+    }
+
+    /// WHO <channel|mask>
+    pub async fn handle_who_command(
+        &self,
+        mask: Option<String>,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        let mask = mask.as_deref().unwrap_or("*");
+
+        let channel = self.get_channel(mask).map(|index| &self.channels[index]);
+        let (channel_name, members) = match channel {
+            Some(channel) => {
+                let is_member = channel.has_member(&nick);
+                let members = channel
+                    .clients
+                    .iter()
+                    .filter_map(|member| self.client_by_nick(member))
+                    .filter(|member| is_member || self.can_see(id, member))
+                    .collect::<Vec<_>>();
+                (mask, members)
+            }
+            None => {
+                let members = self
+                    .clients
+                    .values()
+                    .filter(|client| client.registered && wildcard_match(mask, &client.nick))
+                    .filter(|client| self.can_see(id, client))
+                    .collect::<Vec<_>>();
+                ("*", members)
+            }
+        };
+
+        for member in members {
+            let mut flags = String::from("H");
+            if member.oper.is_some() {
+                flags.push('*');
+            }
+            if let Some(channel) = channel {
+                flags.push_str(channel.status_prefix(&member.nick));
             }
-            Entry::Vacant(_) => {
-                let mut from_conn_write = from_conn_write.lock().await;
-                from_conn_write
-                    .write_message("Target client not found")
-                    .await
-                    .unwrap();
+            if member.modes.bot {
+                flags.push('B');
             }
+            let username = member.username.as_deref().unwrap_or(&member.nick);
+            let realname = member.realname.as_deref().unwrap_or_default();
+            let reply = NumericReply::new(
+                ReplyType::WhoReply as u16,
+                &nick,
+                &[
+                    channel_name,
+                    username,
+                    &member.host,
+                    SERVER_NAME,
+                    &member.nick,
+                    &flags,
+                ],
+                &format!("0 {realname}"),
+            );
+            Self::send_reply(conn_write, reply).await;
         }
+        let reply = NumericReply::new(
+            ReplyType::EndOfWho as u16,
+            &nick,
+            &[mask],
+            "End of WHO list",
+        );
+        Self::send_reply(conn_write, reply).await;
     }
-    pub async fn handle_ping_command(
+
+    /// WHOIS <nick>
+    pub async fn handle_whois_command(
         &self,
-        irc_message: IrcMessage,
+        target_nick: String,
         conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
     ) {
-        let hostname = &irc_message.params[0];
-        let mut conn_write = conn_write.lock().await;
-        conn_write
-            .write_message(&format!("PONG :{}\r\n", hostname))
-            .await
-            .unwrap();
+        let nick = self.client_nick(id);
+        let target_nick = target_nick.as_str();
+        let Some(target) = self.client_by_nick(target_nick) else {
+            let reply = NumericReply::error(ErrorType::NoSuchNick, &nick, &[target_nick]);
+            Self::send_reply(conn_write, reply).await;
+            let reply = NumericReply::new(
+                ReplyType::EndOfWhois as u16,
+                &nick,
+                &[target_nick],
+                "End of /WHOIS list",
+            );
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
+
+        let mut replies = vec![NumericReply::new(
+            ReplyType::WhoisUser as u16,
+            &nick,
+            &[
+                &target.nick,
+                target.username.as_deref().unwrap_or(&target.nick),
+                &target.host,
+                "*",
+            ],
+            target.realname.as_deref().unwrap_or_default(),
+        )];
+        let channels = self
+            .channels
+            .iter()
+            .filter(|channel| channel.has_member(&target.nick))
+            .filter(|channel| !target.modes.invisible || channel.has_member(&nick))
+            .map(|channel| channel.name.clone())
+            .collect::<Vec<_>>();
+        if !channels.is_empty() {
+            replies.push(NumericReply::new(
+                ReplyType::WhoisChannels as u16,
+                &nick,
+                &[&target.nick],
+                &channels.join(" "),
+            ));
+        }
+        replies.push(NumericReply::new(
+            ReplyType::WhoisServer as u16,
+            &nick,
+            &[&target.nick, SERVER_NAME],
+            "iris IRC server",
+        ));
+        if target.oper.is_some() {
+            replies.push(NumericReply::new(
+                ReplyType::WhoisOperator as u16,
+                &nick,
+                &[&target.nick],
+                "is an IRC operator",
+            ));
+        }
+        if let Some(account) = &target.account {
+            replies.push(NumericReply::new(
+                ReplyType::WhoisAccount as u16,
+                &nick,
+                &[&target.nick, account],
+                "is logged in as",
+            ));
+        }
+        if target.modes.bot {
+            replies.push(NumericReply::new(
+                ReplyType::WhoisBot as u16,
+                &nick,
+                &[&target.nick],
+                &format!("is a Bot on {SERVER_NAME}"),
+            ));
+        }
+        if target.modes.secure {
+            replies.push(NumericReply::new(
+                ReplyType::WhoisSecure as u16,
+                &nick,
+                &[&target.nick],
+                "is using a secure connection",
+            ));
+        }
+        replies.push(NumericReply::new(
+            ReplyType::EndOfWhois as u16,
+            &nick,
+            &[&target.nick],
+            "End of /WHOIS list",
+        ));
+        for reply in replies {
+            Self::send_reply(conn_write, reply).await;
+        }
     }
 
-    pub async fn handle_user_command(
+    /// MODE <nick> [modestring]
+    /// MODE <channel> [modestring [args...]]
+    pub async fn handle_mode_command(
         &mut self,
-        irc_message: IrcMessage,
+        mode: ModeMsg,
         conn_write: &mut Arc<Mutex<ConnectionWrite>>,
-        client: &mut Client, // Add client parameter
+        id: &str,
     ) {
-        // Ensure there are at least 4 parameters
-        if irc_message.params.len() < 4 {
-            let mut conn_write = conn_write.lock().await;
-            conn_write
-                .write_message("ERR_NEEDMOREPARAMS\r\n")
-                .await
-                .unwrap();
+        let nick = self.client_nick(id);
+        let target = &mode.target;
+
+        if target.starts_with('#') {
+            self.handle_channel_mode(mode, conn_write, id).await;
+            return;
+        }
+        if !irc_eq(target, &nick) {
+            let reply = NumericReply::error(ErrorType::UsersDontMatch, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
             return;
         }
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        let Some(modestring) = &mode.modestring else {
+            let reply =
+                NumericReply::new(ReplyType::UModeIs as u16, &nick, &[], &client.mode_string());
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
 
-        let realname = match irc_message.params.last() {
-            Some(realname) if realname.starts_with(':') => realname[1..].to_string(),
-            _ => {
-                let mut conn_write = conn_write.lock().await;
-                conn_write
-                    .write_message("ERR_NEEDMOREPARAMS\r\n")
-                    .await
-                    .unwrap();
-                return;
+        let mut adding = true;
+        let mut unknown_flag = false;
+        let mut changes = String::new();
+        let mut last_sign = None;
+        for flag in modestring.chars() {
+            let changed = match flag {
+                '+' | '-' => {
+                    adding = flag == '+';
+                    continue;
+                }
+                'i' => std::mem::replace(&mut client.modes.invisible, adding) != adding,
+                'w' => std::mem::replace(&mut client.modes.wallops, adding) != adding,
+                'B' => std::mem::replace(&mut client.modes.bot, adding) != adding,
+                // Operator status is only granted by OPER, but may be dropped.
+                'o' => !adding && client.oper.take().is_some(),
+                // +Z and +r reflect the connection and account, not user choice.
+                'Z' | 'r' => false,
+                _ => {
+                    unknown_flag = true;
+                    false
+                }
+            };
+            if changed {
+                let sign = if adding { '+' } else { '-' };
+                if last_sign != Some(sign) {
+                    changes.push(sign);
+                    last_sign = Some(sign);
+                }
+                changes.push(flag);
             }
-        };
-        // update realname for client
-        dbg!(&realname);
-        client.realname = Some(realname);
-        //print the realname
+        }
+
+        if unknown_flag {
+            let reply = NumericReply::error(ErrorType::UModeUnknownFlag, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+        }
+        if !changes.is_empty() {
+            let mode = ModeMsg {
+                target: nick.clone(),
+                modestring: Some(changes),
+                args: Vec::new(),
+            };
+            let mode = Message::with_prefix(&nick, Command::Mode(mode)).to_string();
+            let _ = conn_write.lock().await.write_message(&mode).await;
+        }
     }
 
-    pub async fn handle_nick_command(
+    /// MODE <channel> [modestring [args...]]
+    async fn handle_channel_mode(
         &mut self,
-        irc_message: IrcMessage,
-        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
-        client: &mut Client, // Add client parameter
+        mode: ModeMsg,
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+        id: &str,
     ) {
-        let new_nick = irc_message.params[0].clone();
-        if !valid_nickname(&new_nick) {
-            let mut conn_write = conn_write.lock().await;
-            conn_write
-                .write_message("ERR_ERRONEUSNICKNAME\r\n")
-                .await
-                .unwrap();
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
+        let nick = client.nick.as_str();
+        let Some(channel_index) = self.get_channel(&mode.target) else {
+            let reply = NumericReply::error(ErrorType::NoSuchChannel, nick, &[&mode.target]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
+        let channel = &mut self.channels[channel_index];
+        let Some(modestring) = &mode.modestring else {
+            let (modestring, args) = channel.mode_params(channel.has_member(nick));
+            let mut params = vec![channel.name.as_str(), modestring.as_str()];
+            params.extend(args.iter().map(String::as_str));
+            let text = params.pop().unwrap_or_default();
+            let reply = NumericReply::new(ReplyType::ChannelModeIs as u16, nick, &params, text);
+            Self::send_reply(conn_write, reply).await;
             return;
+        };
+
+        let is_op = channel.is_op(nick) || client.has_privilege(Privilege::Override);
+        let mut args = mode.args.iter();
+        let mut adding = true;
+        let mut requested = Vec::new();
+        let mut replies = Vec::new();
+        let mut denied = false;
+        let mut list_bans = false;
+        for flag in modestring.chars() {
+            let arg = match flag {
+                '+' | '-' => {
+                    adding = flag == '+';
+                    continue;
+                }
+                // A bare `b` asks for the ban list, which anyone may see.
+                'b' if args.len() == 0 => {
+                    list_bans = true;
+                    continue;
+                }
+                'o' | 'v' => match args.next() {
+                    Some(arg) => Some(arg.clone()),
+                    None => continue,
+                },
+                'b' => match args.next() {
+                    Some(mask) => Some(normalize_ban_mask(mask)),
+                    None => continue,
+                },
+                'k' if adding => match args.next() {
+                    Some(arg) => Some(arg.clone()),
+                    None => continue,
+                },
+                // `-k` may repeat the key, but does not need it.
+                'k' => {
+                    args.next();
+                    None
+                }
+                't' | 'm' => None,
+                _ => {
+                    let flag = flag.to_string();
+                    replies.push(NumericReply::error(ErrorType::UnknownMode, nick, &[&flag]));
+                    continue;
+                }
+            };
+            if is_op {
+                requested.push(ModeChange {
+                    adding,
+                    mode: flag,
+                    arg,
+                });
+            } else {
+                denied = true;
+            }
         }
 
-        if self.clients.iter().any(|client| client.nick == new_nick) {
-            let mut conn_write = conn_write.lock().await;
-            conn_write
-                .write_message("ERR_NICKCOLLISION\r\n")
-                .await
-                .unwrap();
+        let mut changes = Vec::new();
+        for change in requested {
+            let arg = change.arg.as_deref().unwrap_or_default();
+            let changed = match change.mode {
+                'o' | 'v' if !channel.has_member(arg) => {
+                    replies.push(NumericReply::error(
+                        ErrorType::UserNotInChannel,
+                        nick,
+                        &[arg, &channel.name],
+                    ));
+                    false
+                }
+                'o' => channel.set_op(arg, change.adding),
+                'v' => channel.set_voice(arg, change.adding),
+                'b' if change.adding => {
+                    let known = channel.bans.iter().any(|ban| irc_eq(ban, arg));
+                    if !known {
+                        channel.bans.push(arg.to_string());
+                    }
+                    !known
+                }
+                'b' => {
+                    let before = channel.bans.len();
+                    channel.bans.retain(|ban| !irc_eq(ban, arg));
+                    channel.bans.len() != before
+                }
+                'k' if change.adding => {
+                    channel.key.replace(arg.to_string()).as_deref() != Some(arg)
+                }
+                'k' => channel.key.take().is_some(),
+                't' => {
+                    std::mem::replace(&mut channel.modes.topic_locked, change.adding)
+                        != change.adding
+                }
+                _ => {
+                    std::mem::replace(&mut channel.modes.moderated, change.adding) != change.adding
+                }
+            };
+            if changed {
+                changes.push(change);
+            }
+        }
+
+        if denied {
+            replies.push(NumericReply::error(
+                ErrorType::ChanOPrivsNeeded,
+                nick,
+                &[&channel.name],
+            ));
+        }
+        if list_bans {
+            for ban in &channel.bans {
+                replies.push(NumericReply::new(
+                    ReplyType::BanList as u16,
+                    nick,
+                    &[&channel.name, ban],
+                    "",
+                ));
+            }
+            replies.push(NumericReply::new(
+                ReplyType::EndOfBanList as u16,
+                nick,
+                &[&channel.name],
+                "End of channel ban list",
+            ));
+        }
+        for reply in replies {
+            Self::send_reply(conn_write, reply).await;
+        }
+        if changes.is_empty() {
             return;
         }
+        self.broadcast_modes(channel_index, &client.prefix(), &changes)
+            .await;
+        if changes
+            .iter()
+            .any(|change| !matches!(change.mode, 'o' | 'v'))
+        {
+            self.remember_channel(channel_index);
+        }
+    }
+
+    /// TOPIC <channel> [:<topic>]
+    pub async fn handle_topic_command(
+        &mut self,
+        topic: TopicMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
+        let nick = client.nick.as_str();
+        let Some(channel_index) = self.get_channel(&topic.channel) else {
+            let reply = NumericReply::error(ErrorType::NoSuchChannel, nick, &[&topic.channel]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
+        let channel = &mut self.channels[channel_index];
+        let Some(text) = topic.topic else {
+            for reply in Self::topic_replies(channel, nick) {
+                Self::send_reply(conn_write, reply).await;
+            }
+            return;
+        };
+
+        let error = if !channel.has_member(nick) {
+            Some(ErrorType::NotOnChannel)
+        } else if channel.modes.topic_locked
+            && !channel.is_op(nick)
+            && !client.has_privilege(Privilege::Override)
+        {
+            Some(ErrorType::ChanOPrivsNeeded)
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            let reply = NumericReply::error(error, nick, &[&channel.name]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+
+        // An empty topic clears it.
+        channel.topic = (!text.is_empty()).then(|| Topic {
+            text: text.clone(),
+            set_by: client.prefix(),
+            set_at: chrono::Utc::now().timestamp(),
+        });
+        let topic = TopicMsg {
+            channel: channel.name.clone(),
+            topic: Some(text),
+        };
+        let topic = Message::with_prefix(&client.prefix(), Command::Topic(topic)).to_string();
+        channel
+            .broadcast_message(&topic, &self.connection_map)
+            .await;
+        self.remember_channel(channel_index);
+    }
+
+    /// PART <channel>{,<channel>} [:<reason>]
+    pub async fn handle_part_command(
+        &mut self,
+        part: PartMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
+
+        for (count, channel) in part.channels.iter().enumerate() {
+            let channel_name = channel.0.as_str();
+            let error = if count >= MAX_PART_TARGETS {
+                ErrorType::TooManyTargets
+            } else if self.get_channel(channel_name).is_none() {
+                ErrorType::NoSuchChannel
+            } else if !self
+                .part_channel(id, channel_name, part.reason.as_deref())
+                .await
+            {
+                ErrorType::NotOnChannel
+            } else {
+                continue;
+            };
+            let reply = NumericReply::error(error, &client.nick, &[channel_name]);
+            Self::send_reply(conn_write, reply).await;
+        }
+    }
+
+    /// PRIVMSG <target>{,<target>} :<text>
+    /// NOTICE <target>{,<target>} :<text>
+    /// TAGMSG <target>{,<target>}
+    ///
+    /// Returns the password checks that messages to NickServ need, for the
+    /// client loop to run.
+    pub async fn handle_privmsg_command(
+        &mut self,
+        message: Message,
+        from_conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) -> Vec<PasswordJob> {
+        let mut jobs = Vec::new();
+        let Some(sender) = self.clients.get(id).cloned() else {
+            return jobs;
+        };
+        let (targets, notice) = match &message.command {
+            Command::PrivMsg(privmsg) => (privmsg.targets.clone(), false),
+            Command::Notice(notice) => (notice.targets.clone(), true),
+            Command::TagMsg(targets) => (targets.clone(), false),
+            _ => return jobs,
+        };
+
+        let tags = client_tags(message.tags);
+        let tags_len = tags
+            .iter()
+            .map(Tag::to_string)
+            .collect::<Vec<_>>()
+            .join(";")
+            .len();
+        if tags_len > MAX_CLIENT_TAGS_LEN {
+            let reply = NumericReply::error(ErrorType::InputTooLong, &sender.nick, &[]);
+            Self::send_reply(from_conn_write, reply).await;
+            return jobs;
+        }
+
+        for (count, target) in targets.into_iter().enumerate() {
+            let target_name = target.to_string();
+            let error = if count >= MAX_PRIVMSG_TARGETS {
+                Some(ErrorType::TooManyTargets)
+            } else {
+                let command = match &message.command {
+                    Command::PrivMsg(privmsg) => Command::PrivMsg(PrivMsg {
+                        targets: vec![target],
+                        message: privmsg.message.clone(),
+                    }),
+                    Command::Notice(notice) => Command::Notice(PrivMsg {
+                        targets: vec![target],
+                        message: notice.message.clone(),
+                    }),
+                    _ => Command::TagMsg(vec![target]),
+                };
+                let relayed = Message {
+                    tags: tags.clone(),
+                    prefix: Some(sender.prefix()),
+                    command,
+                };
+                match &relayed.command {
+                    Command::PrivMsg(privmsg) if irc_eq(&target_name, NICKSERV) => {
+                        jobs.extend(
+                            self.handle_nickserv_message(id, &privmsg.message, from_conn_write)
+                                .await,
+                        );
+                        None
+                    }
+                    Command::PrivMsg(privmsg) if irc_eq(&target_name, CHANSERV) => {
+                        self.handle_chanserv_message(id, &privmsg.message).await;
+                        None
+                    }
+                    Command::PrivMsg(privmsg) if irc_eq(&target_name, MEMOSERV) => {
+                        self.handle_memoserv_message(id, &privmsg.message).await;
+                        None
+                    }
+                    _ => self.deliver(&sender, &target_name, &relayed).await.err(),
+                }
+            };
+            // Clients must never be sent an automatic reply to a NOTICE.
+            if let Some(error) = error.filter(|_| !notice) {
+                let reply = NumericReply::error(error, &sender.nick, &[&target_name]);
+                Self::send_reply(from_conn_write, reply).await;
+            }
+        }
+        jobs
+    }
+
+    /// Sends `message` from `sender` to one user or to every other member of
+    /// one channel.
+    async fn deliver(
+        &self,
+        sender: &Client,
+        target: &str,
+        message: &Message,
+    ) -> Result<(), ErrorType> {
+        let recipients = if target.starts_with('#') {
+            let channel_index = self.get_channel(target).ok_or(ErrorType::NoSuchNick)?;
+            let channel = &self.channels[channel_index];
+            if !channel.has_member(&sender.nick) || !channel.can_speak(sender) {
+                return Err(ErrorType::CannotSendToChan);
+            }
+            channel
+                .clients
+                .iter()
+                .filter(|member| !irc_eq(member, &sender.nick))
+                .filter_map(|member| self.client_by_nick(member))
+                .collect::<Vec<_>>()
+        } else {
+            vec![self.client_by_nick(target).ok_or(ErrorType::NoSuchNick)?]
+        };
+
+        let untagged = Message {
+            tags: Vec::new(),
+            ..message.clone()
+        };
+        for recipient in recipients {
+            let Some(conn_write) = self.connection_map.get(&IrcKey::new(&recipient.nick)) else {
+                continue;
+            };
+            // Tags only reach clients that asked for them, and a TAGMSG is
+            // nothing but tags.
+            let line = if recipient.has_capability("message-tags") {
+                message.to_string()
+            } else if matches!(message.command, Command::TagMsg(_)) {
+                continue;
+            } else {
+                untagged.to_string()
+            };
+            let _ = conn_write.lock().await.write_message(&line).await;
+        }
+        Ok(())
+    }
+
+    /// CAP LS / LIST / REQ / END
+    pub async fn handle_cap_command(
+        &mut self,
+        cap: CapMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        let target = client.cap_target().to_string();
+        if !client.registered {
+            if matches!(cap, CapMsg::Ls(_) | CapMsg::Req(_)) {
+                client.cap_state = CapState::Negotiating;
+            }
+            self.negotiating
+                .entry(id.to_string())
+                .or_insert_with(|| Arc::clone(conn_write));
+        }
+
+        match cap {
+            CapMsg::Ls(version) => {
+                let version = version.unwrap_or(0);
+                client.cap_version = client.cap_version.max(version);
+                if version >= CAP_VERSION_302 {
+                    client.capabilities.insert("cap-notify".to_string());
+                }
+                let tokens = self.capabilities.ls_tokens(version);
+                Self::send_cap_lines(conn_write, &target, "LS", &tokens, version).await;
+            }
+            CapMsg::List => {
+                let mut tokens = client.capabilities.iter().cloned().collect::<Vec<_>>();
+                tokens.sort();
+                let version = client.cap_version;
+                Self::send_cap_lines(conn_write, &target, "LIST", &tokens, version).await;
+            }
+            CapMsg::Req(request) => {
+                // cap-notify is always on for 302 clients, so it cannot be
+                // turned off.
+                let sticky = client.cap_version >= CAP_VERSION_302
+                    && request.iter().any(|token| token == "-cap-notify");
+                let accepted = !sticky
+                    && self
+                        .capabilities
+                        .apply_request(&mut client.capabilities, &request);
+                let subcommand = if accepted { "ACK" } else { "NAK" };
+                Self::send_cap(conn_write, &target, subcommand, false, &request.join(" ")).await;
+            }
+            CapMsg::End => {
+                if client.cap_state == CapState::Negotiating {
+                    client.cap_state = CapState::Ended;
+                }
+                // Registration may now finish, which ends any SASL exchange.
+                if client.sasl.take().is_some() {
+                    let reply = NumericReply::error(ErrorType::SaslAborted, &target, &[]);
+                    Self::send_reply(conn_write, reply).await;
+                }
+            }
+            // Only the server sends replies.
+            CapMsg::Reply { .. } => {}
+        }
+    }
+
+    /// AUTHENTICATE <mechanism> | <base64 chunk> | + | *
+    ///
+    /// Returns the password check for PLAIN, for the client loop to run.
+    pub async fn handle_authenticate_command(
+        &mut self,
+        param: String,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) -> Option<PasswordJob> {
+        let client = self.clients.get_mut(id)?;
+        let nick = client.nick.clone();
+        if param == "*" {
+            client.sasl = None;
+            let reply = NumericReply::error(ErrorType::SaslAborted, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return None;
+        }
+
+        // The first AUTHENTICATE of an exchange names the mechanism.
+        let Some(session) = client.sasl.as_mut() else {
+            if client.account.is_some() {
+                let reply = NumericReply::error(ErrorType::SaslAlready, &nick, &[]);
+                Self::send_reply(conn_write, reply).await;
+                return None;
+            }
+            let offered = self
+                .capabilities
+                .get("sasl")
+                .and_then(|capability| capability.value.clone())
+                .unwrap_or_default();
+            let mechanism = Mechanism::from_name(&param).filter(|_| {
+                offered
+                    .split(',')
+                    .any(|name| name.eq_ignore_ascii_case(&param))
+            });
+            match mechanism {
+                Some(mechanism) => {
+                    client.sasl = Some(SaslSession::new(mechanism));
+                    let challenge = Message::new(Command::Authenticate("+".to_string()));
+                    let mut conn_write = conn_write.lock().await;
+                    let _ = conn_write.write_message(&challenge.to_string()).await;
+                }
+                None => {
+                    let reply = NumericReply::new(
+                        ReplyType::SaslMechs as u16,
+                        &nick,
+                        &[&offered],
+                        "are available SASL mechanisms",
+                    );
+                    Self::send_reply(conn_write, reply).await;
+                    let reply = NumericReply::error(ErrorType::SaslFail, &nick, &[]);
+                    Self::send_reply(conn_write, reply).await;
+                }
+            }
+            return None;
+        };
+
+        let payload = match session.push(&param) {
+            Chunk::Incomplete => return None,
+            Chunk::Complete(payload) => payload,
+            Chunk::TooLong => {
+                client.sasl = None;
+                let reply = NumericReply::error(ErrorType::SaslTooLong, &nick, &[]);
+                Self::send_reply(conn_write, reply).await;
+                return None;
+            }
+            Chunk::Invalid => {
+                client.sasl = None;
+                let reply = NumericReply::error(ErrorType::SaslFail, &nick, &[]);
+                Self::send_reply(conn_write, reply).await;
+                return None;
+            }
+        };
+        let scram_state = session.scram.take();
+        let certfp = client.certfp.clone();
+        let ip = client.ip;
+        let uses_password = matches!(session.mechanism, Mechanism::Plain | Mechanism::ScramSha256);
+        if uses_password && self.login_throttle.is_throttled(ip) {
+            client.sasl = None;
+            let text = "Too many failed attempts, try again later";
+            let reply = NumericReply::new(ErrorType::SaslFail as u16, &nick, &[], text);
+            Self::send_reply(conn_write, reply).await;
+            return None;
+        }
+
+        let account = match session.mechanism {
+            Mechanism::Plain => {
+                let creds = PlainCredentials::parse(&payload).filter(|creds| {
+                    creds.authzid.is_empty() || irc_eq(&creds.authzid, &creds.authcid)
+                });
+                let account = creds
+                    .as_ref()
+                    .and_then(|creds| self.accounts.get(&creds.authcid));
+                if let (Some(creds), Some(account)) = (creds.as_ref(), account) {
+                    client.sasl = None;
+                    let hash = account.password.clone();
+                    let then = Then::SaslPlain {
+                        account: account.name.clone(),
+                    };
+                    let job = match account.scram {
+                        Some(_) => PasswordJob::verify(&creds.password, vec![hash], then),
+                        None => PasswordJob::verify_with_scram(&creds.password, hash, then),
+                    };
+                    return Some(job);
+                }
+                None
+            }
+            // EXTERNAL may name the account to use; it must be the one that
+            // trusts the certificate.
+            Mechanism::External => certfp
+                .and_then(|certfp| self.accounts.find_by_certfp(&certfp))
+                .filter(|account| match std::str::from_utf8(&payload) {
+                    Ok(authzid) => authzid.is_empty() || irc_eq(authzid, &account.name),
+                    Err(_) => false,
+                })
+                .map(|account| account.name.clone()),
+            Mechanism::ScramSha256 => match scram::step(scram_state, &payload, &self.accounts) {
+                Ok(ScramStep::Continue(state, challenge)) => {
+                    session.scram = Some(state);
+                    let mut conn_write = conn_write.lock().await;
+                    for chunk in encode_chunks(challenge.as_bytes()) {
+                        let message = Message::new(Command::Authenticate(chunk));
+                        let _ = conn_write.write_message(&message.to_string()).await;
+                    }
+                    return None;
+                }
+                Ok(ScramStep::Done(account)) => Some(account),
+                Err(_) => None,
+            },
+        };
+        client.sasl = None;
+        self.finish_sasl(account, uses_password, conn_write, id)
+            .await;
+        None
+    }
+
+    /// Ends a SASL exchange, logging the client on `id` in to `account` if
+    /// it authenticated. Failed password mechanisms count towards the login
+    /// throttle.
+    async fn finish_sasl(
+        &mut self,
+        account: Option<String>,
+        uses_password: bool,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(client) = self.clients.get(id) else {
+            return;
+        };
+        let nick = client.nick.clone();
+        let ip = client.ip;
+        if uses_password {
+            match account {
+                Some(_) => self.login_throttle.clear(ip),
+                None => self.login_throttle.record_failure(ip),
+            }
+        }
+
+        match account {
+            Some(account) => {
+                self.log_in(id, account, conn_write).await;
+                let reply = NumericReply::new(
+                    ReplyType::SaslSuccess as u16,
+                    &nick,
+                    &[],
+                    "SASL authentication successful",
+                );
+                Self::send_reply(conn_write, reply).await;
+            }
+            None => {
+                let reply = NumericReply::error(ErrorType::SaslFail, &nick, &[]);
+                Self::send_reply(conn_write, reply).await;
+            }
+        }
+    }
+
+    /// Marks the client on `id` as logged in to `account` (user mode +r).
+    async fn log_in(
+        &mut self,
+        id: &str,
+        account: String,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+    ) {
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        client.account = Some(account.clone());
+        client.modes.identified = true;
+        let nick = client.nick.clone();
+        let prefix = client.prefix();
+
+        let text = format!("You are now logged in as {account}");
+        let reply = NumericReply::new(
+            ReplyType::LoggedIn as u16,
+            &nick,
+            &[&prefix, &account],
+            &text,
+        );
+        Self::send_reply(conn_write, reply).await;
+        self.check_nick_ownership(id).await;
+        self.announce_memos(id).await;
+    }
+
+    /// Starts the grace period for a client using a registered nick that it
+    /// is not logged in to, or ends it once the nick is its own.
+    async fn check_nick_ownership(&mut self, id: &str) {
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        let protected = self.accounts.get(&client.nick).is_some_and(|owner| {
+            !client
+                .account
+                .as_ref()
+                .is_some_and(|account| irc_eq(account, &owner.name))
+        });
+        if !client.registered || !protected {
+            client.nick_deadline = None;
+            return;
+        }
+        if client.nick_deadline.is_some() {
+            return;
+        }
+        let grace = self.config.services.nick_grace_seconds;
+        client.nick_deadline = Some(Instant::now() + Duration::from_secs(grace));
+        let nick = client.nick.clone();
+        let text = format!(
+            "This nickname is registered. Identify with /msg {NICKSERV} IDENTIFY <password> \
+             within {grace} seconds, or your nick will be changed."
+        );
+        self.nickserv_reply(&nick, &text).await;
+    }
+
+    /// Renames every client whose grace period to identify has run out.
+    pub async fn enforce_nick_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.nick_deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in expired {
+            let guest = self.guest_nick();
+            let nick = self.client_nick(&id);
+            let text = format!("You did not identify in time, so your nick is now {guest}.");
+            self.nickserv_reply(&nick, &text).await;
+            self.rename_client(&id, guest).await;
+        }
+    }
+
+    /// A free, unregistered nick to move a client to.
+    fn guest_nick(&self) -> String {
+        loop {
+            // Four digits keep it within the nine characters NICK allows.
+            let nick = format!("Guest{:04}", rand::thread_rng().gen_range(0..10_000));
+            if self.find_client_id(&nick).is_none() && self.accounts.get(&nick).is_none() {
+                return nick;
+            }
+        }
+    }
+
+    /// Changes the nick of the registered client on `id`, telling it and
+    /// everyone who shares a channel with it.
+    async fn rename_client(&mut self, id: &str, new_nick: String) {
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        let old_prefix = client.prefix();
+        let old_nick = std::mem::replace(&mut client.nick, new_nick.clone());
+        client.nick_deadline = None;
+        if let Some(conn_write) = self.connection_map.remove(&IrcKey::new(&old_nick)) {
+            self.connection_map
+                .insert(IrcKey::new(&new_nick), conn_write);
+        }
+
+        let mut notified = vec![IrcKey::new(&new_nick)];
+        for channel in &mut self.channels {
+            if !channel.rename_member(&old_nick, &new_nick) {
+                continue;
+            }
+            for nick in &channel.clients {
+                let key = IrcKey::new(nick);
+                if !notified.contains(&key) {
+                    notified.push(key);
+                }
+            }
+        }
+        let nick = NickMsg {
+            nick: Nick(new_nick),
+        };
+        let message = Message::with_prefix(&old_prefix, Command::Nick(nick)).to_string();
+        for key in notified {
+            if let Some(conn_write) = self.connection_map.get(&key) {
+                let _ = conn_write.lock().await.write_message(&message).await;
+            }
+        }
+        self.check_nick_ownership(id).await;
+    }
+
+    async fn nickserv_reply(&self, nick: &str, text: &str) {
+        self.send_privmsg_from_server(&service_prefix(NICKSERV), nick, text)
+            .await;
+    }
+
+    /// Carries out a PRIVMSG to NickServ from the client on `id`, up to the
+    /// password check or hash it may need.
+    async fn handle_nickserv_message(
+        &mut self,
+        id: &str,
+        text: &str,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+    ) -> Option<PasswordJob> {
+        let client = self.clients.get(id).cloned()?;
+        let nick = client.nick.as_str();
+        let command = match NickServCommand::parse(text) {
+            Ok(command) => command,
+            Err(usage) => {
+                self.nickserv_reply(nick, &usage).await;
+                return None;
+            }
+        };
+
+        match command {
+            NickServCommand::Register { password } => {
+                let reply = if let Some(account) = &client.account {
+                    format!("You are already logged in as {account}.")
+                } else if self.accounts.get(nick).is_some() {
+                    format!("{nick} is already registered.")
+                } else if password.len() < MIN_PASSWORD_LEN {
+                    format!("Passwords must be at least {MIN_PASSWORD_LEN} characters long.")
+                } else {
+                    let then = Then::Register {
+                        nick: nick.to_string(),
+                    };
+                    return Some(PasswordJob::hash(&password, then));
+                };
+                self.nickserv_reply(nick, &reply).await;
+            }
+            NickServCommand::Identify { account, password } => {
+                if let Some(current) = &client.account {
+                    let reply = format!("You are already logged in as {current}.");
+                    self.nickserv_reply(nick, &reply).await;
+                    return None;
+                }
+                if self.login_throttle.is_throttled(client.ip) {
+                    self.nickserv_reply(nick, TOO_MANY_FAILURES).await;
+                    return None;
+                }
+                let name = account.unwrap_or_else(|| nick.to_string());
+                let Some(account) = self.accounts.get(&name) else {
+                    self.finish_identify(&name, None, conn_write, id).await;
+                    return None;
+                };
+                let hash = account.password.clone();
+                let then = Then::Identify { account: name };
+                return Some(match account.scram {
+                    Some(_) => PasswordJob::verify(&password, vec![hash], then),
+                    None => PasswordJob::verify_with_scram(&password, hash, then),
+                });
+            }
+            NickServCommand::Ghost {
+                nick: ghost,
+                password,
+            } => {
+                let reply = match self.ghost_owner(&client, &ghost) {
+                    Err(reply) => reply,
+                    Ok(None) => self.ghost(&client, &ghost).await,
+                    Ok(Some(_)) if password.is_none() => "Access denied.".to_string(),
+                    Ok(Some(_)) if self.login_throttle.is_throttled(client.ip) => {
+                        TOO_MANY_FAILURES.to_string()
+                    }
+                    Ok(Some(hash)) => {
+                        let password = password.unwrap_or_default();
+                        let then = Then::Ghost { ghost };
+                        return Some(PasswordJob::verify(&password, vec![hash], then));
+                    }
+                };
+                self.nickserv_reply(nick, &reply).await;
+            }
+            NickServCommand::Drop { password } => {
+                let reply = match &client.account {
+                    None => "You are not logged in.".to_string(),
+                    Some(_) if self.login_throttle.is_throttled(client.ip) => {
+                        TOO_MANY_FAILURES.to_string()
+                    }
+                    Some(account) => {
+                        let hashes = self
+                            .accounts
+                            .get(account)
+                            .map(|account| account.password.clone())
+                            .into_iter()
+                            .collect();
+                        let then = Then::Drop {
+                            account: account.clone(),
+                        };
+                        return Some(PasswordJob::verify(&password, hashes, then));
+                    }
+                };
+                self.nickserv_reply(nick, &reply).await;
+            }
+            NickServCommand::SetPassword { password } => {
+                let reply = match &client.account {
+                    None => "You are not logged in.".to_string(),
+                    Some(_) if password.len() < MIN_PASSWORD_LEN => {
+                        format!("Passwords must be at least {MIN_PASSWORD_LEN} characters long.")
+                    }
+                    Some(account) => {
+                        let then = Then::SetPassword {
+                            account: account.clone(),
+                        };
+                        return Some(PasswordJob::hash(&password, then));
+                    }
+                };
+                self.nickserv_reply(nick, &reply).await;
+            }
+            NickServCommand::Info { nick: target } => {
+                let target = target.as_deref().unwrap_or(nick);
+                let Some(account) = self.accounts.get(target) else {
+                    let reply = format!("{target} is not registered.");
+                    self.nickserv_reply(nick, &reply).await;
+                    return None;
+                };
+                let registered = format_timestamp(account.registered_at);
+                let mut online = self
+                    .clients
+                    .values()
+                    .filter(|other| {
+                        other
+                            .account
+                            .as_ref()
+                            .is_some_and(|name| irc_eq(name, &account.name))
+                    })
+                    .map(|other| other.nick.clone())
+                    .collect::<Vec<_>>();
+                online.sort();
+                let online = if online.is_empty() {
+                    "Logged in from: nowhere".to_string()
+                } else {
+                    format!("Logged in from: {}", online.join(" "))
+                };
+                let lines = [
+                    format!("Information on {}:", account.name),
+                    format!("Registered: {registered}"),
+                    online,
+                ];
+                for line in lines {
+                    self.nickserv_reply(nick, &line).await;
+                }
+            }
+            NickServCommand::Help => {
+                for line in nickserv::HELP {
+                    self.nickserv_reply(nick, line).await;
+                }
+            }
+        }
+        None
+    }
+
+    /// Acts on a password check or hash that a handler returned, once the
+    /// client loop has run it. Anything that changed while it ran, such as
+    /// an account being dropped, is checked again here.
+    pub async fn finish_password_job(
+        &mut self,
+        job: FinishedJob,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        match (job.then, job.outcome) {
+            (Then::Oper { name }, Outcome::Verified { matched, .. }) => {
+                self.finish_oper(&name, matched, conn_write, id).await;
+            }
+            (Then::Webirc(webirc), Outcome::Verified { matched, .. }) => {
+                self.finish_webirc(webirc, matched, conn_write, id).await;
+            }
+            (Then::SaslPlain { account }, Outcome::Verified { matched, scram }) => {
+                let account = matched
+                    .and_then(|hash| self.accounts.logged_in(&account, &hash, scram))
+                    .map(|account| account.name.clone());
+                self.finish_sasl(account, true, conn_write, id).await;
+            }
+            (Then::Identify { account }, Outcome::Verified { matched, scram }) => {
+                let verified = matched.map(|hash| (hash, scram));
+                self.finish_identify(&account, verified, conn_write, id)
+                    .await;
+            }
+            (Then::Ghost { ghost }, Outcome::Verified { matched, .. }) => {
+                self.finish_ghost(&ghost, matched, id).await;
+            }
+            (Then::Drop { account }, Outcome::Verified { matched, .. }) => {
+                self.finish_drop(&account, matched, id).await;
+            }
+            (Then::Register { nick }, Outcome::Hashed(hashes)) => {
+                self.finish_register(&nick, hashes, conn_write, id).await;
+            }
+            (Then::SetPassword { account }, Outcome::Hashed(hashes)) => {
+                let Some(client) = self.clients.get(id) else {
+                    return;
+                };
+                let nick = client.nick.clone();
+                let reply = match self.accounts.set_password(&account, hashes) {
+                    Ok(true) => "Your password has been changed.",
+                    Ok(false) => "You are not logged in.",
+                    Err(_) => NOT_SAVED,
+                };
+                self.nickserv_reply(&nick, reply).await;
+            }
+            (then, outcome) => unreachable!("{then:?} cannot finish with {outcome:?}"),
+        }
+    }
+
+    /// Logs the client on `id` in to `name` if its password matched, with
+    /// the matching hash and any SCRAM keys derived on the way.
+    async fn finish_identify(
+        &mut self,
+        name: &str,
+        verified: Option<(String, Option<ScramCredentials>)>,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(client) = self.clients.get(id) else {
+            return;
+        };
+        let (nick, ip) = (client.nick.clone(), client.ip);
+        let account = verified
+            .and_then(|(hash, scram)| self.accounts.logged_in(name, &hash, scram))
+            .map(|account| account.name.clone());
+        match account {
+            Some(account) => {
+                self.login_throttle.clear(ip);
+                let reply = format!("You are now identified for {account}.");
+                self.nickserv_reply(&nick, &reply).await;
+                self.log_in(id, account, conn_write).await;
+            }
+            None => {
+                self.login_throttle.record_failure(ip);
+                let reply = format!("Invalid password for {name}.");
+                self.nickserv_reply(&nick, &reply).await;
+            }
+        }
+    }
+
+    /// Disconnects `ghost` for the client on `id` if the password matched
+    /// `matched`, the hash of the account owning that nick.
+    async fn finish_ghost(&mut self, ghost: &str, matched: Option<String>, id: &str) {
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
+        let reply = match self.ghost_owner(&client, ghost) {
+            Err(reply) => reply,
+            Ok(None) => self.ghost(&client, ghost).await,
+            Ok(Some(hash)) if matched.as_ref() == Some(&hash) => {
+                self.login_throttle.clear(client.ip);
+                self.ghost(&client, ghost).await
+            }
+            Ok(Some(_)) => {
+                self.login_throttle.record_failure(client.ip);
+                "Access denied.".to_string()
+            }
+        };
+        self.nickserv_reply(&client.nick, &reply).await;
+    }
+
+    /// Drops `account` for the client on `id` if the password matched
+    /// `matched`, the account's hash.
+    async fn finish_drop(&mut self, account: &str, matched: Option<String>, id: &str) {
+        let Some(client) = self.clients.get(id) else {
+            return;
+        };
+        let (nick, ip) = (client.nick.clone(), client.ip);
+        let verified = matched.is_some_and(|hash| {
+            self.accounts
+                .get(account)
+                .is_some_and(|account| account.password == hash)
+        });
+        let reply = if !verified {
+            self.login_throttle.record_failure(ip);
+            "Invalid password.".to_string()
+        } else if self.drop_account(account).is_err() {
+            NOT_SAVED.to_string()
+        } else {
+            format!("Account {account} has been dropped.")
+        };
+        self.nickserv_reply(&nick, &reply).await;
+    }
+
+    /// Deletes `account` with its channels and memos, and logs out everyone
+    /// using it. The account itself goes last, so if storage fails part way
+    /// it still exists and can be dropped again.
+    fn drop_account(&mut self, account: &str) -> Result<(), StorageError> {
+        self.channel_registry.forget_account(account)?;
+        self.memos.delete_all(account)?;
+        self.accounts.remove(account)?;
+        for other in self.clients.values_mut() {
+            if other.account.as_deref() == Some(account) {
+                other.account = None;
+                other.modes.identified = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers `nick` with the password behind `hashes` and logs the
+    /// client on `id` in to it, unless someone registered it first.
+    async fn finish_register(
+        &mut self,
+        nick: &str,
+        hashes: PasswordHashes,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(current) = self.clients.get(id).map(|client| client.nick.clone()) else {
+            return;
+        };
+        let reply = match self.accounts.add(Account::new(nick, hashes)) {
+            Ok(true) => None,
+            Ok(false) => Some(format!("{nick} is already registered.")),
+            Err(_) => Some(NOT_SAVED.to_string()),
+        };
+        if let Some(reply) = reply {
+            self.nickserv_reply(&current, &reply).await;
+            return;
+        }
+        let reply = format!("{nick} is now registered to you.");
+        self.nickserv_reply(&current, &reply).await;
+        self.log_in(id, nick.to_string(), conn_write).await;
+    }
+
+    async fn chanserv_reply(&self, nick: &str, text: &str) {
+        self.send_privmsg_from_server(&service_prefix(CHANSERV), nick, text)
+            .await;
+    }
+
+    /// The registered channel `name`, if `client` is logged in to an account
+    /// with at least `level` access to it. Otherwise, the reply saying why
+    /// not.
+    fn channel_access(
+        &self,
+        client: &Client,
+        name: &str,
+        level: AccessLevel,
+    ) -> Result<&RegisteredChannel, String> {
+        let registered = self
+            .channel_registry
+            .get(name)
+            .ok_or_else(|| format!("{name} is not registered."))?;
+        let Some(account) = &client.account else {
+            return Err(format!("You must identify to {NICKSERV} first."));
+        };
+        match registered.access_level(account) {
+            Some(have) if have >= level => Ok(registered),
+            _ => Err("Access denied.".to_string()),
+        }
+    }
+
+    /// Carries out a PRIVMSG to ChanServ from the client on `id`.
+    async fn handle_chanserv_message(&mut self, id: &str, text: &str) {
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
+        let nick = client.nick.as_str();
+        let command = match ChanServCommand::parse(text) {
+            Ok(command) => command,
+            Err(usage) => {
+                self.chanserv_reply(nick, &usage).await;
+                return;
+            }
+        };
+
+        let lines = match command {
+            ChanServCommand::Register { channel } => {
+                let channel_index = self
+                    .get_channel(&channel)
+                    .filter(|&index| self.channels[index].is_op(nick));
+                let reply = match (&client.account, channel_index) {
+                    (None, _) => format!("You must identify to {NICKSERV} first."),
+                    _ if self.channel_registry.get(&channel).is_some() => {
+                        format!("{channel} is already registered.")
+                    }
+                    (Some(account), Some(index)) => {
+                        let registered = RegisteredChannel::new(&self.channels[index], account);
+                        let reply = format!("{} is now registered to {account}.", registered.name);
+                        match self.channel_registry.add(registered) {
+                            Ok(_) => reply,
+                            Err(_) => NOT_SAVED.to_string(),
+                        }
+                    }
+                    (Some(_), None) => {
+                        format!("You must be a channel operator in {channel} to register it.")
+                    }
+                };
+                vec![reply]
+            }
+            ChanServCommand::AccessAdd {
+                channel,
+                account,
+                level,
+            } => {
+                let reply = match self.channel_access(&client, &channel, AccessLevel::Founder) {
+                    Err(reply) => reply,
+                    Ok(registered) if irc_eq(&registered.founder, &account) => {
+                        format!("{account} is the founder of {}.", registered.name)
+                    }
+                    Ok(registered) => match self.accounts.get(&account) {
+                        None => format!("{account} is not registered."),
+                        Some(account) => {
+                            let account = account.name.clone();
+                            let name = registered.name.clone();
+                            let updated = self.channel_registry.update(&name, |registered| {
+                                registered
+                                    .access
+                                    .retain(|entry| !irc_eq(&entry.account, &account));
+                                registered.access.push(AccessEntry {
+                                    account: account.clone(),
+                                    level,
+                                });
+                            });
+                            match updated {
+                                Ok(_) => format!("{account} now has {level} access to {name}."),
+                                Err(_) => NOT_SAVED.to_string(),
+                            }
+                        }
+                    },
+                };
+                vec![reply]
+            }
+            ChanServCommand::AccessDel { channel, account } => {
+                let reply = match self.channel_access(&client, &channel, AccessLevel::Founder) {
+                    Err(reply) => reply,
+                    Ok(registered) => {
+                        let name = registered.name.clone();
+                        let listed = registered
+                            .access
+                            .iter()
+                            .any(|entry| irc_eq(&entry.account, &account));
+                        let removed = listed.then(|| {
+                            self.channel_registry.update(&name, |registered| {
+                                registered
+                                    .access
+                                    .retain(|entry| !irc_eq(&entry.account, &account));
+                            })
+                        });
+                        match removed {
+                            Some(Ok(_)) => format!(
+                                "{account} has been removed from the access list of {name}."
+                            ),
+                            Some(Err(_)) => NOT_SAVED.to_string(),
+                            None => format!("{account} is not on the access list of {name}."),
+                        }
+                    }
+                };
+                vec![reply]
+            }
+            ChanServCommand::AccessList { channel } => {
+                match self.channel_access(&client, &channel, AccessLevel::Voice) {
+                    Err(reply) => vec![reply],
+                    Ok(registered) => {
+                        let mut lines = vec![
+                            format!("Access list for {}:", registered.name),
+                            format!("{} {}", registered.founder, AccessLevel::Founder),
+                        ];
+                        lines.extend(
+                            registered
+                                .access
+                                .iter()
+                                .map(|entry| format!("{} {}", entry.account, entry.level)),
+                        );
+                        lines.push("End of access list.".to_string());
+                        lines
+                    }
+                }
+            }
+            ChanServCommand::Info { channel } => match self.channel_registry.get(&channel) {
+                None => vec![format!("{channel} is not registered.")],
+                Some(registered) => {
+                    let mut lines = vec![
+                        format!("Information on {}:", registered.name),
+                        format!("Founder: {}", registered.founder),
+                        format!("Registered: {}", format_timestamp(registered.registered_at)),
+                    ];
+                    if let Some(topic) = &registered.topic {
+                        lines.push(format!("Topic: {}", topic.text));
+                    }
+                    lines
+                }
+            },
+            ChanServCommand::Drop { channel } => {
+                let reply = match self.channel_access(&client, &channel, AccessLevel::Founder) {
+                    Err(reply) => reply,
+                    Ok(registered) => {
+                        let name = registered.name.clone();
+                        match self.channel_registry.remove(&name) {
+                            Ok(_) => format!("{name} has been dropped."),
+                            Err(_) => NOT_SAVED.to_string(),
+                        }
+                    }
+                };
+                vec![reply]
+            }
+            ChanServCommand::Help => chanserv::HELP.iter().map(|line| line.to_string()).collect(),
+        };
+        for line in lines {
+            self.chanserv_reply(nick, &line).await;
+        }
+    }
+
+    async fn memoserv_reply(&self, nick: &str, text: &str) {
+        self.send_privmsg_from_server(&service_prefix(MEMOSERV), nick, text)
+            .await;
+    }
+
+    /// Tells the client on `id` how many unread memos its account has.
+    async fn announce_memos(&self, id: &str) {
+        let Some(client) = self.clients.get(id).filter(|client| client.registered) else {
+            return;
+        };
+        let Some(account) = &client.account else {
+            return;
+        };
+        let text = match self.memos.unread(account) {
+            0 => return,
+            1 => format!("You have 1 new memo. Use /msg {MEMOSERV} LIST to see it."),
+            unread => format!("You have {unread} new memos. Use /msg {MEMOSERV} LIST to see them."),
+        };
+        self.memoserv_reply(&client.nick, &text).await;
+    }
+
+    /// Carries out a PRIVMSG to MemoServ from the client on `id`.
+    async fn handle_memoserv_message(&mut self, id: &str, text: &str) {
+        let Some(client) = self.clients.get(id).cloned() else {
+            return;
+        };
+        let nick = client.nick.as_str();
+        let command = match MemoServCommand::parse(text) {
+            Ok(command) => command,
+            Err(usage) => {
+                self.memoserv_reply(nick, &usage).await;
+                return;
+            }
+        };
+        if command == MemoServCommand::Help {
+            for line in memoserv::HELP {
+                self.memoserv_reply(nick, line).await;
+            }
+            return;
+        }
+        let Some(account) = client.account.as_deref() else {
+            let reply = format!("You must identify to {NICKSERV} first.");
+            self.memoserv_reply(nick, &reply).await;
+            return;
+        };
+
+        let lines = match command {
+            MemoServCommand::Send { account: to, text } => {
+                match self.accounts.get(&to).map(|to| to.name.clone()) {
+                    None => vec![format!("{to} is not registered.")],
+                    Some(to) => {
+                        let quota = self.config.services.memo_quota;
+                        match self.memos.send(Memo::new(account, &to, &text), quota) {
+                            Ok(None) => vec![format!("{to}'s memo box is full.")],
+                            Ok(Some(number)) => {
+                                self.notify_new_memo(account, &to, number).await;
+                                vec![format!("Your memo to {to} has been sent.")]
+                            }
+                            Err(_) => vec![NOT_SAVED.to_string()],
+                        }
+                    }
+                }
+            }
+            MemoServCommand::List => {
+                let inbox = self.memos.inbox(account);
+                if inbox.is_empty() {
+                    vec!["You have no memos.".to_string()]
+                } else {
+                    let mut lines = vec![format!("Memos for {account}:")];
+                    lines.extend(inbox.iter().enumerate().map(|(index, memo)| {
+                        let new = if memo.read { "" } else { " (new)" };
+                        format!(
+                            "{}. From {} at {}{new}",
+                            index + 1,
+                            memo.from,
+                            format_timestamp(memo.sent_at)
+                        )
+                    }));
+                    lines.push(format!("Use /msg {MEMOSERV} READ <number> to read a memo."));
+                    lines
+                }
+            }
+            MemoServCommand::Read { number } => match self.memos.read(account, number) {
+                None => vec![format!("You have no memo {number}.")],
+                Some(memo) => vec![
+                    format!(
+                        "Memo {number} from {}, sent {}:",
+                        memo.from,
+                        format_timestamp(memo.sent_at)
+                    ),
+                    memo.text,
+                ],
+            },
+            MemoServCommand::Del { number: None } => match self.memos.delete_all(account) {
+                Ok(deleted) => vec![format!("Deleted {deleted} memos.")],
+                Err(_) => vec![NOT_SAVED.to_string()],
+            },
+            MemoServCommand::Del {
+                number: Some(number),
+            } => match self.memos.delete(account, number) {
+                Ok(true) => vec![format!("Memo {number} has been deleted.")],
+                Ok(false) => vec![format!("You have no memo {number}.")],
+                Err(_) => vec![NOT_SAVED.to_string()],
+            },
+            // Answered above, without needing an account.
+            MemoServCommand::Help => Vec::new(),
+        };
+        for line in lines {
+            self.memoserv_reply(nick, &line).await;
+        }
+    }
+
+    /// Tells every client logged in to `to` about memo `number` from `from`.
+    async fn notify_new_memo(&self, from: &str, to: &str, number: usize) {
+        let text = format!(
+            "You have a new memo from {from}. Use /msg {MEMOSERV} READ {number} to read it."
+        );
+        let recipients = self.clients.values().filter(|client| {
+            client.registered
+                && client
+                    .account
+                    .as_ref()
+                    .is_some_and(|account| irc_eq(account, to))
+        });
+        for recipient in recipients {
+            self.memoserv_reply(&recipient.nick, &text).await;
+        }
+    }
+
+    /// Whether the client may ghost `ghost`: `Ok(None)` if it is logged in
+    /// to the account owning that nick, `Ok(Some(hash))` if it must give
+    /// that account's password, and otherwise the reply saying why not.
+    fn ghost_owner(&self, client: &Client, ghost: &str) -> Result<Option<String>, String> {
+        if !self.connection_map.contains_key(&IrcKey::new(ghost)) {
+            return Err(format!("{ghost} is not online."));
+        }
+        if irc_eq(&client.nick, ghost) {
+            return Err("You cannot ghost yourself.".to_string());
+        }
+        let owner = self
+            .accounts
+            .get(ghost)
+            .ok_or_else(|| format!("{ghost} is not registered."))?;
+        let logged_in = client
+            .account
+            .as_ref()
+            .is_some_and(|account| irc_eq(account, &owner.name));
+        Ok((!logged_in).then(|| owner.password.clone()))
+    }
+
+    /// Disconnects the client using `ghost` on behalf of `client`, once
+    /// `ghost_owner` allows it.
+    async fn ghost(&mut self, client: &Client, ghost: &str) -> String {
+        let (Some(ghost_id), Some(ghost_conn_write)) = (
+            self.find_client_id(ghost),
+            self.connection_map.get(&IrcKey::new(ghost)).cloned(),
+        ) else {
+            return format!("{ghost} is not online.");
+        };
+        let reason = format!(
+            "Killed ({NICKSERV} (GHOST command used by {}))",
+            client.nick
+        );
+        self.disconnect(&ghost_id, &reason, &ghost_conn_write).await;
+        format!("{ghost} has been ghosted.")
+    }
+
+    async fn send_cap(
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+        target: &str,
+        subcommand: &str,
+        more: bool,
+        caps: &str,
+    ) {
+        let cap = CapMsg::Reply {
+            target: target.to_string(),
+            subcommand: subcommand.to_string(),
+            more,
+            caps: caps.to_string(),
+        };
+        let message = Message::with_prefix(SERVER_NAME, Command::Cap(cap)).to_string();
+        let mut conn_write = conn_write.lock().await;
+        let _ = conn_write.write_message(&message).await;
+    }
+
+    /// Sends `tokens` as a CAP LS or LIST reply. Clients that asked for
+    /// version 302 may get it split over several lines; others get one.
+    async fn send_cap_lines(
+        conn_write: &Arc<Mutex<ConnectionWrite>>,
+        target: &str,
+        subcommand: &str,
+        tokens: &[String],
+        version: u32,
+    ) {
+        if version < CAP_VERSION_302 {
+            Self::send_cap(conn_write, target, subcommand, false, &tokens.join(" ")).await;
+            return;
+        }
+        // `:iris-server CAP <target> <subcommand> * :` and the `\r\n`.
+        let overhead = SERVER_NAME.len() + target.len() + subcommand.len() + 12;
+        let lines = cap_lines(tokens, 512 - overhead);
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            Self::send_cap(conn_write, target, subcommand, i < last, line).await;
+        }
+    }
+
+    /// Starts offering a capability, or changes its value, and tells every
+    /// client that has enabled `cap-notify`.
+    pub async fn advertise_capability(&mut self, name: &str, value: Option<&str>) {
+        if !self.capabilities.add(name, value) {
+            return;
+        }
+        let Some(capability) = self.capabilities.get(name).cloned() else {
+            return;
+        };
+        for (id, client) in &self.clients {
+            if !client.has_capability("cap-notify") {
+                continue;
+            }
+            if let Some(conn_write) = self.writer(id, client) {
+                let token = capability.token(client.cap_version);
+                Self::send_cap(conn_write, client.cap_target(), "NEW", false, &token).await;
+            }
+        }
+    }
+
+    /// Stops offering a capability, turning it off for every client and
+    /// telling those that have enabled `cap-notify`.
+    pub async fn withdraw_capability(&mut self, name: &str) {
+        if !self.capabilities.remove(name) {
+            return;
+        }
+        for client in self.clients.values_mut() {
+            client.capabilities.remove(name);
+        }
+        for (id, client) in &self.clients {
+            if !client.has_capability("cap-notify") {
+                continue;
+            }
+            if let Some(conn_write) = self.writer(id, client) {
+                Self::send_cap(conn_write, client.cap_target(), "DEL", false, name).await;
+            }
+        }
+    }
+
+    pub async fn handle_ping_command(
+        &self,
+        origin: String,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+    ) {
+        let pong = Message::new(Command::Pong(origin));
+        let mut conn_write = conn_write.lock().await;
+        let _ = conn_write.write_message(&pong.to_string()).await;
+    }
+
+    pub async fn handle_user_command(&mut self, user: UserMsg, id: &str) {
+        // update username and realname for client
+        if let Some(client) = self.clients.get_mut(id) {
+            client.username = Some(user.username);
+            client.realname = Some(user.real_name);
+        }
+    }
+
+    pub async fn handle_nick_command(
+        &mut self,
+        new_nick: Nick,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        let new_nick = new_nick.0;
+        if is_service(&new_nick)
+            || self
+                .find_client_id(&new_nick)
+                .is_some_and(|other_id| other_id != id)
+        {
+            let reply = NumericReply::error(ErrorType::NicknameInUse, &nick, &[&new_nick]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        if client.registered {
+            self.rename_client(id, new_nick).await;
+        } else {
+            client.nick = new_nick;
+            println!("New client with nickname {}", client.nick);
+        }
+    }
+
+    /// WEBIRC <password> <gateway> <hostname> <ip> [:<options>]
+    ///
+    /// Lets a configured gateway say who it is connecting for. The client
+    /// takes on the user's host, IP and TLS status before registration, so
+    /// bans and everything after them apply to the user, not the gateway.
+    /// The user only counts as secure if the gateway's own link is as well.
+    ///
+    /// Returns the password check for the client loop to run; the rest is
+    /// done by `finish_webirc`.
+    pub async fn handle_webirc_command(
+        &mut self,
+        webirc: WebircMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) -> Option<PasswordJob> {
+        let client = self.clients.get(id)?;
+        if client.registered || client.gateway.is_some() {
+            let reply = NumericReply::error(ErrorType::AlreadyRegistered, &client.nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return None;
+        }
+
+        let source = client.ip;
+        let hashes = self
+            .config
+            .webirc_gateways
+            .iter()
+            .filter(|gateway| gateway.hosts.iter().any(|mask| cidr_contains(mask, source)))
+            .map(|gateway| gateway.password.clone())
+            .collect::<Vec<_>>();
+        if hashes.is_empty() {
+            self.finish_webirc(webirc, None, conn_write, id).await;
+            return None;
+        }
+        if self.login_throttle.is_throttled(source) {
+            eprintln!("[WARN] refused WEBIRC from {source}: too many failures");
+            self.disconnect(id, TOO_MANY_FAILURES, conn_write).await;
+            return None;
+        }
+        let password = webirc.password.clone();
+        Some(PasswordJob::verify(&password, hashes, Then::Webirc(webirc)))
+    }
+
+    /// Gives the client on `id` the user's host and IP from `webirc` if the
+    /// password matched `matched`, the hash of a gateway it may connect
+    /// from.
+    async fn finish_webirc(
+        &mut self,
+        webirc: WebircMsg,
+        matched: Option<String>,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(client) = self.clients.get(id) else {
+            return;
+        };
+        let source = client.ip;
+        // The user's traffic is only as safe as its weakest hop, so the
+        // gateway's link to the server must be secure too.
+        let link_secure = client.modes.secure || client.uid.is_some();
+        // Only a host some gateway may connect from had its password checked.
+        let trusted = self
+            .config
+            .webirc_gateways
+            .iter()
+            .any(|gateway| gateway.hosts.iter().any(|mask| cidr_contains(mask, source)));
+        let gateway = matched.and_then(|matched| {
+            self.config.webirc_gateways.iter().find(|gateway| {
+                gateway.hosts.iter().any(|mask| cidr_contains(mask, source))
+                    && gateway.password == matched
+            })
+        });
+        let Some(gateway) = gateway.map(|gateway| gateway.name.clone()) else {
+            if trusted {
+                self.login_throttle.record_failure(source);
+            }
+            eprintln!("[WARN] rejected WEBIRC from {source} ({})", webirc.gateway);
+            self.disconnect(id, "Invalid WebIRC credentials", conn_write)
+                .await;
+            return;
+        };
+        self.login_throttle.clear(source);
+        let Ok(ip) = webirc.ip.parse::<IpAddr>().map(|ip| ip.to_canonical()) else {
+            self.disconnect(id, "Invalid WebIRC IP address", conn_write)
+                .await;
+            return;
+        };
+        let host = if valid_hostname(&webirc.hostname) {
+            webirc.hostname.clone()
+        } else {
+            ip_host(ip)
+        };
+
+        println!("[INFO] WEBIRC from {gateway} at {source} for {host} ({ip})");
+        let Some(client) = self.clients.get_mut(id) else {
+            return;
+        };
+        client.ip = ip;
+        client.host = host;
+        client.modes.secure = webirc.secure() && link_secure;
+        // Neither the gateway's certificate nor its local account is the
+        // user's.
+        client.certfp = None;
+        client.uid = None;
+        client.gateway = Some(gateway);
+    }
+
+    /// OPER <name> <password>
+    ///
+    /// Returns the password check for the client loop to run; the rest is
+    /// done by `finish_oper`.
+    pub async fn handle_oper_command(
+        &mut self,
+        oper: OperMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) -> Option<PasswordJob> {
+        let ip = self.clients.get(id)?.ip;
+        let nick = self.client_nick(id);
+        let OperMsg { name, password } = oper;
+
+        if self.oper_throttle.is_throttled(ip) {
+            let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+            eprintln!(
+                "[WARN] [{timestamp}] OPER {name} by {nick} from {ip} refused: too many failures"
+            );
+            let text = "Too many failed attempts, try again later";
+            let reply = NumericReply::new(ErrorType::PasswdMismatch as u16, &nick, &[], text);
+            Self::send_reply(conn_write, reply).await;
+            return None;
+        }
+
+        let hashes = self
+            .config
+            .opers
+            .iter()
+            .filter(|block| block.name == name)
+            .map(|block| block.password.clone())
+            .collect::<Vec<_>>();
+        if hashes.is_empty() {
+            self.finish_oper(&name, None, conn_write, id).await;
+            return None;
+        }
+        Some(PasswordJob::verify(&password, hashes, Then::Oper { name }))
+    }
+
+    /// Makes the client on `id` the operator `name` if its password matched
+    /// `matched`, the hash of one of that operator's blocks.
+    async fn finish_oper(
+        &mut self,
+        name: &str,
+        matched: Option<String>,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(ip) = self.clients.get(id).map(|client| client.ip) else {
+            return;
+        };
+        let nick = self.client_nick(id);
+        let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+
+        // REHASH may have changed the blocks while the password was checked.
+        let block = matched.and_then(|matched| {
+            self.config
+                .opers
+                .iter()
+                .find(|block| block.name == name && block.password == matched)
+        });
+        let Some(block) = block else {
+            self.oper_throttle.record_failure(ip);
+            eprintln!("[WARN] [{timestamp}] failed OPER {name} by {nick} from {ip}");
+            let reply = NumericReply::error(ErrorType::PasswdMismatch, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
+        let Some(class) = self.config.oper_class(&block.class) else {
+            eprintln!(
+                "[WARN] [{timestamp}] oper {name} refers to unknown class {}",
+                block.class
+            );
+            let reply = NumericReply::error(ErrorType::NoOperHost, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
+
+        let operator = Operator {
+            name: block.name.clone(),
+            class: class.name.clone(),
+            privileges: class.privileges.clone(),
+        };
+        println!(
+            "[{timestamp}] {nick} from {ip} is now operator {name} ({})",
+            class.name
+        );
+        self.oper_throttle.clear(ip);
+        if let Some(client) = self.clients.get_mut(id) {
+            client.oper = Some(operator);
+            client.modes.wallops = true;
+        }
+
+        let reply = NumericReply::new(
+            ReplyType::YoureOper as u16,
+            &nick,
+            &[],
+            "You are now an IRC operator",
+        );
+        Self::send_reply(conn_write, reply).await;
+        let mode = ModeMsg {
+            target: nick.clone(),
+            modestring: Some("+ow".to_string()),
+            args: Vec::new(),
+        };
+        let mode = Message::with_prefix(&nick, Command::Mode(mode)).to_string();
+        let _ = conn_write.lock().await.write_message(&mode).await;
+    }
+
+    /// REHASH: reloads the configuration file the server was started with.
+    pub async fn handle_rehash_command(
+        &mut self,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        if !self.has_privilege(id, Privilege::Rehash) {
+            let reply = NumericReply::error(ErrorType::NoPrivileges, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+        let Some(path) = self.config_path.clone() else {
+            Self::send_notice(conn_write, &nick, "No configuration file to reload").await;
+            return;
+        };
+
+        let file = path.display().to_string();
+        let reply = NumericReply::new(ReplyType::Rehashing as u16, &nick, &[&file], "Rehashing");
+        Self::send_reply(conn_write, reply).await;
+        match Config::load(&path) {
+            Ok(config) => {
+                self.config = config;
+                self.reload_tls(conn_write, &nick).await;
+                let mechanisms = sasl::mechanisms(self.config.tls.is_some());
+                self.advertise_capability("sasl", Some(&mechanisms)).await;
+            }
+            Err(err) => {
+                eprintln!("[WARN] REHASH by {} failed: {err}", nick);
+                Self::send_notice(conn_write, &nick, &format!("REHASH failed: {err}")).await;
+            }
+        }
+    }
+
+    /// Reads the TLS certificate and key again. Connections that are already
+    /// established keep using the old ones.
+    async fn reload_tls(&self, conn_write: &Arc<Mutex<ConnectionWrite>>, nick: &str) {
+        let (Some(tls), Some(config)) = (&self.tls, &self.config.tls) else {
+            return;
+        };
+        if let Err(err) = tls.reload(config) {
+            eprintln!("[WARN] REHASH by {nick} could not reload TLS: {err}");
+            let text = format!("TLS reload failed, keeping the old certificate: {err}");
+            Self::send_notice(conn_write, nick, &text).await;
+        }
+    }
+
+    /// KILL <nick> :<reason>
+    pub async fn handle_kill_command(
+        &mut self,
+        kill: KillMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(killer) = self.clients.get(id).cloned() else {
+            return;
+        };
+        if !self.has_privilege(id, Privilege::Kill) {
+            let reply = NumericReply::error(ErrorType::NoPrivileges, &killer.nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+        let KillMsg { target, reason } = &kill;
+        let (Some(target_id), Some(target_conn_write)) = (
+            self.find_client_id(target),
+            self.connection_map.get(&IrcKey::new(target)).cloned(),
+        ) else {
+            let reply = NumericReply::error(ErrorType::NoSuchNick, &killer.nick, &[target]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        };
+
+        eprintln!(
+            "[WARN] [{}] {} killed {}: {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            killer.nick,
+            target,
+            reason
+        );
+        let kill = Message::with_prefix(&killer.prefix(), Command::Kill(kill.clone())).to_string();
+        let _ = target_conn_write.lock().await.write_message(&kill).await;
+        let quit_message = format!("Killed ({} ({}))", killer.nick, reason);
+        self.disconnect(&target_id, &quit_message, &target_conn_write)
+            .await;
+    }
+
+    /// WALLOPS :<text>, sent to every user with user mode +w.
+    pub async fn handle_wallops_command(
+        &mut self,
+        text: String,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(sender) = self.clients.get(id).cloned() else {
+            return;
+        };
+        if sender.oper.is_none() {
+            let reply = NumericReply::error(ErrorType::NoPrivileges, &sender.nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+
+        let wallops = Message::with_prefix(&sender.prefix(), Command::Wallops(text)).to_string();
+        for client in self.clients.values() {
+            if !client.registered || !client.modes.wallops {
+                continue;
+            }
+            if let Some(target_conn_write) = self.connection_map.get(&IrcKey::new(&client.nick)) {
+                let _ = target_conn_write.lock().await.write_message(&wallops).await;
+            }
+        }
+    }
+
+    /// GLOBOPS :<text>, sent as a server notice to every operator.
+    pub async fn handle_globops_command(
+        &mut self,
+        text: String,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let Some(sender) = self.clients.get(id).cloned() else {
+            return;
+        };
+        if sender.oper.is_none() {
+            let reply = NumericReply::error(ErrorType::NoPrivileges, &sender.nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+
+        let text = format!("*** Global -- from {}: {}", sender.nick, text);
+        for client in self.clients.values() {
+            if !client.registered || client.oper.is_none() {
+                continue;
+            }
+            if let Some(target_conn_write) = self.connection_map.get(&IrcKey::new(&client.nick)) {
+                Self::send_notice(target_conn_write, &client.nick, &text).await;
+            }
+        }
+    }
+
+    /// KLINE [minutes] <user@host> :<reason>
+    /// DLINE [minutes] <ip[/prefix]> :<reason>
+    pub async fn handle_ban_command(
+        &mut self,
+        kind: BanKind,
+        ban: BanMsg,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        if !self.has_privilege(id, Privilege::Ban) {
+            let reply = NumericReply::error(ErrorType::NoPrivileges, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+
+        let BanMsg {
+            minutes,
+            mask,
+            reason,
+        } = ban;
+        let valid = match kind {
+            BanKind::KLine => mask.contains('@'),
+            BanKind::DLine => valid_cidr(&mask),
+        };
+        if !valid {
+            let text = format!("Invalid {kind} mask {mask}");
+            Self::send_notice(conn_write, &nick, &text).await;
+            return;
+        }
+        let reason = reason.unwrap_or_else(|| "No reason".to_string());
+
+        let ban = Ban {
+            kind,
+            mask: mask.clone(),
+            reason,
+            set_by: nick.clone(),
+            expires_at: minutes
+                .filter(|minutes| *minutes > 0)
+                .map(|minutes| chrono::Utc::now().timestamp() + minutes * 60),
+        };
+        if let Err(err) = self.bans.lock().await.add(ban.clone()) {
+            let text = format!("Could not save the {kind} for [{mask}]: {err}");
+            Self::send_notice(conn_write, &nick, &text).await;
+            return;
+        }
+        eprintln!(
+            "[WARN] [{}] {nick} added {kind} on {mask}: {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            ban.reason
+        );
+        let duration = match minutes.filter(|minutes| *minutes > 0) {
+            Some(minutes) => format!("temporary {minutes} min."),
+            None => "permanent".to_string(),
+        };
+        let text = format!("Added {duration} {kind} for [{mask}]");
+        Self::send_notice(conn_write, &nick, &text).await;
+
+        // Users who are already online are removed straight away.
+        let matching = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client.registered
+                    && match kind {
+                        BanKind::KLine => ban.matches_user(
+                            client.username.as_deref().unwrap_or_default(),
+                            &client.host,
+                        ),
+                        BanKind::DLine => ban.matches_ip(client.ip),
+                    }
+            })
+            .filter_map(|(id, client)| {
+                let target_conn_write = self.connection_map.get(&IrcKey::new(&client.nick))?;
+                Some((id.clone(), Arc::clone(target_conn_write)))
+            })
+            .collect::<Vec<_>>();
+        for (target_id, target_conn_write) in matching {
+            self.reject_banned(&target_id, &ban, &target_conn_write)
+                .await;
+        }
+    }
+
+    /// UNKLINE <user@host>
+    /// UNDLINE <ip[/prefix]>
+    pub async fn handle_unban_command(
+        &mut self,
+        kind: BanKind,
+        mask: String,
+        conn_write: &mut Arc<Mutex<ConnectionWrite>>,
+        id: &str,
+    ) {
+        let nick = self.client_nick(id);
+        if !self.has_privilege(id, Privilege::Ban) {
+            let reply = NumericReply::error(ErrorType::NoPrivileges, &nick, &[]);
+            Self::send_reply(conn_write, reply).await;
+            return;
+        }
+        let removed = self.bans.lock().await.remove(kind, &mask);
+        let text = match removed {
+            Ok(true) => {
+                eprintln!(
+                    "[WARN] [{}] {nick} removed {kind} on {mask}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                );
+                format!("{kind} for [{mask}] is removed")
+            }
+            Ok(false) => format!("No {kind} for [{mask}] found"),
+            Err(err) => format!("Could not remove the {kind} for [{mask}]: {err}"),
+        };
+        Self::send_notice(conn_write, &nick, &text).await;
+    }
+}
+
+impl Default for IrcServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{OperBlock, OperClass, WebircGateway};
+    use crate::connect::{connection, BoxedStream, ConnectionInfo};
+    use crate::storage::{Change, Storage};
+    use crate::types::Nick;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    /// A client talking to a server through an in-memory connection, as
+    /// the client loop would drive it.
+    struct TestClient {
+        id: String,
+        conn_write: Arc<Mutex<ConnectionWrite>>,
+        peer: DuplexStream,
+        unread: String,
+    }
+
+    impl TestClient {
+        /// Connects from `ip` without registering.
+        fn connect(server: &mut IrcServer, ip: [u8; 4]) -> Self {
+            let (ours, peer) = tokio::io::duplex(1 << 20);
+            let addr = SocketAddr::from((ip, 6667));
+            let info = ConnectionInfo::plaintext(addr);
+            let id = info.id.clone();
+            let (_, conn_write) = connection(Box::new(ours) as BoxedStream, info);
+            server.add_client(id.clone(), Client::new(addr.ip(), ip_host(addr.ip())));
+            Self {
+                id,
+                conn_write: Arc::new(Mutex::new(conn_write)),
+                peer,
+                unread: String::new(),
+            }
+        }
+
+        /// Connects and registers as `nick`, skipping the welcome.
+        async fn register(server: &mut IrcServer, nick: &str) -> Self {
+            let last = server.clients.len() as u8 + 1;
+            let mut client = Self::connect(server, [192, 0, 2, last]);
+            client.send(server, &format!("NICK {nick}")).await;
+            client
+                .send(server, &format!("USER {nick} 0 * :{nick}"))
+                .await;
+            client.lines().await;
+            client
+        }
+
+        /// Makes the client an operator with `privileges`.
+        fn oper(&self, server: &mut IrcServer, privileges: &[Privilege]) {
+            let client = server.clients.get_mut(&self.id).unwrap();
+            client.oper = Some(Operator {
+                name: client.nick.clone(),
+                class: "test".to_string(),
+                privileges: privileges.to_vec(),
+            });
+        }
+
+        /// Handles `line` as if the client had sent it.
+        async fn send(&mut self, server: &mut IrcServer, line: &str) {
+            let message = Message::parse(line).unwrap();
+            let conn_write = &mut self.conn_write;
+            let id = self.id.as_str();
+            match message.command {
+                Command::Nick(m) => server.handle_nick_command(m.nick, conn_write, id).await,
+                Command::User(m) => {
+                    server.handle_user_command(m, id).await;
+                    server.try_register(id, conn_write).await;
+                }
+                Command::Cap(m) => {
+                    server.handle_cap_command(m, conn_write, id).await;
+                    if !server.clients[id].registered {
+                        server.try_register(id, conn_write).await;
+                    }
+                }
+                Command::Join(m) => server.handle_join_command(m, conn_write, id).await,
+                Command::Part(m) => server.handle_part_command(m, conn_write, id).await,
+                Command::Mode(m) => server.handle_mode_command(m, conn_write, id).await,
+                Command::Topic(m) => server.handle_topic_command(m, conn_write, id).await,
+                Command::Who(m) => server.handle_who_command(m, conn_write, id).await,
+                Command::Names(m) => server.handle_names_command(m, conn_write, id).await,
+                Command::Quit(m) => server.handle_quit_command(m, conn_write, id).await,
+                Command::Rehash => server.handle_rehash_command(conn_write, id).await,
+                Command::Kill(m) => server.handle_kill_command(m, conn_write, id).await,
+                Command::Wallops(m) => server.handle_wallops_command(m, conn_write, id).await,
+                Command::Globops(m) => server.handle_globops_command(m, conn_write, id).await,
+                Command::PrivMsg(_) | Command::Notice(_) => {
+                    let jobs = server.handle_privmsg_command(message, conn_write, id).await;
+                    for job in jobs {
+                        let finished = job.run().await;
+                        server.finish_password_job(finished, conn_write, id).await;
+                    }
+                }
+                Command::Webirc(m) => {
+                    if let Some(job) = server.handle_webirc_command(m, conn_write, id).await {
+                        let finished = job.run().await;
+                        server.finish_password_job(finished, conn_write, id).await;
+                    }
+                }
+                command => panic!("TestClient cannot send {}", command.name()),
+            }
+        }
+
+        /// The lines the server has sent since the last call, without their
+        /// CRLFs.
+        async fn lines(&mut self) -> Vec<String> {
+            let mut buf = [0; 4096];
+            let read = Duration::from_millis(10);
+            while let Ok(Ok(len @ 1..)) = tokio::time::timeout(read, self.peer.read(&mut buf)).await
+            {
+                self.unread.push_str(&String::from_utf8_lossy(&buf[..len]));
+            }
+            let end = self.unread.rfind("\r\n").map_or(0, |end| end + 2);
+            let lines = self.unread[..end]
+                .split_terminator("\r\n")
+                .map(String::from)
+                .collect();
+            self.unread.drain(..end);
+            lines
+        }
+
+        /// Whether the server sent the numeric `code` since the last call.
+        async fn got_numeric(&mut self, code: &str) -> bool {
+            let code = format!(" {code} ");
+            self.lines().await.iter().any(|line| line.contains(&code))
+        }
+    }
+
+    /// An account whose password takes one iteration to check, where a
+    /// real one takes many.
+    fn account(name: &str, password: &str) -> Account {
+        let salt = b"salt";
+        let mut hash = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), salt, 1, &mut hash);
+        Account {
+            name: name.to_string(),
+            password: format!(
+                "$pbkdf2-sha256$1${}${}",
+                BASE64.encode(salt),
+                BASE64.encode(hash)
+            ),
+            scram: Some(ScramCredentials::with_salt(password, salt, 1).to_string()),
+            certfps: Vec::new(),
+            registered_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_valid_hostname() {
+        assert!(valid_hostname("user.example.com"));
+        assert!(valid_hostname("2001:db8::1"));
+        assert!(valid_hostname(&format!("{}.example.com", "a".repeat(63))));
+        assert!(!valid_hostname(&format!("{}.example.com", "a".repeat(64))));
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(long.len(), 255);
+        assert!(!valid_hostname(&long));
+        assert!(valid_hostname(&long[2..]));
+        for host in [
+            "",
+            ".example.com",
+            "user..example.com",
+            "-user",
+            ":1",
+            "a b",
+        ] {
+            assert!(!valid_hostname(host), "{host}");
+        }
+    }
+
+    #[test]
+    fn test_guest_nick_is_valid() {
+        let server = IrcServer::new();
+        for _ in 0..100 {
+            assert!(Nick::try_from(server.guest_nick()).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_override_joins_past_ban_and_key() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        alice.send(&mut server, "JOIN #a").await;
+        alice.send(&mut server, "MODE #a +bk bob!*@* secret").await;
+
+        bob.send(&mut server, "JOIN #a").await;
+        assert!(bob.got_numeric("474").await);
+        bob.oper(&mut server, &[Privilege::Kill]);
+        bob.send(&mut server, "JOIN #a").await;
+        assert!(bob.got_numeric("474").await);
+
+        bob.oper(&mut server, &[Privilege::Override]);
+        bob.send(&mut server, "JOIN #a").await;
+        assert!(bob
+            .lines()
+            .await
+            .iter()
+            .any(|line| line.contains("JOIN #a")));
+        alice.send(&mut server, "MODE #a -b bob!*@*").await;
+        bob.send(&mut server, "PART #a").await;
+        bob.oper(&mut server, &[]);
+        bob.lines().await;
+        bob.send(&mut server, "JOIN #a").await;
+        assert!(bob.got_numeric("475").await);
+    }
+
+    #[tokio::test]
+    async fn test_override_changes_modes_and_topic() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        alice.send(&mut server, "JOIN #a").await;
+        alice.send(&mut server, "MODE #a +t").await;
+        bob.send(&mut server, "JOIN #a").await;
+        alice.lines().await;
+
+        bob.send(&mut server, "MODE #a +m").await;
+        assert!(bob.got_numeric("482").await);
+        bob.send(&mut server, "TOPIC #a :mine").await;
+        assert!(bob.got_numeric("482").await);
+
+        bob.oper(&mut server, &[Privilege::Override]);
+        bob.send(&mut server, "MODE #a +m").await;
+        bob.send(&mut server, "TOPIC #a :mine").await;
+        let lines = alice.lines().await;
+        assert!(lines.iter().any(|line| line.ends_with("MODE #a +m")));
+        assert!(lines.iter().any(|line| line.ends_with("TOPIC #a :mine")));
+    }
+
+    #[tokio::test]
+    async fn test_override_speaks_in_moderated_channel() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        alice.send(&mut server, "JOIN #a").await;
+        bob.send(&mut server, "JOIN #a").await;
+        alice.send(&mut server, "MODE #a +m").await;
+        alice.lines().await;
+
+        bob.send(&mut server, "PRIVMSG #a :hello").await;
+        assert!(bob.got_numeric("404").await);
+        bob.oper(&mut server, &[Privilege::Override]);
+        bob.send(&mut server, "PRIVMSG #a :hello").await;
+        assert!(alice
+            .lines()
+            .await
+            .iter()
+            .any(|line| line.ends_with("PRIVMSG #a :hello")));
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        let mut carol = TestClient::register(&mut server, "carol").await;
+        bob.send(&mut server, "JOIN #a").await;
+        carol.send(&mut server, "JOIN #a").await;
+        bob.lines().await;
+        carol.lines().await;
+
+        alice.send(&mut server, "KILL bob :bye").await;
+        assert!(alice.got_numeric("481").await);
+        alice.oper(&mut server, &[Privilege::Kill]);
+        alice.send(&mut server, "KILL nobody :bye").await;
+        assert!(alice.got_numeric("401").await);
+
+        alice.send(&mut server, "KILL bob :bye").await;
+        let lines = bob.lines().await;
+        assert!(lines[0].ends_with("KILL bob :bye"));
+        assert!(lines[1].starts_with("ERROR :Closing Link"));
+        assert!(server.find_client_id("bob").is_none());
+        assert_eq!(
+            carol.lines().await,
+            vec![":bob!bob@192.0.2.2 QUIT :Killed (alice (bye))"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wallops_and_globops() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        let mut carol = TestClient::register(&mut server, "carol").await;
+        bob.send(&mut server, "MODE bob +w").await;
+        bob.lines().await;
+
+        alice.send(&mut server, "WALLOPS :hello").await;
+        assert!(alice.got_numeric("481").await);
+        alice.send(&mut server, "GLOBOPS :hello").await;
+        assert!(alice.got_numeric("481").await);
+        assert!(bob.lines().await.is_empty());
+
+        alice.oper(&mut server, &[]);
+        alice.send(&mut server, "WALLOPS :hello").await;
+        assert_eq!(
+            bob.lines().await,
+            vec![":alice!alice@192.0.2.1 WALLOPS :hello"]
+        );
+        assert!(carol.lines().await.is_empty());
+
+        carol.oper(&mut server, &[]);
+        alice.send(&mut server, "GLOBOPS :hi").await;
+        assert!(bob.lines().await.is_empty());
+        assert!(carol.lines().await[0].ends_with(":*** Global -- from alice: hi"));
+    }
+
+    #[tokio::test]
+    async fn test_cap_notify() {
+        let dir = std::env::temp_dir().join(format!("iris-cap-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("iris.toml");
+        std::fs::write(&path, "").unwrap();
+        let mut server = IrcServer::with_config(Config::default(), Some(path.clone()));
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        alice.oper(&mut server, &[Privilege::Rehash]);
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        bob.send(&mut server, "CAP REQ :cap-notify").await;
+        bob.lines().await;
+        let mut carol = TestClient::connect(&mut server, [192, 0, 2, 3]);
+        carol.send(&mut server, "CAP LS 302").await;
+        assert!(
+            carol.lines().await[0].ends_with(" :cap-notify message-tags sasl=PLAIN,SCRAM-SHA-256")
+        );
+
+        // Configuring TLS makes EXTERNAL available.
+        std::fs::write(&path, "[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\n").unwrap();
+        alice.send(&mut server, "REHASH").await;
+        assert_eq!(bob.lines().await, vec![":iris-server CAP bob NEW :sasl"]);
+        assert_eq!(
+            carol.lines().await,
+            vec![":iris-server CAP * NEW :sasl=PLAIN,EXTERNAL,SCRAM-SHA-256"]
+        );
+        assert!(!alice
+            .lines()
+            .await
+            .iter()
+            .any(|line| line.contains(" CAP ")));
+
+        carol.send(&mut server, "CAP REQ :sasl").await;
+        carol.lines().await;
+        server.withdraw_capability("sasl").await;
+        assert_eq!(bob.lines().await, vec![":iris-server CAP bob DEL :sasl"]);
+        assert_eq!(carol.lines().await, vec![":iris-server CAP * DEL :sasl"]);
+        carol.send(&mut server, "CAP LIST").await;
+        assert_eq!(
+            carol.lines().await,
+            vec![":iris-server CAP * LIST :cap-notify"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invisible_users() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        let mut carol = TestClient::register(&mut server, "carol").await;
+
+        alice.send(&mut server, "MODE alice +i").await;
+        assert_eq!(alice.lines().await, vec![":alice MODE alice +i"]);
+        alice.send(&mut server, "MODE bob +i").await;
+        assert!(alice.got_numeric("502").await);
+        alice.send(&mut server, "MODE alice +x").await;
+        assert!(alice.got_numeric("501").await);
+        alice.send(&mut server, "JOIN #a").await;
+        bob.send(&mut server, "JOIN #a").await;
+        bob.lines().await;
+
+        // A stranger sees neither the user nor their place in the channel.
+        carol.send(&mut server, "WHO a*").await;
+        assert!(!carol.got_numeric("352").await);
+        carol.send(&mut server, "NAMES #a").await;
+        assert!(carol
+            .lines()
+            .await
+            .contains(&":iris-server 353 carol = #a :bob".to_string()));
+        carol.send(&mut server, "WHO #a").await;
+        let who = carol.lines().await;
+        assert_eq!(who.len(), 2);
+        assert!(who[0].contains(" 352 carol #a bob "));
+
+        // Someone who shares a channel does.
+        bob.send(&mut server, "WHO alice").await;
+        assert!(bob.lines().await[0].contains(" 352 bob * alice "));
+        bob.send(&mut server, "NAMES #a").await;
+        assert!(bob
+            .lines()
+            .await
+            .contains(&":iris-server 353 bob = #a :@alice bob".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_part() {
+        let mut server = IrcServer::new();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        alice.send(&mut server, "JOIN #a,#b").await;
+        bob.send(&mut server, "JOIN #a").await;
+        alice.lines().await;
+        bob.lines().await;
+
+        bob.send(&mut server, "PART #b").await;
+        assert!(bob.got_numeric("442").await);
+        bob.send(&mut server, "PART #nowhere").await;
+        assert!(bob.got_numeric("403").await);
+
+        bob.send(&mut server, "PART #a :Going home").await;
+        let part = ":bob!bob@192.0.2.2 PART #a :Going home".to_string();
+        assert_eq!(bob.lines().await, vec![part.clone()]);
+        assert_eq!(alice.lines().await, vec![part]);
+
+        // The last member leaving ends the channel.
+        alice.send(&mut server, "PART #a,#b").await;
+        assert_eq!(alice.lines().await.len(), 2);
+        assert!(server.get_channel("#a").is_none());
+        assert!(server.get_channel("#b").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chanserv_access_on_join() {
+        let mut server = IrcServer::new();
+        server.accounts.add(account("alice", "hunter2")).unwrap();
+        server.accounts.add(account("bob", "hunter2")).unwrap();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        let mut carol = TestClient::register(&mut server, "carol").await;
+        alice
+            .send(&mut server, "PRIVMSG NickServ :IDENTIFY hunter2")
+            .await;
+        bob.send(&mut server, "PRIVMSG NickServ :IDENTIFY hunter2")
+            .await;
+        alice.send(&mut server, "JOIN #a").await;
+        alice
+            .send(&mut server, "PRIVMSG ChanServ :REGISTER #a")
+            .await;
+        alice
+            .send(&mut server, "PRIVMSG ChanServ :ACCESS #a ADD bob OP")
+            .await;
+        alice.lines().await;
+        bob.lines().await;
+
+        carol.send(&mut server, "JOIN #a").await;
+        assert!(!alice.lines().await.iter().any(|line| line.contains("MODE")));
+        bob.send(&mut server, "JOIN #a").await;
+        let lines = alice.lines().await;
+        assert_eq!(lines[0], ":bob!bob@192.0.2.2 JOIN #a");
+        assert!(lines[1].ends_with(" MODE #a +o bob"));
+        bob.send(&mut server, "NAMES #a").await;
+        assert!(bob
+            .lines()
+            .await
+            .iter()
+            .any(|line| line.ends_with(" = #a :@alice carol @bob")));
+    }
+
+    #[tokio::test]
+    async fn test_memoserv_quota_and_notices() {
+        let mut server = IrcServer::new();
+        server.config.services.memo_quota = 2;
+        server.accounts.add(account("alice", "hunter2")).unwrap();
+        server.accounts.add(account("bob", "hunter2")).unwrap();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let mut bob = TestClient::register(&mut server, "bob").await;
+        alice
+            .send(&mut server, "PRIVMSG NickServ :IDENTIFY hunter2")
+            .await;
+        alice.lines().await;
+
+        let memoserv = ":MemoServ!MemoServ@services.iris-server PRIVMSG";
+        for _ in 0..2 {
+            alice
+                .send(&mut server, "PRIVMSG MemoServ :SEND bob hi")
+                .await;
+            assert_eq!(
+                alice.lines().await,
+                vec![format!("{memoserv} alice :Your memo to bob has been sent.")]
+            );
+        }
+        alice
+            .send(&mut server, "PRIVMSG MemoServ :SEND bob hi")
+            .await;
+        assert_eq!(
+            alice.lines().await,
+            vec![format!("{memoserv} alice :bob's memo box is full.")]
+        );
+        assert!(bob.lines().await.is_empty());
+
+        // Unread memos are announced on identifying, and new ones as they
+        // arrive.
+        bob.send(&mut server, "PRIVMSG NickServ :IDENTIFY hunter2")
+            .await;
+        let lines = bob.lines().await;
+        let announcement =
+            format!("{memoserv} bob :You have 2 new memos. Use /msg MemoServ LIST to see them.");
+        assert!(lines.contains(&announcement));
+        bob.send(&mut server, "PRIVMSG MemoServ :DEL 1").await;
+        bob.lines().await;
+        alice
+            .send(&mut server, "PRIVMSG MemoServ :SEND bob again")
+            .await;
+        let notice = format!(
+            "{memoserv} bob :You have a new memo from alice. Use /msg MemoServ READ 2 to read it."
+        );
+        assert_eq!(bob.lines().await, vec![notice]);
+    }
+
+    #[tokio::test]
+    async fn test_password_jobs_check_again_when_done() {
+        let mut server = IrcServer::new();
+        server.config.oper_classes.push(OperClass {
+            name: "netadmin".to_string(),
+            privileges: vec![Privilege::Kill],
+        });
+        server.config.opers.push(OperBlock {
+            name: "admin".to_string(),
+            password: account("admin", "hunter2").password,
+            class: "netadmin".to_string(),
+        });
+        server.accounts.add(account("bob", "hunter2")).unwrap();
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        let oper = OperMsg {
+            name: "admin".to_string(),
+            password: "hunter2".to_string(),
+        };
+
+        // Nothing happens until the job is finished, and a password that
+        // matched a block REHASH has since changed does not count.
+        let job = server
+            .handle_oper_command(oper.clone(), &mut alice.conn_write, &alice.id)
+            .await
+            .unwrap();
+        assert!(server.clients[&alice.id].oper.is_none());
+        let finished = job.run().await;
+        server.config.opers[0].password = account("admin", "hunter3").password;
+        server
+            .finish_password_job(finished, &mut alice.conn_write, &alice.id)
+            .await;
+        assert!(alice.got_numeric("464").await);
+        server.config.opers[0].password = account("admin", "hunter2").password;
+        let job = server
+            .handle_oper_command(oper, &mut alice.conn_write, &alice.id)
+            .await
+            .unwrap();
+        let finished = job.run().await;
+        server
+            .finish_password_job(finished, &mut alice.conn_write, &alice.id)
+            .await;
+        assert!(alice.got_numeric("381").await);
+
+        let nickserv = ":NickServ!NickServ@services.iris-server PRIVMSG alice";
+        let identify = Message::parse("PRIVMSG NickServ :IDENTIFY bob hunter2").unwrap();
+        let mut jobs = server
+            .handle_privmsg_command(identify, &mut alice.conn_write, &alice.id)
+            .await;
+        let finished = jobs.pop().unwrap().run().await;
+        server.accounts.remove("bob").unwrap();
+        server
+            .finish_password_job(finished, &mut alice.conn_write, &alice.id)
+            .await;
+        assert_eq!(
+            alice.lines().await,
+            vec![format!("{nickserv} :Invalid password for bob.")]
+        );
+
+        let register = Message::parse("PRIVMSG NickServ :REGISTER hunter22").unwrap();
+        let mut jobs = server
+            .handle_privmsg_command(register, &mut alice.conn_write, &alice.id)
+            .await;
+        let finished = jobs.pop().unwrap().run().await;
+        server.accounts.add(account("alice", "hunter3")).unwrap();
+        server
+            .finish_password_job(finished, &mut alice.conn_write, &alice.id)
+            .await;
+        assert_eq!(
+            alice.lines().await,
+            vec![format!("{nickserv} :alice is already registered.")]
+        );
+        assert_eq!(server.clients[&alice.id].account, None);
+    }
+
+    /// Storage that cannot write anything, like a full disk.
+    struct FailingStorage;
+
+    impl Storage for FailingStorage {
+        fn load(&mut self) -> Result<State, StorageError> {
+            Ok(State::default())
+        }
+
+        fn apply(&mut self, _change: &Change) -> Result<(), StorageError> {
+            Err(std::io::Error::other("disk full").into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsaved_changes_are_refused() {
+        let storage: SharedStorage = Arc::new(std::sync::Mutex::new(FailingStorage));
+        let mut server =
+            IrcServer::with_storage(Config::default(), None, State::default(), Some(storage));
+        let mut alice = TestClient::register(&mut server, "alice").await;
+        alice.oper(&mut server, &[Privilege::Ban]);
+
+        alice
+            .send(&mut server, "PRIVMSG NickServ :REGISTER hunter22")
+            .await;
+        assert_eq!(
+            alice.lines().await,
+            vec![format!(
+                ":NickServ!NickServ@services.iris-server PRIVMSG alice :{NOT_SAVED}"
+            )]
+        );
+        assert!(server.accounts.get("alice").is_none());
+        assert_eq!(server.clients[&alice.id].account, None);
+
+        let kline = BanMsg {
+            minutes: None,
+            mask: "*@bad.example".to_string(),
+            reason: None,
+        };
+        server
+            .handle_ban_command(BanKind::KLine, kline, &mut alice.conn_write, &alice.id)
+            .await;
+        assert!(alice.lines().await[0].ends_with(":Could not save the K-line for [*@bad.example]: could not access stored state: disk full"));
+        assert!(server
+            .bans
+            .lock()
+            .await
+            .find_kline("user", "bad.example")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_webirc_passwords_are_throttled() {
+        let mut server = IrcServer::new();
+        server.config.webirc_gateways.push(WebircGateway {
+            name: "kiwi".to_string(),
+            password: account("kiwi", "gwpass").password,
+            hosts: vec!["198.51.100.0/24".to_string()],
+        });
+        let gateway = [198, 51, 100, 1];
+        for _ in 0..3 {
+            let mut client = TestClient::connect(&mut server, gateway);
+            client
+                .send(
+                    &mut server,
+                    "WEBIRC wrong kiwi user.example.com 203.0.113.9",
+                )
+                .await;
+            assert!(!server.clients.contains_key(&client.id));
+        }
+
+        // Right password, but too late.
+        let mut client = TestClient::connect(&mut server, gateway);
+        client
+            .send(
+                &mut server,
+                "WEBIRC gwpass kiwi user.example.com 203.0.113.9",
+            )
+            .await;
+        assert!(!server.clients.contains_key(&client.id));
+        let lines = client.lines().await;
+        assert!(lines.iter().any(|line| line.contains(TOO_MANY_FAILURES)));
 
-        client.nick = new_nick;
-        println!("New client with nickname {}", client.nick);
-        self.clients.push(client.clone());
+        server.login_throttle.clear(IpAddr::from(gateway));
+        let mut client = TestClient::connect(&mut server, gateway);
+        client
+            .send(
+                &mut server,
+                "WEBIRC gwpass kiwi user.example.com 203.0.113.9",
+            )
+            .await;
+        assert_eq!(server.clients[&client.id].host, "user.example.com");
     }
-    // Implementations for IrcServer
 }
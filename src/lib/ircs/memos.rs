@@ -0,0 +1,166 @@
+// src/lib/ircs/memos.rs
+//! Memos left with MemoServ for accounts, kept until their owner deletes
+//! them.
+use crate::ircs::casemap::irc_eq;
+use crate::storage::{record, Change, SharedStorage, StorageError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Memo {
+    /// Identifies the memo in storage; given out by `MemoStore::send`.
+    pub id: u64,
+    /// The account the memo is for.
+    pub to: String,
+    /// The account that sent it.
+    pub from: String,
+    pub text: String,
+    /// Unix timestamp of when the memo was sent.
+    pub sent_at: i64,
+    #[serde(default)]
+    pub read: bool,
+}
+
+impl Memo {
+    /// A new, unread memo, sent now.
+    pub fn new(from: &str, to: &str, text: &str) -> Self {
+        Self {
+            id: 0,
+            to: to.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            sent_at: chrono::Utc::now().timestamp(),
+            read: false,
+        }
+    }
+}
+
+/// Every account's memos, oldest first, kept in `storage` as they change.
+/// Memos are numbered from 1 within each account's inbox.
+#[derive(Debug, Default)]
+pub struct MemoStore {
+    memos: Vec<Memo>,
+    storage: Option<SharedStorage>,
+}
+
+impl MemoStore {
+    /// A store holding `memos`, as loaded from `storage`. Without storage,
+    /// memos only live in memory.
+    pub fn new(memos: Vec<Memo>, storage: Option<SharedStorage>) -> Self {
+        Self { memos, storage }
+    }
+
+    /// The memos for `account`, oldest first.
+    pub fn inbox(&self, account: &str) -> Vec<&Memo> {
+        self.memos
+            .iter()
+            .filter(|memo| irc_eq(&memo.to, account))
+            .collect()
+    }
+
+    /// How many memos for `account` are still unread.
+    pub fn unread(&self, account: &str) -> usize {
+        self.inbox(account)
+            .into_iter()
+            .filter(|memo| !memo.read)
+            .count()
+    }
+
+    /// Adds `memo` unless its recipient already has `quota` memos. Returns
+    /// the new memo's number in the recipient's inbox.
+    pub fn send(&mut self, mut memo: Memo, quota: usize) -> Result<Option<usize>, StorageError> {
+        let count = self.inbox(&memo.to).len();
+        if count >= quota {
+            return Ok(None);
+        }
+        memo.id = self.memos.iter().map(|memo| memo.id).max().unwrap_or(0) + 1;
+        record(self.storage.as_ref(), Change::PutMemo(memo.clone()))?;
+        self.memos.push(memo);
+        Ok(Some(count + 1))
+    }
+
+    /// Position in `memos` of memo `number` for `account`.
+    fn position(&self, account: &str, number: usize) -> Option<usize> {
+        self.memos
+            .iter()
+            .enumerate()
+            .filter(|(_, memo)| irc_eq(&memo.to, account))
+            .nth(number.checked_sub(1)?)
+            .map(|(position, _)| position)
+    }
+
+    /// Memo `number` for `account`, which is now marked as read. If that
+    /// cannot be saved, the memo stays unread.
+    pub fn read(&mut self, account: &str, number: usize) -> Option<Memo> {
+        let position = self.position(account, number)?;
+        let memo = &mut self.memos[position];
+        if !memo.read {
+            let read = Memo {
+                read: true,
+                ..memo.clone()
+            };
+            if record(self.storage.as_ref(), Change::PutMemo(read.clone())).is_ok() {
+                *memo = read;
+            }
+        }
+        Some(memo.clone())
+    }
+
+    /// Deletes memo `number` for `account`, returning whether there was one.
+    pub fn delete(&mut self, account: &str, number: usize) -> Result<bool, StorageError> {
+        let Some(position) = self.position(account, number) else {
+            return Ok(false);
+        };
+        let id = self.memos[position].id;
+        record(self.storage.as_ref(), Change::RemoveMemo { id })?;
+        self.memos.remove(position);
+        Ok(true)
+    }
+
+    /// Deletes every memo for `account`, returning how many there were.
+    /// Stops at the first one that cannot be deleted from storage.
+    pub fn delete_all(&mut self, account: &str) -> Result<usize, StorageError> {
+        let mut deleted = 0;
+        while self.delete(account, 1)? {
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbox() {
+        let mut store = MemoStore::default();
+        assert_eq!(
+            store.send(Memo::new("alice", "Bob", "one"), 2).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            store.send(Memo::new("carol", "dave", "other"), 2).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            store.send(Memo::new("alice", "bob", "two"), 2).unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            store.send(Memo::new("alice", "BOB", "three"), 2).unwrap(),
+            None
+        );
+        assert_eq!(store.unread("bob"), 2);
+
+        assert_eq!(
+            store.read("bob", 2).map(|memo| memo.text),
+            Some("two".to_string())
+        );
+        assert_eq!(store.unread("bob"), 1);
+        assert!(store.read("bob", 0).is_none());
+        assert!(store.delete("bob", 1).unwrap());
+        assert_eq!(store.inbox("bob")[0].text, "two");
+        assert_eq!(store.delete_all("bob").unwrap(), 1);
+        assert_eq!(store.inbox("dave").len(), 1);
+    }
+}
@@ -1,10 +1,23 @@
+pub mod accounts;
+pub mod bans;
+pub mod capability;
+pub mod casemap;
 pub mod channel;
+pub mod channel_registry;
 pub mod client;
-pub mod irc_message;
 pub mod irc_server;
+pub mod memos;
+pub mod oper;
+pub mod password_job;
+pub mod sasl;
+pub mod scram;
+pub mod services;
+pub mod throttle;
 pub mod write_message;
+pub use capability::{CapState, CapabilityRegistry};
+pub use casemap::IrcKey;
 pub use channel::Channel;
 pub use client::Client;
-pub use irc_message::{IrcCommand, IrcMessage};
 pub use irc_server::IrcServer;
+pub use oper::{Operator, Privilege};
 pub use write_message::WriteMessage;
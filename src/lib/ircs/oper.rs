@@ -0,0 +1,30 @@
+// src/lib/ircs/oper.rs
+use serde::Deserialize;
+
+/// Something an operator class can allow its members to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    /// Disconnect other users with KILL.
+    Kill,
+    /// Reload the server configuration with REHASH.
+    Rehash,
+    /// Bypass channel restrictions.
+    Override,
+    /// Add and remove K-lines and D-lines.
+    Ban,
+}
+
+/// The operator status held by a client after a successful OPER.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operator {
+    pub name: String,
+    pub class: String,
+    pub privileges: Vec<Privilege>,
+}
+
+impl Operator {
+    pub fn has_privilege(&self, privilege: Privilege) -> bool {
+        self.privileges.contains(&privilege)
+    }
+}
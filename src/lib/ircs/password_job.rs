@@ -0,0 +1,116 @@
+// src/lib/ircs/password_job.rs
+//! Password checks and hashes that commands need. Each takes a noticeable
+//! fraction of a second on purpose, so handlers hand them back as a
+//! `PasswordJob` and the client loop runs it on the blocking pool, without
+//! holding the server lock, before the server acts on the outcome.
+use crate::password::{verify_password, PasswordHashes, ScramCredentials};
+use crate::types::WebircMsg;
+
+/// The derivation a job runs.
+#[derive(Debug)]
+pub enum Work {
+    /// Checks `password` against each of `hashes`. With `scram`, a match
+    /// also derives SCRAM keys for an account that has none yet.
+    Verify {
+        password: String,
+        hashes: Vec<String>,
+        scram: bool,
+    },
+    /// Hashes a new password.
+    Hash { password: String },
+}
+
+/// What a job found.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The hash that matched, if any, and the SCRAM keys derived for it.
+    Verified {
+        matched: Option<String>,
+        scram: Option<ScramCredentials>,
+    },
+    Hashed(PasswordHashes),
+}
+
+/// The command a job was started for, with what the server needs to
+/// finish it.
+#[derive(Debug)]
+pub enum Then {
+    Oper { name: String },
+    Webirc(WebircMsg),
+    SaslPlain { account: String },
+    Identify { account: String },
+    Ghost { ghost: String },
+    Drop { account: String },
+    Register { nick: String },
+    SetPassword { account: String },
+}
+
+#[derive(Debug)]
+pub struct PasswordJob {
+    pub work: Work,
+    pub then: Then,
+}
+
+/// A job that has run, ready for `IrcServer::finish_password_job`.
+#[derive(Debug)]
+pub struct FinishedJob {
+    pub outcome: Outcome,
+    pub then: Then,
+}
+
+impl Work {
+    fn run(self) -> Outcome {
+        match self {
+            Work::Verify {
+                password,
+                hashes,
+                scram,
+            } => {
+                let matched = hashes
+                    .into_iter()
+                    .find(|hash| verify_password(&password, hash));
+                let scram = (scram && matched.is_some()).then(|| ScramCredentials::new(&password));
+                Outcome::Verified { matched, scram }
+            }
+            Work::Hash { password } => Outcome::Hashed(PasswordHashes::new(&password)),
+        }
+    }
+}
+
+impl PasswordJob {
+    pub fn verify(password: &str, hashes: Vec<String>, then: Then) -> Self {
+        let work = Work::Verify {
+            password: password.to_string(),
+            hashes,
+            scram: false,
+        };
+        Self { work, then }
+    }
+
+    /// Like `verify`, but also derives SCRAM keys on a match, for an
+    /// account saved before they were kept.
+    pub fn verify_with_scram(password: &str, hash: String, then: Then) -> Self {
+        let work = Work::Verify {
+            password: password.to_string(),
+            hashes: vec![hash],
+            scram: true,
+        };
+        Self { work, then }
+    }
+
+    pub fn hash(password: &str, then: Then) -> Self {
+        let work = Work::Hash {
+            password: password.to_string(),
+        };
+        Self { work, then }
+    }
+
+    /// Runs the derivation on the blocking pool.
+    pub async fn run(self) -> FinishedJob {
+        let PasswordJob { work, then } = self;
+        let outcome = tokio::task::spawn_blocking(move || work.run())
+            .await
+            .expect("password derivation panicked");
+        FinishedJob { outcome, then }
+    }
+}
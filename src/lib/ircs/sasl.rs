@@ -0,0 +1,181 @@
+// src/lib/ircs/sasl.rs
+//! The AUTHENTICATE exchange: which mechanisms are offered, and how a
+//! client's base64 payload is reassembled from its 400-byte chunks.
+use crate::ircs::scram::ScramState;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+/// AUTHENTICATE payloads are split into chunks of this many bytes; a
+/// shorter chunk, or `+`, ends the payload.
+pub const SASL_CHUNK_LEN: usize = 400;
+/// The longest base64 payload accepted, across all of its chunks.
+const MAX_PAYLOAD_LEN: usize = 8 * SASL_CHUNK_LEN;
+
+/// The mechanisms offered, as shown in the `sasl` capability value.
+/// EXTERNAL needs a client certificate, so it is only offered with TLS.
+pub fn mechanisms(tls: bool) -> String {
+    if tls {
+        "PLAIN,EXTERNAL,SCRAM-SHA-256".to_string()
+    } else {
+        "PLAIN,SCRAM-SHA-256".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    /// RFC 4616: an account name and password.
+    Plain,
+    /// RFC 4422 appendix A: the TLS client certificate.
+    External,
+    /// RFC 7677: a challenge-response that never reveals the password.
+    ScramSha256,
+}
+
+impl Mechanism {
+    /// Looks up a mechanism by the name a client gives in AUTHENTICATE.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "PLAIN" => Some(Mechanism::Plain),
+            "EXTERNAL" => Some(Mechanism::External),
+            "SCRAM-SHA-256" => Some(Mechanism::ScramSha256),
+            _ => None,
+        }
+    }
+}
+
+/// What a client's AUTHENTICATE chunk amounted to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    /// The payload continues in the next chunk.
+    Incomplete,
+    /// The whole payload, decoded.
+    Complete(Vec<u8>),
+    /// A chunk, or the whole payload, was too long.
+    TooLong,
+    /// The payload was not valid base64.
+    Invalid,
+}
+
+/// An AUTHENTICATE exchange in progress on one connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaslSession {
+    pub mechanism: Mechanism,
+    /// Where a SCRAM-SHA-256 exchange has got to; `None` before its first
+    /// message.
+    pub scram: Option<ScramState>,
+    payload: String,
+}
+
+impl SaslSession {
+    pub fn new(mechanism: Mechanism) -> Self {
+        Self {
+            mechanism,
+            scram: None,
+            payload: String::new(),
+        }
+    }
+
+    /// Adds one AUTHENTICATE parameter to the payload. A complete payload
+    /// is handed back, and the next one starts empty.
+    pub fn push(&mut self, chunk: &str) -> Chunk {
+        if chunk.len() > SASL_CHUNK_LEN {
+            return Chunk::TooLong;
+        }
+        if chunk != "+" {
+            self.payload.push_str(chunk);
+        }
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Chunk::TooLong;
+        }
+        if chunk.len() == SASL_CHUNK_LEN {
+            return Chunk::Incomplete;
+        }
+        match BASE64.decode(std::mem::take(&mut self.payload)) {
+            Ok(payload) => Chunk::Complete(payload),
+            Err(_) => Chunk::Invalid,
+        }
+    }
+}
+
+/// Splits a server message into the AUTHENTICATE parameters that carry
+/// it, ending with `+` when the last chunk would otherwise be full.
+pub fn encode_chunks(payload: &[u8]) -> Vec<String> {
+    let encoded = BASE64.encode(payload);
+    let mut chunks = encoded
+        .as_bytes()
+        .chunks(SASL_CHUNK_LEN)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>();
+    if chunks
+        .last()
+        .is_none_or(|chunk| chunk.len() == SASL_CHUNK_LEN)
+    {
+        chunks.push("+".to_string());
+    }
+    chunks
+}
+
+/// The fields of a PLAIN payload: `authzid NUL authcid NUL password`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlainCredentials {
+    /// The account to act as; empty means the same as `authcid`.
+    pub authzid: String,
+    pub authcid: String,
+    pub password: String,
+}
+
+impl PlainCredentials {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let payload = std::str::from_utf8(payload).ok()?;
+        let mut fields = payload.split('\0');
+        let (Some(authzid), Some(authcid), Some(password), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return None;
+        };
+        Some(Self {
+            authzid: authzid.to_string(),
+            authcid: authcid.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunking() {
+        let mut session = SaslSession::new(Mechanism::Plain);
+        let encoded = BASE64.encode([b'x'; 300]);
+        assert_eq!(encoded.len(), SASL_CHUNK_LEN);
+        assert_eq!(session.push(&encoded), Chunk::Incomplete);
+        assert_eq!(session.push("+"), Chunk::Complete(vec![b'x'; 300]));
+
+        let mut session = SaslSession::new(Mechanism::Plain);
+        assert_eq!(session.push(&"A".repeat(401)), Chunk::TooLong);
+        assert_eq!(session.push("!!"), Chunk::Invalid);
+    }
+
+    #[test]
+    fn test_encode_chunks() {
+        assert_eq!(encode_chunks(b"v=abc"), vec!["dj1hYmM="]);
+        assert_eq!(encode_chunks(b""), vec!["+"]);
+        let chunks = encode_chunks(&[b'x'; 300]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1], "+");
+    }
+
+    #[test]
+    fn test_plain_credentials() {
+        assert_eq!(
+            PlainCredentials::parse(b"\0bot\0hunter2"),
+            Some(PlainCredentials {
+                authzid: String::new(),
+                authcid: "bot".to_string(),
+                password: "hunter2".to_string(),
+            })
+        );
+        assert_eq!(PlainCredentials::parse(b"bot\0hunter2"), None);
+    }
+}
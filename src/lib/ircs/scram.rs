@@ -0,0 +1,367 @@
+// src/lib/ircs/scram.rs
+//! The server's side of SASL SCRAM-SHA-256 (RFC 5802, RFC 7677):
+//!
+//! ```text
+//! C: n,,n=user,r=<client nonce>
+//! S: r=<client nonce><server nonce>,s=<salt>,i=<iterations>
+//! C: c=biws,r=<nonce>,p=<proof>
+//! S: v=<server signature>
+//! C: (empty)
+//! ```
+//!
+//! Channel binding is not supported, so clients must send `n` or `y`.
+//!
+//! A name that is not an account gets a server-first-message like any
+//! other, and fails where a wrong password would, so the exchange does not
+//! tell anyone which accounts exist.
+use crate::ircs::accounts::AccountStore;
+use crate::ircs::casemap::{irc_eq, irc_lowercase};
+use crate::password::{hmac_sha256, sha256, ScramCredentials, DEFAULT_ITERATIONS};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::RngCore;
+use std::sync::OnceLock;
+
+/// Why an exchange failed. The client is only ever told that it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScramError {
+    /// A message did not follow the SCRAM grammar.
+    Malformed,
+    /// The client asked for channel binding.
+    ChannelBinding,
+    /// No account has the name the client gave, or it has no SCRAM keys.
+    /// Only reported once the client has sent its proof.
+    UnknownAccount,
+    /// The client-final nonce or channel binding differs from before.
+    Mismatch,
+    /// The proof does not match the account's password.
+    BadProof,
+}
+
+/// Turns a SCRAM `saslname`, where `,` and `=` are escaped, back into a name.
+fn decode_saslname(name: &str) -> Result<String, ScramError> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(after) = rest.strip_prefix("=2C") {
+            decoded.push(',');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("=3D") {
+            decoded.push('=');
+            rest = after;
+        } else {
+            return Err(ScramError::Malformed);
+        }
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+/// A client-first-message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFirst {
+    pub username: String,
+    /// The account to act as, if the client named one.
+    pub authzid: Option<String>,
+    /// `n,,` or similar, repeated base64-encoded in the client-final message.
+    gs2_header: String,
+    /// The message without its GS2 header, which is part of what is signed.
+    bare: String,
+    nonce: String,
+}
+
+impl ClientFirst {
+    pub fn parse(message: &[u8]) -> Result<Self, ScramError> {
+        let message = std::str::from_utf8(message).map_err(|_| ScramError::Malformed)?;
+        let mut parts = message.splitn(3, ',');
+        let (Some(cbind), Some(authzid), Some(bare)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ScramError::Malformed);
+        };
+        match cbind {
+            "n" | "y" => {}
+            _ if cbind.starts_with("p=") => return Err(ScramError::ChannelBinding),
+            _ => return Err(ScramError::Malformed),
+        }
+        let authzid = match authzid {
+            "" => None,
+            _ => Some(decode_saslname(
+                authzid.strip_prefix("a=").ok_or(ScramError::Malformed)?,
+            )?),
+        };
+
+        let mut attributes = bare.split(',');
+        let username = attributes
+            .next()
+            .and_then(|attribute| attribute.strip_prefix("n="))
+            .ok_or(ScramError::Malformed)?;
+        let nonce = attributes
+            .next()
+            .and_then(|attribute| attribute.strip_prefix("r="))
+            .filter(|nonce| !nonce.is_empty())
+            .ok_or(ScramError::Malformed)?;
+        Ok(Self {
+            username: decode_saslname(username)?,
+            authzid,
+            gs2_header: message[..message.len() - bare.len()].to_string(),
+            bare: bare.to_string(),
+            nonce: nonce.to_string(),
+        })
+    }
+}
+
+/// A fresh random server nonce.
+pub fn server_nonce() -> String {
+    let mut nonce = [0u8; 18];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    BASE64.encode(nonce)
+}
+
+/// Credentials for a name that is not an account, which no proof matches.
+/// The salt is the same each time a name is tried, as a real account's
+/// would be.
+fn fake_credentials(username: &str) -> ScramCredentials {
+    static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+    let secret = SECRET.get_or_init(|| {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        secret
+    });
+    let name = username.chars().map(irc_lowercase).collect::<String>();
+    let key = hmac_sha256(secret, name.as_bytes());
+    ScramCredentials {
+        iterations: DEFAULT_ITERATIONS,
+        salt: hmac_sha256(&key, b"salt")[..16].to_vec(),
+        stored_key: hmac_sha256(&key, b"Stored Key"),
+        server_key: hmac_sha256(&key, b"Server Key"),
+    }
+}
+
+/// An exchange that has sent its server-first-message and is waiting for
+/// the client-final-message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramExchange {
+    client_first: ClientFirst,
+    server_first: String,
+    nonce: String,
+    credentials: ScramCredentials,
+}
+
+impl ScramExchange {
+    pub fn new(
+        client_first: ClientFirst,
+        credentials: ScramCredentials,
+        server_nonce: &str,
+    ) -> Self {
+        let nonce = format!("{}{server_nonce}", client_first.nonce);
+        let server_first = format!(
+            "r={nonce},s={},i={}",
+            BASE64.encode(&credentials.salt),
+            credentials.iterations
+        );
+        Self {
+            client_first,
+            server_first,
+            nonce,
+            credentials,
+        }
+    }
+
+    pub fn server_first(&self) -> &str {
+        &self.server_first
+    }
+
+    /// Checks a client-final-message, returning the server-final-message
+    /// when the client has proved that it knows the password.
+    pub fn client_final(&self, message: &[u8]) -> Result<String, ScramError> {
+        let message = std::str::from_utf8(message).map_err(|_| ScramError::Malformed)?;
+        let (without_proof, proof) = message.rsplit_once(",p=").ok_or(ScramError::Malformed)?;
+        let proof = BASE64.decode(proof).map_err(|_| ScramError::Malformed)?;
+
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attributes
+            .next()
+            .and_then(|attribute| attribute.strip_prefix("c="))
+            .ok_or(ScramError::Malformed)?;
+        let nonce = attributes
+            .next()
+            .and_then(|attribute| attribute.strip_prefix("r="))
+            .ok_or(ScramError::Malformed)?;
+        if channel_binding != BASE64.encode(&self.client_first.gs2_header) || nonce != self.nonce {
+            return Err(ScramError::Mismatch);
+        }
+
+        let auth_message = format!(
+            "{},{},{without_proof}",
+            self.client_first.bare, self.server_first
+        );
+        let client_signature = hmac_sha256(&self.credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(ScramError::BadProof);
+        }
+        let client_key = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect::<Vec<_>>();
+        if !crate::password::constant_time_eq(&sha256(&client_key), &self.credentials.stored_key) {
+            return Err(ScramError::BadProof);
+        }
+
+        let server_signature = hmac_sha256(&self.credentials.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", BASE64.encode(server_signature)))
+    }
+}
+
+/// How far a SCRAM exchange on a connection has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScramState {
+    /// Waiting for the client-final-message for `account`, which is
+    /// `None` when the client named no usable account.
+    AwaitingFinal {
+        exchange: Box<ScramExchange>,
+        account: Option<String>,
+    },
+    /// The server-final-message was sent; the client answers with an
+    /// empty message to finish.
+    AwaitingAck { account: String },
+}
+
+/// What to do after one message of an exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScramStep {
+    /// Send the message to the client and wait for its answer.
+    Continue(ScramState, String),
+    /// The client has logged in to the account.
+    Done(String),
+}
+
+/// Advances an exchange by one client message. `state` is `None` for the
+/// client-first-message.
+pub fn step(
+    state: Option<ScramState>,
+    message: &[u8],
+    accounts: &AccountStore,
+) -> Result<ScramStep, ScramError> {
+    match state {
+        None => {
+            let client_first = ClientFirst::parse(message)?;
+            let found = accounts
+                .get(&client_first.username)
+                .filter(|account| {
+                    client_first
+                        .authzid
+                        .as_ref()
+                        .is_none_or(|authzid| irc_eq(authzid, &account.name))
+                })
+                .and_then(|account| Some((account.name.clone(), account.scram_credentials()?)));
+            let (account, credentials) = match found {
+                Some((account, credentials)) => (Some(account), credentials),
+                None => (None, fake_credentials(&client_first.username)),
+            };
+            let exchange = ScramExchange::new(client_first, credentials, &server_nonce());
+            let server_first = exchange.server_first().to_string();
+            let exchange = Box::new(exchange);
+            Ok(ScramStep::Continue(
+                ScramState::AwaitingFinal { exchange, account },
+                server_first,
+            ))
+        }
+        Some(ScramState::AwaitingFinal { exchange, account }) => {
+            let server_final = exchange.client_final(message)?;
+            let account = account.ok_or(ScramError::UnknownAccount)?;
+            Ok(ScramStep::Continue(
+                ScramState::AwaitingAck { account },
+                server_final,
+            ))
+        }
+        Some(ScramState::AwaitingAck { account }) if message.is_empty() => {
+            Ok(ScramStep::Done(account))
+        }
+        Some(ScramState::AwaitingAck { .. }) => Err(ScramError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example exchange from RFC 7677 section 3.
+    #[test]
+    fn test_rfc7677_vectors() {
+        let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = ScramCredentials::with_salt("pencil", &salt, 4096);
+        let client_first = ClientFirst::parse(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        assert_eq!(client_first.username, "user");
+
+        let exchange =
+            ScramExchange::new(client_first, credentials, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0");
+        assert_eq!(
+            exchange.server_first(),
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+        assert_eq!(
+            exchange.client_final(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                  p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            ),
+            Ok("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_string())
+        );
+        assert_eq!(
+            exchange.client_final(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                  p=AHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            ),
+            Err(ScramError::BadProof)
+        );
+    }
+
+    #[test]
+    fn test_client_first() {
+        let client_first = ClientFirst::parse(b"y,a=b=2Cot,n=b=2Cot,r=abc").unwrap();
+        assert_eq!(client_first.username, "b,ot");
+        assert_eq!(client_first.authzid.as_deref(), Some("b,ot"));
+        assert_eq!(client_first.gs2_header, "y,a=b=2Cot,");
+        assert_eq!(
+            ClientFirst::parse(b"p=tls-unique,,n=user,r=abc"),
+            Err(ScramError::ChannelBinding)
+        );
+        assert_eq!(
+            ClientFirst::parse(b"n,,n=us=er,r=abc"),
+            Err(ScramError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_unknown_account() {
+        let mut accounts = AccountStore::default();
+        accounts
+            .add(crate::ircs::accounts::Account::new(
+                "user",
+                crate::password::PasswordHashes::new("pencil"),
+            ))
+            .unwrap();
+        let first = |name: &str| {
+            let message = format!("n,,n={name},r=abc");
+            match step(None, message.as_bytes(), &accounts) {
+                Ok(ScramStep::Continue(state, server_first)) => (state, server_first),
+                other => panic!("no server-first-message: {other:?}"),
+            }
+        };
+        let salt = |server_first: &str| server_first.split(',').nth(1).unwrap().to_string();
+
+        let (_, real) = first("user");
+        let (state, fake) = first("nobody");
+        assert_eq!(real.len(), fake.len());
+        assert!(fake.ends_with(&format!(",i={DEFAULT_ITERATIONS}")));
+        assert_eq!(salt(&fake), salt(&first("NOBODY").1));
+
+        let ScramState::AwaitingFinal { exchange, .. } = &state else {
+            panic!("not waiting for the client-final-message");
+        };
+        let client_final = format!("c=biws,r={},p=AAAA", exchange.nonce);
+        assert!(step(Some(state), client_final.as_bytes(), &accounts).is_err());
+    }
+}
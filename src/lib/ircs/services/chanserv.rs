@@ -0,0 +1,125 @@
+// src/lib/ircs/services/chanserv.rs
+//! ChanServ: registers channels to accounts and keeps their access lists.
+use crate::ircs::channel_registry::AccessLevel;
+
+pub const CHANSERV: &str = "ChanServ";
+
+pub const HELP: &[&str] = &[
+    "ChanServ commands:",
+    "REGISTER <#channel>                 register a channel you are an operator in",
+    "ACCESS <#channel> ADD <account> <OP|VOICE>  give an account status on join",
+    "ACCESS <#channel> DEL <account>     remove an account from the access list",
+    "ACCESS <#channel> LIST              show the access list",
+    "INFO <#channel>                     show information about a registered channel",
+    "DROP <#channel>                     unregister a channel you founded",
+];
+
+/// A line sent to ChanServ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChanServCommand {
+    Register {
+        channel: String,
+    },
+    AccessAdd {
+        channel: String,
+        account: String,
+        level: AccessLevel,
+    },
+    AccessDel {
+        channel: String,
+        account: String,
+    },
+    AccessList {
+        channel: String,
+    },
+    Info {
+        channel: String,
+    },
+    Drop {
+        channel: String,
+    },
+    Help,
+}
+
+impl ChanServCommand {
+    /// Parses the text of a PRIVMSG to ChanServ. On failure, returns the
+    /// usage line to send back.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let command = words.next().unwrap_or_default().to_ascii_uppercase();
+        let args = words.map(str::to_string).collect::<Vec<_>>();
+        let usage = |usage: &str| Err(format!("Syntax: {usage}"));
+        let access_usage = "ACCESS <#channel> ADD <account> <OP|VOICE> | DEL <account> | LIST";
+        match (command.as_str(), args.as_slice()) {
+            ("REGISTER", [channel]) => Ok(ChanServCommand::Register {
+                channel: channel.clone(),
+            }),
+            ("REGISTER", _) => usage("REGISTER <#channel>"),
+            ("ACCESS", [channel, subcommand, rest @ ..]) => {
+                match (subcommand.to_ascii_uppercase().as_str(), rest) {
+                    ("ADD", [account, level]) => {
+                        let level = match level.to_ascii_uppercase().as_str() {
+                            "OP" => AccessLevel::Op,
+                            "VOICE" => AccessLevel::Voice,
+                            _ => return usage(access_usage),
+                        };
+                        Ok(ChanServCommand::AccessAdd {
+                            channel: channel.clone(),
+                            account: account.clone(),
+                            level,
+                        })
+                    }
+                    ("DEL", [account]) => Ok(ChanServCommand::AccessDel {
+                        channel: channel.clone(),
+                        account: account.clone(),
+                    }),
+                    ("LIST", []) => Ok(ChanServCommand::AccessList {
+                        channel: channel.clone(),
+                    }),
+                    _ => usage(access_usage),
+                }
+            }
+            ("ACCESS", _) => usage(access_usage),
+            ("INFO", [channel]) => Ok(ChanServCommand::Info {
+                channel: channel.clone(),
+            }),
+            ("INFO", _) => usage("INFO <#channel>"),
+            ("DROP", [channel]) => Ok(ChanServCommand::Drop {
+                channel: channel.clone(),
+            }),
+            ("DROP", _) => usage("DROP <#channel>"),
+            ("HELP", _) => Ok(ChanServCommand::Help),
+            _ => Err(format!(
+                "Unknown command {command}. Use /msg {CHANSERV} HELP for a list."
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ChanServCommand::parse("access #team add Bob op"),
+            Ok(ChanServCommand::AccessAdd {
+                channel: "#team".to_string(),
+                account: "Bob".to_string(),
+                level: AccessLevel::Op,
+            })
+        );
+        assert_eq!(
+            ChanServCommand::parse("ACCESS #team LIST"),
+            Ok(ChanServCommand::AccessList {
+                channel: "#team".to_string()
+            })
+        );
+        assert!(ChanServCommand::parse("ACCESS #team ADD Bob founder").is_err());
+        assert_eq!(
+            ChanServCommand::parse("REGISTER"),
+            Err("Syntax: REGISTER <#channel>".to_string())
+        );
+    }
+}
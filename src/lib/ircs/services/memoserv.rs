@@ -0,0 +1,92 @@
+// src/lib/ircs/services/memoserv.rs
+//! MemoServ: leaves messages for accounts whose owners are offline.
+
+pub const MEMOSERV: &str = "MemoServ";
+
+pub const HELP: &[&str] = &[
+    "MemoServ commands:",
+    "SEND <account> <text>       leave a memo for an account",
+    "LIST                        list your memos",
+    "READ <number>               read one of your memos",
+    "DEL <number|ALL>            delete one or all of your memos",
+];
+
+/// A line sent to MemoServ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoServCommand {
+    Send {
+        account: String,
+        text: String,
+    },
+    List,
+    Read {
+        number: usize,
+    },
+    /// Deletes memo `number`, or every memo when it is `None`.
+    Del {
+        number: Option<usize>,
+    },
+    Help,
+}
+
+impl MemoServCommand {
+    /// Parses the text of a PRIVMSG to MemoServ. On failure, returns the
+    /// usage line to send back.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (command, rest) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+        let command = command.to_ascii_uppercase();
+        let rest = rest.trim_start();
+        let usage = |usage: &str| Err(format!("Syntax: {usage}"));
+        match command.as_str() {
+            // Everything after the account is the memo, spacing and all.
+            "SEND" => match rest.split_once(' ') {
+                Some((account, text)) if !text.trim().is_empty() => Ok(MemoServCommand::Send {
+                    account: account.to_string(),
+                    text: text.trim_start().to_string(),
+                }),
+                _ => usage("SEND <account> <text>"),
+            },
+            "LIST" => Ok(MemoServCommand::List),
+            "READ" => match rest.parse() {
+                Ok(number) => Ok(MemoServCommand::Read { number }),
+                Err(_) => usage("READ <number>"),
+            },
+            "DEL" if rest.eq_ignore_ascii_case("ALL") => Ok(MemoServCommand::Del { number: None }),
+            "DEL" => match rest.parse() {
+                Ok(number) => Ok(MemoServCommand::Del {
+                    number: Some(number),
+                }),
+                Err(_) => usage("DEL <number|ALL>"),
+            },
+            "HELP" => Ok(MemoServCommand::Help),
+            _ => Err(format!(
+                "Unknown command {command}. Use /msg {MEMOSERV} HELP for a list."
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            MemoServCommand::parse("send Bob  see you  at 10"),
+            Ok(MemoServCommand::Send {
+                account: "Bob".to_string(),
+                text: "see you  at 10".to_string()
+            })
+        );
+        assert_eq!(
+            MemoServCommand::parse("DEL all"),
+            Ok(MemoServCommand::Del { number: None })
+        );
+        assert_eq!(
+            MemoServCommand::parse("READ one"),
+            Err("Syntax: READ <number>".to_string())
+        );
+        assert!(MemoServCommand::parse("SEND Bob").is_err());
+    }
+}
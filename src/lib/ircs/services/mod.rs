@@ -0,0 +1,27 @@
+// src/lib/ircs/services/mod.rs
+//! In-process services: pseudo-users that clients talk to with PRIVMSG,
+//! and that answer through `IrcServer::send_privmsg_from_server`.
+use crate::ircs::casemap::irc_eq;
+
+pub mod chanserv;
+pub mod memoserv;
+pub mod nickserv;
+
+pub use chanserv::{ChanServCommand, CHANSERV};
+pub use memoserv::{MemoServCommand, MEMOSERV};
+pub use nickserv::{NickServCommand, NICKSERV};
+
+/// The host shown in the prefix of every service.
+pub const SERVICES_HOST: &str = "services.iris-server";
+
+/// The nicks of every service. No client may use them.
+pub const SERVICE_NICKS: &[&str] = &[NICKSERV, CHANSERV, MEMOSERV];
+
+pub fn is_service(nick: &str) -> bool {
+    SERVICE_NICKS.iter().any(|service| irc_eq(service, nick))
+}
+
+/// The `nick!user@host` source of messages from a service.
+pub fn service_prefix(service: &str) -> String {
+    format!("{service}!{service}@{SERVICES_HOST}")
+}
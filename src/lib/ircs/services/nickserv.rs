@@ -0,0 +1,124 @@
+// src/lib/ircs/services/nickserv.rs
+//! NickServ: registers nicknames as accounts and protects them.
+
+pub const NICKSERV: &str = "NickServ";
+
+/// Passwords shorter than this are refused by REGISTER and SET PASSWORD.
+pub const MIN_PASSWORD_LEN: usize = 5;
+
+pub const HELP: &[&str] = &[
+    "NickServ commands:",
+    "REGISTER <password>         register your current nick",
+    "IDENTIFY [account] <password>  log in to an account",
+    "GHOST <nick> [password]     disconnect a session using your nick",
+    "DROP <password>             delete your account",
+    "SET PASSWORD <password>     change your password",
+    "INFO [nick]                 show information about a registered nick",
+];
+
+/// A line sent to NickServ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NickServCommand {
+    Register {
+        password: String,
+    },
+    Identify {
+        account: Option<String>,
+        password: String,
+    },
+    Ghost {
+        nick: String,
+        password: Option<String>,
+    },
+    Drop {
+        password: String,
+    },
+    SetPassword {
+        password: String,
+    },
+    Info {
+        nick: Option<String>,
+    },
+    Help,
+}
+
+impl NickServCommand {
+    /// Parses the text of a PRIVMSG to NickServ. On failure, returns the
+    /// usage line to send back.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let command = words.next().unwrap_or_default().to_ascii_uppercase();
+        let args = words.map(str::to_string).collect::<Vec<_>>();
+        let usage = |usage: &str| Err(format!("Syntax: {usage}"));
+        match (command.as_str(), args.as_slice()) {
+            ("REGISTER", [password, ..]) => Ok(NickServCommand::Register {
+                password: password.clone(),
+            }),
+            ("REGISTER", _) => usage("REGISTER <password>"),
+            ("IDENTIFY", [password]) => Ok(NickServCommand::Identify {
+                account: None,
+                password: password.clone(),
+            }),
+            ("IDENTIFY", [account, password]) => Ok(NickServCommand::Identify {
+                account: Some(account.clone()),
+                password: password.clone(),
+            }),
+            ("IDENTIFY", _) => usage("IDENTIFY [account] <password>"),
+            ("GHOST", [nick]) => Ok(NickServCommand::Ghost {
+                nick: nick.clone(),
+                password: None,
+            }),
+            ("GHOST", [nick, password]) => Ok(NickServCommand::Ghost {
+                nick: nick.clone(),
+                password: Some(password.clone()),
+            }),
+            ("GHOST", _) => usage("GHOST <nick> [password]"),
+            ("DROP", [password]) => Ok(NickServCommand::Drop {
+                password: password.clone(),
+            }),
+            ("DROP", _) => usage("DROP <password>"),
+            ("SET", [setting, password]) if setting.eq_ignore_ascii_case("PASSWORD") => {
+                Ok(NickServCommand::SetPassword {
+                    password: password.clone(),
+                })
+            }
+            ("SET", _) => usage("SET PASSWORD <password>"),
+            ("INFO", []) => Ok(NickServCommand::Info { nick: None }),
+            ("INFO", [nick]) => Ok(NickServCommand::Info {
+                nick: Some(nick.clone()),
+            }),
+            ("INFO", _) => usage("INFO [nick]"),
+            ("HELP", _) => Ok(NickServCommand::Help),
+            _ => Err(format!(
+                "Unknown command {command}. Use /msg {NICKSERV} HELP for a list."
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            NickServCommand::parse("identify bot hunter2"),
+            Ok(NickServCommand::Identify {
+                account: Some("bot".to_string()),
+                password: "hunter2".to_string()
+            })
+        );
+        assert_eq!(
+            NickServCommand::parse("SET password  s3cret"),
+            Ok(NickServCommand::SetPassword {
+                password: "s3cret".to_string()
+            })
+        );
+        assert_eq!(
+            NickServCommand::parse("GHOST"),
+            Err("Syntax: GHOST <nick> [password]".to_string())
+        );
+        assert!(NickServCommand::parse("").is_err());
+    }
+}
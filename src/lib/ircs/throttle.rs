@@ -0,0 +1,58 @@
+// src/lib/ircs/throttle.rs
+//! Limits on failed password attempts, so that OPER, account and WebIRC
+//! gateway passwords cannot be brute-forced from one address.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How many failed attempts an address may make per window.
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+const FAILED_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Tracks failed password attempts per address.
+#[derive(Debug, Default)]
+pub struct PasswordThrottle {
+    failures: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl PasswordThrottle {
+    /// Whether `ip` has used up its attempts for the current window.
+    pub fn is_throttled(&mut self, ip: IpAddr) -> bool {
+        match self.failures.get(&ip) {
+            Some((_, since)) if since.elapsed() >= FAILED_ATTEMPT_WINDOW => {
+                self.failures.remove(&ip);
+                false
+            }
+            Some((count, _)) => *count >= MAX_FAILED_ATTEMPTS,
+            None => false,
+        }
+    }
+
+    pub fn record_failure(&mut self, ip: IpAddr) {
+        let entry = self.failures.entry(ip).or_insert((0, Instant::now()));
+        entry.0 += 1;
+    }
+
+    pub fn clear(&mut self, ip: IpAddr) {
+        self.failures.remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = PasswordThrottle::default();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            assert!(!throttle.is_throttled(ip));
+            throttle.record_failure(ip);
+        }
+        assert!(throttle.is_throttled(ip));
+        assert!(!throttle.is_throttled(IpAddr::from([192, 0, 2, 2])));
+        throttle.clear(ip);
+        assert!(!throttle.is_throttled(ip));
+    }
+}
@@ -1,5 +1,13 @@
+pub mod config;
 pub mod connect;
 pub mod ircs;
+pub mod parser;
+pub mod password;
+pub mod persist;
+pub mod proxy;
+pub mod storage;
+pub mod tls;
 pub mod types;
+pub mod websocket;
 
 pub use connect::{ConnectionError, ConnectionManager, ConnectionRead, ConnectionWrite};
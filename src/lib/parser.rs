@@ -0,0 +1,362 @@
+// src/lib/parser.rs
+//! The IRC line grammar, independent of what any command means:
+//!
+//! ```text
+//! [@tags SPACE] [:prefix SPACE] command *(SPACE middle) [SPACE :trailing]
+//! ```
+//!
+//! `RawMessage::parse` never panics; every malformed line is reported as a
+//! `ParseError`. `Display` writes a `RawMessage` back out in a form that
+//! parses to the same value.
+use std::fmt;
+
+/// RFC 1459 allows at most 15 parameters.
+pub const MAX_PARAMS: usize = 15;
+
+/// One `key[=value]` entry of a line's tag section. Values are stored
+/// unescaped; an empty value is the same as no value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// A line split into its grammatical parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    pub tags: Vec<Tag>,
+    pub prefix: Option<String>,
+    /// The command, uppercased, or a three-digit numeric.
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+/// Why a line does not follow the IRC grammar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line is empty or only whitespace.
+    Empty,
+    /// The line contains NUL, or CR or LF before its end.
+    IllegalCharacter,
+    /// `@` was not followed by well-formed tags.
+    InvalidTags,
+    /// `:` was not followed by a prefix.
+    EmptyPrefix,
+    /// Tags or a prefix were not followed by a command.
+    MissingCommand,
+    /// The command is neither letters nor a three-digit numeric.
+    InvalidCommand(String),
+    /// More than `MAX_PARAMS` parameters.
+    TooManyParams,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(fmt, "empty line"),
+            ParseError::IllegalCharacter => write!(fmt, "illegal character in line"),
+            ParseError::InvalidTags => write!(fmt, "malformed message tags"),
+            ParseError::EmptyPrefix => write!(fmt, "empty prefix"),
+            ParseError::MissingCommand => write!(fmt, "missing command"),
+            ParseError::InvalidCommand(command) => write!(fmt, "invalid command {command:?}"),
+            ParseError::TooManyParams => write!(fmt, "more than {MAX_PARAMS} parameters"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Splits `rest` at the first space, skipping any further spaces.
+fn next_word(rest: &str) -> (&str, &str) {
+    match rest.split_once(' ') {
+        Some((word, rest)) => (word, rest.trim_start_matches(' ')),
+        None => (rest, ""),
+    }
+}
+
+fn valid_tag_key(key: &str) -> bool {
+    let name = key.strip_prefix('+').unwrap_or(key);
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '/' | '.'))
+}
+
+/// Undoes the IRCv3 tag value escapes, such as `\s` for a space.
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            // `\\` and any unknown escape stand for the character itself;
+            // a lone `\` at the end is dropped.
+            Some(other) => unescaped.push(other),
+            None => break,
+        }
+    }
+    unescaped
+}
+
+fn write_escaped_tag_value(fmt: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    for c in value.chars() {
+        match c {
+            ';' => write!(fmt, "\\:")?,
+            ' ' => write!(fmt, "\\s")?,
+            '\\' => write!(fmt, "\\\\")?,
+            '\r' => write!(fmt, "\\r")?,
+            '\n' => write!(fmt, "\\n")?,
+            _ => write!(fmt, "{c}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes `tags` as a line's tag section, including the `@` and the space
+/// after it. Writes nothing when there are no tags.
+pub fn write_tags(fmt: &mut fmt::Formatter<'_>, tags: &[Tag]) -> fmt::Result {
+    if tags.is_empty() {
+        return Ok(());
+    }
+    write!(fmt, "@")?;
+    for (i, tag) in tags.iter().enumerate() {
+        if i > 0 {
+            write!(fmt, ";")?;
+        }
+        write!(fmt, "{tag}")?;
+    }
+    write!(fmt, " ")
+}
+
+impl fmt::Display for Tag {
+    /// Writes the tag as it appears on the wire, with its value escaped.
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}", self.key)?;
+        match &self.value {
+            Some(value) => {
+                write!(fmt, "=")?;
+                write_escaped_tag_value(fmt, value)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_tags(tags: &str) -> Result<Vec<Tag>, ParseError> {
+    tags.split(';')
+        .map(|tag| {
+            let (key, value) = match tag.split_once('=') {
+                Some((key, value)) if !value.is_empty() => (key, Some(unescape_tag_value(value))),
+                Some((key, _)) => (key, None),
+                None => (tag, None),
+            };
+            if !valid_tag_key(key) {
+                return Err(ParseError::InvalidTags);
+            }
+            Ok(Tag {
+                key: key.to_string(),
+                value,
+            })
+        })
+        .collect()
+}
+
+fn valid_command(command: &str) -> bool {
+    command.chars().all(|c| c.is_ascii_alphabetic())
+        || (command.len() == 3 && command.chars().all(|c| c.is_ascii_digit()))
+}
+
+impl RawMessage {
+    /// Parses one line, with or without its `\r\n`.
+    pub fn parse(line: &str) -> Result<RawMessage, ParseError> {
+        let line = line
+            .strip_suffix("\r\n")
+            .or_else(|| line.strip_suffix('\n'))
+            .unwrap_or(line);
+        if line.contains(['\0', '\r', '\n']) {
+            return Err(ParseError::IllegalCharacter);
+        }
+        let mut rest = line.trim_start_matches(' ');
+        if rest.trim_end_matches(' ').is_empty() {
+            return Err(ParseError::Empty);
+        }
+
+        let mut tags = Vec::new();
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let (tag_section, after) = next_word(tagged);
+            tags = parse_tags(tag_section)?;
+            rest = after;
+        }
+
+        let mut prefix = None;
+        if let Some(prefixed) = rest.strip_prefix(':') {
+            let (source, after) = next_word(prefixed);
+            if source.is_empty() {
+                return Err(ParseError::EmptyPrefix);
+            }
+            prefix = Some(source.to_string());
+            rest = after;
+        }
+
+        let (command, mut rest) = next_word(rest);
+        if command.is_empty() {
+            return Err(ParseError::MissingCommand);
+        }
+        if !valid_command(command) {
+            return Err(ParseError::InvalidCommand(command.to_string()));
+        }
+
+        let mut params = Vec::new();
+        while !rest.is_empty() {
+            if params.len() == MAX_PARAMS {
+                return Err(ParseError::TooManyParams);
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            let (middle, after) = next_word(rest);
+            params.push(middle.to_string());
+            rest = after;
+        }
+
+        Ok(RawMessage {
+            tags,
+            prefix,
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+}
+
+impl fmt::Display for RawMessage {
+    /// Writes the line, including its `\r\n`. The last parameter is written
+    /// as a trailing parameter whenever it could not be read back otherwise.
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_tags(fmt, &self.tags)?;
+        if let Some(prefix) = &self.prefix {
+            write!(fmt, ":{prefix} ")?;
+        }
+        write!(fmt, "{}", self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(fmt, " {param}")?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(fmt, " :{last}")?;
+            } else {
+                write!(fmt, " {last}")?;
+            }
+        }
+        write!(fmt, "\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse_parts() {
+        let message =
+            RawMessage::parse("@id=1;+draft/react :nick!u@h privmsg #chan :hi there\r\n").unwrap();
+        assert_eq!(
+            message.tags,
+            vec![
+                Tag {
+                    key: "id".to_string(),
+                    value: Some("1".to_string())
+                },
+                Tag {
+                    key: "+draft/react".to_string(),
+                    value: None
+                },
+            ]
+        );
+        assert_eq!(message.prefix.as_deref(), Some("nick!u@h"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["#chan", "hi there"]);
+
+        let message = RawMessage::parse("PRIVMSG  #chan   a:b  :").unwrap();
+        assert_eq!(message.params, vec!["#chan", "a:b", ""]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(RawMessage::parse("  \r\n"), Err(ParseError::Empty));
+        assert_eq!(RawMessage::parse(": NICK a"), Err(ParseError::EmptyPrefix));
+        assert_eq!(RawMessage::parse(":pfx"), Err(ParseError::MissingCommand));
+        assert_eq!(RawMessage::parse("@ NICK a"), Err(ParseError::InvalidTags));
+        assert_eq!(
+            RawMessage::parse("NI\0CK"),
+            Err(ParseError::IllegalCharacter)
+        );
+        assert_eq!(
+            RawMessage::parse("N1CK a"),
+            Err(ParseError::InvalidCommand("N1CK".to_string()))
+        );
+        let too_many = format!("CMD{}", " x".repeat(MAX_PARAMS + 1));
+        assert_eq!(RawMessage::parse(&too_many), Err(ParseError::TooManyParams));
+    }
+
+    #[test]
+    fn test_tag_escapes() {
+        let message = RawMessage::parse(r"@a=x\sy\:z\\w;b=;c=q\ TAGMSG #chan").unwrap();
+        let values = message
+            .tags
+            .iter()
+            .map(|tag| tag.value.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![Some(r"x y;z\w"), None, Some("q")]);
+        assert_eq!(
+            message.to_string(),
+            "@a=x\\sy\\:z\\\\w;b;c=q TAGMSG #chan\r\n"
+        );
+    }
+
+    fn raw_message() -> impl Strategy<Value = RawMessage> {
+        let tag = (
+            "\\+?[a-z][a-z0-9/.-]{0,8}",
+            proptest::option::of("[^\0]{1,8}"),
+        )
+            .prop_map(|(key, value)| Tag { key, value });
+        let middle = "[^ :\0\r\n][^ \0\r\n]{0,8}";
+        let trailing = "[^\0\r\n]{0,16}";
+        (
+            proptest::collection::vec(tag, 0..3),
+            proptest::option::of("[^ :\0\r\n][^ \0\r\n]{0,16}"),
+            "[A-Z]{1,8}|[0-9]{3}",
+            proptest::collection::vec(middle, 0..MAX_PARAMS - 1),
+            proptest::option::of(trailing),
+        )
+            .prop_map(|(tags, prefix, command, mut params, trailing)| {
+                params.extend(trailing);
+                RawMessage {
+                    tags,
+                    prefix,
+                    command,
+                    params,
+                }
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(message in raw_message()) {
+            prop_assert_eq!(RawMessage::parse(&message.to_string()), Ok(message));
+        }
+
+        #[test]
+        fn test_never_panics(line in "\\PC{0,64}") {
+            let _ = RawMessage::parse(&line);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// src/lib/password.rs
+//! Salted, iterated password hashes for credentials kept in configuration
+//! and in the account store.
+//!
+//! Hashes are stored as `$pbkdf2-sha256$<iterations>$<salt>$<hash>`, where the
+//! salt and hash are standard base64. Accounts also keep SCRAM-SHA-256 keys
+//! as `$scram-sha-256$<iterations>$<salt>$<stored key>$<server key>`.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const SCHEME: &str = "pbkdf2-sha256";
+const SCRAM_SCHEME: &str = "scram-sha-256";
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// Hashes `password` with a fresh random salt.
+pub fn hash_password(password: &str) -> String {
+    let salt = random_salt();
+
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, DEFAULT_ITERATIONS, &mut hash);
+
+    format!(
+        "${SCHEME}${DEFAULT_ITERATIONS}${}${}",
+        BASE64.encode(salt),
+        BASE64.encode(hash)
+    )
+}
+
+/// Splits a `hash_password` hash into its iterations, salt and hash.
+fn parse_hash(stored: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let mut fields = stored.split('$');
+    let (Some(""), Some(SCHEME), Some(iterations), Some(salt), Some(hash), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+    let (Ok(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse::<u32>(),
+        BASE64.decode(salt),
+        BASE64.decode(hash),
+    ) else {
+        return None;
+    };
+    if iterations == 0 || hash.is_empty() {
+        return None;
+    }
+    Some((iterations, salt, hash))
+}
+
+/// Checks `password` against a hash produced by `hash_password`.
+/// Malformed hashes never match.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    let Some((iterations, salt, expected)) = parse_hash(stored) else {
+        return false;
+    };
+
+    let mut actual = vec![0u8; expected.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut actual);
+    constant_time_eq(&actual, &expected)
+}
+
+/// Compares two byte strings without exiting early on the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+/// Everything an account keeps for one password: a `hash_password` hash
+/// and SCRAM-SHA-256 credentials. Deriving both takes two slow key
+/// derivations, so callers run this off the async executor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordHashes {
+    pub hash: String,
+    pub scram: ScramCredentials,
+}
+
+impl PasswordHashes {
+    pub fn new(password: &str) -> Self {
+        Self {
+            hash: hash_password(password),
+            scram: ScramCredentials::new(password),
+        }
+    }
+}
+
+/// What the server keeps to check SCRAM-SHA-256 logins (RFC 5802). The
+/// password cannot be recovered from these, and they are not enough to
+/// log in as the account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /// Derives credentials for `password` with a fresh random salt.
+    pub fn new(password: &str) -> Self {
+        Self::with_salt(password, &random_salt(), DEFAULT_ITERATIONS)
+    }
+
+    pub fn with_salt(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0u8; HASH_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        Self {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: sha256(&client_key),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Reads credentials written with `Display`.
+    pub fn parse(stored: &str) -> Option<Self> {
+        let mut fields = stored.split('$');
+        let (Some(""), Some(SCRAM_SCHEME), Some(iterations), Some(salt), Some(stored_key)) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return None;
+        };
+        let (Some(server_key), None) = (fields.next(), fields.next()) else {
+            return None;
+        };
+        let credentials = Self {
+            iterations: iterations.parse().ok()?,
+            salt: BASE64.decode(salt).ok()?,
+            stored_key: BASE64.decode(stored_key).ok()?,
+            server_key: BASE64.decode(server_key).ok()?,
+        };
+        (credentials.iterations > 0).then_some(credentials)
+    }
+}
+
+impl std::fmt::Display for ScramCredentials {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "${SCRAM_SCHEME}${}${}${}${}",
+            self.iterations,
+            BASE64.encode(&self.salt),
+            BASE64.encode(&self.stored_key),
+            BASE64.encode(&self.server_key)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_round_trip() {
+        let stored = hash_password("hunter2");
+        assert!(verify_password("hunter2", &stored));
+        assert!(!verify_password("hunter3", &stored));
+        assert!(!verify_password("hunter2", "hunter2"));
+    }
+
+    #[test]
+    fn test_scram_credentials() {
+        let credentials = ScramCredentials::with_salt("hunter2", b"salt", 4096);
+        assert_eq!(
+            ScramCredentials::parse(&credentials.to_string()),
+            Some(credentials)
+        );
+    }
+}
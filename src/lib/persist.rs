@@ -0,0 +1,37 @@
+// src/lib/persist.rs
+//! Small helpers for the files under the data directory.
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::path::Path;
+
+/// Replaces the contents of `path` so that a crash leaves either the old or
+/// the new file behind, never a half-written one.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    // The rename itself is only durable once the directory is synced.
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Reads a TOML file, treating a missing file as the default value.
+pub fn load_toml<T: DeserializeOwned + Default>(path: &Path) -> Result<T, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents).map_err(|err| err.to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.to_string()),
+    }
+}
@@ -0,0 +1,174 @@
+// src/lib/proxy.rs
+//! The PROXY protocol, which load balancers use to pass on the address of
+//! the client they are relaying, in a header before the client's own data.
+//! Version 1 is a line of text and version 2 is binary; both are accepted.
+//!
+//! See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How a version 2 header starts.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest version 1 header, including its CRLF.
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug)]
+pub enum ProxyError {
+    Io(std::io::Error),
+    /// The connection did not start with a valid header.
+    Invalid(&'static str),
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::Io(err) => write!(f, "could not read PROXY header: {err}"),
+            ProxyError::Invalid(reason) => write!(f, "invalid PROXY header: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<std::io::Error> for ProxyError {
+    fn from(err: std::io::Error) -> Self {
+        ProxyError::Io(err)
+    }
+}
+
+/// Reads the PROXY header at the start of `stream`, and nothing after it.
+/// Returns the address of the client the proxy is relaying, or `None` when
+/// the proxy is speaking for itself, as it does for health checks.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    // Every header is at least this long, so this never reads past one.
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(ProxyError::Invalid("missing signature"));
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(ProxyError::Invalid("line too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyError::Invalid("not ASCII"))?;
+    parse_v1(line)
+}
+
+/// Parses a version 1 header line, without its CRLF, such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, ProxyError> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| ProxyError::Invalid("bad source address"))?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(ProxyError::Invalid("address does not match protocol"));
+            }
+            let port = port
+                .parse()
+                .map_err(|_| ProxyError::Invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(ProxyError::Invalid("malformed line")),
+    }
+}
+
+/// Reads the rest of a version 2 header, after its signature.
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+    let mut addresses = vec![0; usize::from(len)];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection.
+        0 => return Ok(None),
+        // PROXY: relaying a client.
+        1 => {}
+        _ => return Err(ProxyError::Invalid("unsupported command")),
+    }
+    parse_v2_addresses(family, &addresses)
+}
+
+/// The source address in the address block of a version 2 header. Any TLVs
+/// after the addresses are ignored.
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+    let too_short = ProxyError::Invalid("address block too short");
+    let (ip, port) = match family >> 4 {
+        // AF_INET
+        1 => {
+            let block = addresses.get(..12).ok_or(too_short)?;
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&block[..4]).unwrap());
+            (IpAddr::from(ip), [block[8], block[9]])
+        }
+        // AF_INET6
+        2 => {
+            let block = addresses.get(..36).ok_or(too_short)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&block[..16]).unwrap());
+            (IpAddr::from(ip), [block[32], block[33]])
+        }
+        // AF_UNSPEC or AF_UNIX: there is no address worth keeping.
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(
+        ip.to_canonical(),
+        u16::from_be_bytes(port),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut header: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+        let result = read_header(&mut header).await;
+        assert!(result.is_err() || header == b"NICK a\r\n");
+        result
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let client = SocketAddr::from(([192, 0, 2, 1], 56324));
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667\r\nNICK a\r\n";
+        assert_eq!(parse(v1).await.unwrap(), Some(client));
+        let v1 = b"PROXY UNKNOWN\r\nNICK a\r\n";
+        assert_eq!(parse(v1).await.unwrap(), None);
+        assert!(parse(b"PROXY TCP6 192.0.2.1 ::1 1 2\r\nNICK a\r\n")
+            .await
+            .is_err());
+        assert!(parse(b"NICK a\r\nUSER a 0 * :a\r\n").await.is_err());
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 15]);
+        v2.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x1a, 0x0b]);
+        // A TLV the server does not use.
+        v2.extend([0x04, 0, 0]);
+        v2.extend(b"NICK a\r\n");
+        assert_eq!(parse(&v2).await.unwrap(), Some(client));
+        // LOCAL
+        v2[12] = 0x20;
+        assert_eq!(parse(&v2).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,222 @@
+// src/lib/storage/file.rs
+//! `Storage` in the data directory: a TOML snapshot of the whole state and
+//! an append-only log of the changes made since it was written.
+//!
+//! Each change is appended to `state.log` as one line of JSON and synced
+//! before it is acknowledged. When the log grows long, and every time the
+//! server starts, the log is folded into a new `state.toml`, which replaces
+//! the old one atomically. A crash can only ever leave a partly written last
+//! line in the log, which is dropped on the next start.
+use crate::persist::write_atomically;
+use crate::storage::migrations::{self, SCHEMA_VERSION};
+use crate::storage::{Change, State, Storage, StorageError};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_FILE: &str = "state.toml";
+const LOG_FILE: &str = "state.log";
+/// Changes logged before the log is folded into a new snapshot.
+const COMPACT_AFTER: usize = 1000;
+
+/// The layout of `state.toml`.
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    version: u32,
+    #[serde(flatten)]
+    state: &'a State,
+}
+
+pub struct FileStorage {
+    dir: PathBuf,
+    /// The state as of the last change, kept to write snapshots from.
+    state: State,
+    log: Option<File>,
+    /// The length of the log up to its last complete entry.
+    log_len: u64,
+    log_entries: usize,
+}
+
+impl FileStorage {
+    /// Storage in `dir`, which is created on `load` if needed.
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            state: State::default(),
+            log: None,
+            log_len: 0,
+            log_entries: 0,
+        }
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(SNAPSHOT_FILE)
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.dir.join(LOG_FILE)
+    }
+
+    /// The snapshot as a TOML table, brought up to the current version.
+    fn read_snapshot(&self) -> Result<toml::Table, StorageError> {
+        let mut snapshot = match std::fs::read_to_string(self.snapshot_path()) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| StorageError::Corrupt(format!("{SNAPSHOT_FILE}: {err}")))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+            Err(err) => return Err(err.into()),
+        };
+        migrations::migrate(&mut snapshot, &self.dir)?;
+        Ok(snapshot)
+    }
+
+    /// Applies every complete entry in the log to `state`.
+    fn replay_log(&self, state: &mut State) -> Result<(), StorageError> {
+        let log = match std::fs::read(self.log_path()) {
+            Ok(log) => log,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        // Whatever follows the last newline was cut off by a crash.
+        let complete = log
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(&log[..0], |end| &log[..end]);
+        let lines = complete.split(|&byte| byte == b'\n').enumerate();
+        for (number, line) in lines.filter(|(_, line)| !line.is_empty()) {
+            let change = serde_json::from_slice::<Change>(line).map_err(|err| {
+                StorageError::Corrupt(format!("{LOG_FILE} line {}: {err}", number + 1))
+            })?;
+            state.apply(change);
+        }
+        Ok(())
+    }
+
+    /// Writes the whole state as a new snapshot and empties the log.
+    fn compact(&mut self) -> Result<(), StorageError> {
+        let snapshot = Snapshot {
+            version: SCHEMA_VERSION,
+            state: &self.state,
+        };
+        let contents =
+            toml::to_string(&snapshot).map_err(|err| StorageError::Corrupt(err.to_string()))?;
+        write_atomically(&self.snapshot_path(), contents.as_bytes())?;
+        // A crash before the log is emptied replays changes the snapshot
+        // already has, which is harmless because changes are idempotent.
+        let log = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.log_path())?;
+        // The old handle appends, so it keeps working on the emptied log if
+        // anything below fails.
+        self.log_len = 0;
+        self.log_entries = 0;
+        log.sync_all()?;
+        self.log = Some(OpenOptions::new().append(true).open(self.log_path())?);
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<State, StorageError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let snapshot = self.read_snapshot()?;
+        let mut state = toml::Value::Table(snapshot)
+            .try_into::<State>()
+            .map_err(|err| StorageError::Corrupt(format!("{SNAPSHOT_FILE}: {err}")))?;
+        self.replay_log(&mut state)?;
+        self.state = state.clone();
+        self.compact()?;
+        Ok(state)
+    }
+
+    fn apply(&mut self, change: &Change) -> Result<(), StorageError> {
+        let mut line =
+            serde_json::to_string(change).map_err(|err| StorageError::Corrupt(err.to_string()))?;
+        line.push('\n');
+        let Some(log) = &mut self.log else {
+            let err = std::io::Error::other("storage was used before it was loaded");
+            return Err(err.into());
+        };
+        if let Err(err) = log
+            .write_all(line.as_bytes())
+            .and_then(|()| log.sync_data())
+        {
+            // Drop any partial entry, so that the next one starts a new line.
+            let _ = log.set_len(self.log_len);
+            return Err(err.into());
+        }
+        self.log_len += line.len() as u64;
+        self.log_entries += 1;
+        self.state.apply(change.clone());
+        // The change is already safe in the log, so a failed compaction is
+        // only logged, and tried again after the next change.
+        if self.log_entries >= COMPACT_AFTER {
+            if let Err(err) = self.compact() {
+                eprintln!("[WARN] could not compact stored state: {err}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iris-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_log_survives_restart() {
+        let dir = temp_dir("storage");
+        let setting = |key: &str, value: &str| Change::PutSetting {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+
+        let mut storage = FileStorage::new(&dir);
+        assert_eq!(storage.load().unwrap(), State::default());
+        storage.apply(&setting("motd", "hello")).unwrap();
+        storage.apply(&setting("motd", "goodbye")).unwrap();
+        // A crash in the middle of writing an entry.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(b"{\"op\":\"put_set").unwrap();
+
+        let mut storage = FileStorage::new(&dir);
+        let state = storage.load().unwrap();
+        assert_eq!(state.settings["motd"], "goodbye");
+        assert_eq!(std::fs::read(dir.join(LOG_FILE)).unwrap(), b"");
+        let snapshot = std::fs::read_to_string(dir.join(SNAPSHOT_FILE)).unwrap();
+        assert!(snapshot.starts_with("version = 1\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_compaction_keeps_change() {
+        let dir = temp_dir("compaction");
+        let mut storage = FileStorage::new(&dir);
+        storage.load().unwrap();
+        // A directory in the way of the new snapshot makes compaction fail.
+        std::fs::remove_file(dir.join(SNAPSHOT_FILE)).unwrap();
+        std::fs::create_dir(dir.join(SNAPSHOT_FILE)).unwrap();
+        storage.log_entries = COMPACT_AFTER - 1;
+        let change = Change::PutSetting {
+            key: "motd".to_string(),
+            value: "hello".to_string(),
+        };
+        storage.apply(&change).unwrap();
+
+        std::fs::remove_dir(dir.join(SNAPSHOT_FILE)).unwrap();
+        let state = FileStorage::new(&dir).load().unwrap();
+        assert_eq!(state.settings["motd"], "hello");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+// src/lib/storage/migrations.rs
+//! Versioned upgrades of the snapshot layout. `MIGRATIONS[n]` turns a
+//! version `n` snapshot into version `n + 1`. Version 0 is a data directory
+//! from before there was a snapshot at all.
+use crate::persist::load_toml;
+use crate::storage::StorageError;
+use std::path::Path;
+use toml::Value;
+
+/// The snapshot layout this server writes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+type Migration = fn(&mut toml::Table, &Path) -> Result<(), StorageError>;
+
+const MIGRATIONS: &[Migration] = &[import_toml_files];
+
+/// Brings `snapshot`, read from the data directory `dir`, up to
+/// `SCHEMA_VERSION`.
+pub fn migrate(snapshot: &mut toml::Table, dir: &Path) -> Result<(), StorageError> {
+    let version = match snapshot.get("version") {
+        None => 0,
+        Some(Value::Integer(version)) => u32::try_from(*version)
+            .map_err(|_| StorageError::Corrupt(format!("bad version {version}")))?,
+        Some(version) => return Err(StorageError::Corrupt(format!("bad version {version}"))),
+    };
+    if version > SCHEMA_VERSION {
+        return Err(StorageError::TooNew(version));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(snapshot, dir)?;
+        println!(
+            "[INFO] migrated stored state from version {from} to {}",
+            from + 1
+        );
+    }
+    snapshot.insert("version".to_string(), Value::Integer(SCHEMA_VERSION.into()));
+    Ok(())
+}
+
+/// Version 1 keeps everything in one snapshot. Before it, accounts,
+/// channels, bans and memos each had a TOML file of their own, laid out
+/// like the matching part of the snapshot. The old files are left alone.
+fn import_toml_files(snapshot: &mut toml::Table, dir: &Path) -> Result<(), StorageError> {
+    let files = [
+        ("accounts.toml", "account"),
+        ("channels.toml", "channel"),
+        ("bans.toml", "ban"),
+        ("memos.toml", "memo"),
+    ];
+    for (file, key) in files {
+        let path = dir.join(file);
+        let mut contents = load_toml::<toml::Table>(&path)
+            .map_err(|err| StorageError::Corrupt(format!("{}: {err}", path.display())))?;
+        if let Some(records) = contents.remove(key) {
+            snapshot.insert(key.to_string(), records);
+        }
+    }
+    // Memos are now addressed by id rather than by position.
+    if let Some(Value::Array(memos)) = snapshot.get_mut("memo") {
+        for (id, memo) in (1..).zip(memos.iter_mut()) {
+            if let Value::Table(memo) = memo {
+                memo.insert("id".to_string(), Value::Integer(id));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_toml_files() {
+        let dir = std::env::temp_dir().join(format!("iris-migrations-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("memos.toml"),
+            "[[memo]]\nto = \"bob\"\nfrom = \"alice\"\ntext = \"hi\"\nsent_at = 0\n",
+        )
+        .unwrap();
+
+        let mut snapshot = toml::Table::new();
+        migrate(&mut snapshot, &dir).unwrap();
+        assert_eq!(snapshot["version"].as_integer(), Some(1));
+        assert_eq!(snapshot["memo"][0]["id"].as_integer(), Some(1));
+        assert!(!snapshot.contains_key("account"));
+
+        snapshot.insert("version".to_string(), Value::Integer(2));
+        assert!(matches!(
+            migrate(&mut snapshot, &dir),
+            Err(StorageError::TooNew(2))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+// src/lib/storage/mod.rs
+//! State that must survive a restart: registered accounts and channels,
+//! K-lines and D-lines, memos and server settings.
+//!
+//! The stores that own this state in memory, such as `AccountStore`, hand
+//! every change to a `Storage` before making it, and only make it once it
+//! has been kept, so memory never holds a change the disk lacks.
+//! `FileStorage` keeps it in the data directory; without one, state only
+//! lives in memory.
+use crate::ircs::accounts::Account;
+use crate::ircs::bans::{Ban, BanKind};
+use crate::ircs::casemap::irc_eq;
+use crate::ircs::channel_registry::RegisteredChannel;
+use crate::ircs::memos::Memo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, PoisonError};
+
+pub mod file;
+pub mod migrations;
+
+pub use file::FileStorage;
+
+/// Everything that is kept, as it is laid out in a snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default, rename = "account")]
+    pub accounts: Vec<Account>,
+    #[serde(default, rename = "channel")]
+    pub channels: Vec<RegisteredChannel>,
+    #[serde(default, rename = "ban")]
+    pub bans: Vec<Ban>,
+    #[serde(default, rename = "memo")]
+    pub memos: Vec<Memo>,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+/// One change to the kept state. Each sets a record to a new value or
+/// removes it, so applying a change twice is the same as applying it once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+    PutAccount(Account),
+    RemoveAccount { name: String },
+    PutChannel(RegisteredChannel),
+    RemoveChannel { name: String },
+    PutBan(Ban),
+    RemoveBan { kind: BanKind, mask: String },
+    PutMemo(Memo),
+    RemoveMemo { id: u64 },
+    PutSetting { key: String, value: String },
+    RemoveSetting { key: String },
+}
+
+/// Replaces the record that `matches`, or adds `record` if there is none.
+fn put<T>(records: &mut Vec<T>, record: T, matches: impl Fn(&T, &T) -> bool) {
+    match records
+        .iter_mut()
+        .find(|existing| matches(existing, &record))
+    {
+        Some(existing) => *existing = record,
+        None => records.push(record),
+    }
+}
+
+impl State {
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::PutAccount(account) => {
+                put(&mut self.accounts, account, |a, b| irc_eq(&a.name, &b.name))
+            }
+            Change::RemoveAccount { name } => self
+                .accounts
+                .retain(|account| !irc_eq(&account.name, &name)),
+            Change::PutChannel(channel) => {
+                put(&mut self.channels, channel, |a, b| irc_eq(&a.name, &b.name))
+            }
+            Change::RemoveChannel { name } => self
+                .channels
+                .retain(|channel| !irc_eq(&channel.name, &name)),
+            Change::PutBan(ban) => put(&mut self.bans, ban, |a, b| {
+                a.kind == b.kind && irc_eq(&a.mask, &b.mask)
+            }),
+            Change::RemoveBan { kind, mask } => self
+                .bans
+                .retain(|ban| ban.kind != kind || !irc_eq(&ban.mask, &mask)),
+            Change::PutMemo(memo) => put(&mut self.memos, memo, |a, b| a.id == b.id),
+            Change::RemoveMemo { id } => self.memos.retain(|memo| memo.id != id),
+            Change::PutSetting { key, value } => {
+                self.settings.insert(key, value);
+            }
+            Change::RemoveSetting { key } => {
+                self.settings.remove(&key);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    /// A snapshot or log entry could not be understood.
+    Corrupt(String),
+    /// The data was written by a newer version of the server.
+    TooNew(u32),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "could not access stored state: {err}"),
+            StorageError::Corrupt(err) => write!(f, "stored state is corrupt: {err}"),
+            StorageError::TooNew(version) => write!(
+                f,
+                "stored state has version {version}, newer than this server understands"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// Somewhere to keep state between runs.
+pub trait Storage: Send {
+    /// Reads everything that was kept. Called once, before any `apply`.
+    fn load(&mut self) -> Result<State, StorageError>;
+
+    /// Keeps one change. Once this returns `Ok`, the change survives a
+    /// crash.
+    ///
+    /// This blocks until the change is on disk, and the stores call it with
+    /// the server lock held, so every client waits for each change to be
+    /// synced. Kept changes come from registrations, bans and memos, which
+    /// are rare next to ordinary traffic, so this is accepted rather than
+    /// handing changes to a writer thread.
+    fn apply(&mut self, change: &Change) -> Result<(), StorageError>;
+}
+
+impl std::fmt::Debug for dyn Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Storage")
+    }
+}
+
+/// A `Storage` shared by every store that writes to it.
+pub type SharedStorage = Arc<Mutex<dyn Storage>>;
+
+/// Hands `change` to `storage`, if there is one. Callers make the change in
+/// memory only if this succeeds. A failure is logged here, and the caller
+/// tells whoever asked for the change.
+pub fn record(storage: Option<&SharedStorage>, change: Change) -> Result<(), StorageError> {
+    let Some(storage) = storage else {
+        return Ok(());
+    };
+    let mut storage = storage.lock().unwrap_or_else(PoisonError::into_inner);
+    storage.apply(&change).inspect_err(|err| {
+        eprintln!("[WARN] could not save state: {err}");
+    })
+}
+
+/// Server settings that are changed at runtime rather than in the
+/// configuration file, and kept across restarts.
+#[derive(Debug, Default)]
+pub struct Settings {
+    values: BTreeMap<String, String>,
+    storage: Option<SharedStorage>,
+}
+
+impl Settings {
+    pub fn new(values: BTreeMap<String, String>, storage: Option<SharedStorage>) -> Self {
+        Self { values, storage }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        let change = Change::PutSetting {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        record(self.storage.as_ref(), change)?;
+        self.values.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Removes the setting `key`, returning whether it was set.
+    pub fn remove(&mut self, key: &str) -> Result<bool, StorageError> {
+        if !self.values.contains_key(key) {
+            return Ok(false);
+        }
+        let change = Change::RemoveSetting {
+            key: key.to_string(),
+        };
+        record(self.storage.as_ref(), change)?;
+        self.values.remove(key);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_is_idempotent() {
+        let mut state = State::default();
+        let account = |name: &str| Account {
+            name: name.to_string(),
+            password: String::new(),
+            scram: None,
+            certfps: Vec::new(),
+            registered_at: 0,
+        };
+        let changes = [
+            Change::PutAccount(account("Bot[1]")),
+            Change::PutAccount(account("bot{1}")),
+            Change::PutSetting {
+                key: "motd".to_string(),
+                value: "hello".to_string(),
+            },
+            Change::RemoveAccount {
+                name: "nobody".to_string(),
+            },
+        ];
+        for change in changes.iter().chain(&changes) {
+            state.apply(change.clone());
+        }
+        assert_eq!(state.accounts.len(), 1);
+        assert_eq!(state.accounts[0].name, "bot{1}");
+        assert_eq!(state.settings["motd"], "hello");
+    }
+}
@@ -0,0 +1,181 @@
+// src/lib/tls.rs
+//! TLS for client connections: the server certificate, which REHASH can
+//! replace, and the fingerprints of the certificates clients present.
+use crate::config::TlsConfig;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(std::io::Error),
+    /// The certificate file holds no certificates.
+    NoCertificate,
+    /// The key file holds no private key.
+    NoKey,
+    Rustls(rustls::Error),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(err) => write!(f, "could not read certificate or key: {err}"),
+            TlsError::NoCertificate => write!(f, "no certificate found"),
+            TlsError::NoKey => write!(f, "no private key found"),
+            TlsError::Rustls(err) => write!(f, "invalid certificate or key: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<std::io::Error> for TlsError {
+    fn from(err: std::io::Error) -> Self {
+        TlsError::Io(err)
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(err: rustls::Error) -> Self {
+        TlsError::Rustls(err)
+    }
+}
+
+/// The certificate and key new TLS connections are accepted with. Reloading
+/// only affects connections accepted afterwards; established sessions keep
+/// the configuration they started with.
+#[derive(Debug)]
+pub struct TlsContext {
+    server_config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsContext {
+    pub fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let server_config = server_config(&config.cert, &config.key)?;
+        Ok(Self {
+            server_config: RwLock::new(Arc::new(server_config)),
+        })
+    }
+
+    /// Reads the certificate and key again. On failure the old ones stay in
+    /// use.
+    pub fn reload(&self, config: &TlsConfig) -> Result<(), TlsError> {
+        let server_config = server_config(&config.cert, &config.key)?;
+        *self
+            .server_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(server_config);
+        Ok(())
+    }
+
+    /// An acceptor for one new connection.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let server_config = self
+            .server_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        TlsAcceptor::from(Arc::clone(&server_config))
+    }
+}
+
+fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, TlsError> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(key)?))?
+        .ok_or(TlsError::NoKey)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = Arc::new(AnyClientCert {
+        provider: Arc::clone(&provider),
+    });
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    Ok(server_config)
+}
+
+/// The lowercase hex SHA-256 fingerprint of a DER-encoded certificate, the
+/// form SASL EXTERNAL matches against.
+pub fn certfp(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Asks for a client certificate but takes any, or none. Certificates are
+/// not used to trust the client, only to recognise it by fingerprint, so
+/// the only check is that the client holds the certificate's key.
+#[derive(Debug)]
+struct AnyClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certfp() {
+        assert_eq!(
+            certfp(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
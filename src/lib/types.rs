@@ -1,6 +1,8 @@
 /// All relevant IRC errors are listed here.
 /// See the assignment documentation for more information.
 // src/lib/types.rs
+use crate::parser::{write_tags, ParseError, RawMessage, Tag};
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum ErrorType {
     NoNickNameGiven = 431,
@@ -9,10 +11,33 @@ pub enum ErrorType {
     NoRecipient = 411,
     NoTextToSend = 412,
     NoOrigin = 409,
+    InvalidCapCmd = 410,
+    InputTooLong = 417,
     UnknownCommand = 421,
     NeedMoreParams = 461,
     NoSuchNick = 401,
     NoSuchChannel = 403,
+    CannotSendToChan = 404,
+    TooManyTargets = 407,
+    NicknameInUse = 433,
+    UserNotInChannel = 441,
+    NotOnChannel = 442,
+    NotRegistered = 451,
+    AlreadyRegistered = 462,
+    PasswdMismatch = 464,
+    UnknownMode = 472,
+    BannedFromChan = 474,
+    BadChannelKey = 475,
+    ChanOPrivsNeeded = 482,
+    YoureBannedCreep = 465,
+    NoPrivileges = 481,
+    NoOperHost = 491,
+    UModeUnknownFlag = 501,
+    UsersDontMatch = 502,
+    SaslFail = 904,
+    SaslTooLong = 905,
+    SaslAborted = 906,
+    SaslAlready = 907,
 }
 
 /// This is the name of your server, all messages originating from
@@ -20,61 +45,148 @@ pub enum ErrorType {
 pub const SERVER_NAME: &str = "iris-server";
 
 impl std::fmt::Display for ErrorType {
+    /// Writes the human-readable text of the error. Use `NumericReply::error`
+    /// to build the full line sent to a client.
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match *self {
-            ErrorType::NoNickNameGiven => {
-                write!(fmt, ":{SERVER_NAME} 431 :No nickname given.")
-            }
-            ErrorType::ErroneousNickname => {
-                // Typo is same as in RFC1459
-                write!(fmt, ":{SERVER_NAME} 432 :Erroneus nickname")
-            }
-            ErrorType::NoRecipient => {
-                write!(fmt, ":{SERVER_NAME} 411 :No recipient given")
-            }
-            ErrorType::NoTextToSend => {
-                write!(fmt, ":{SERVER_NAME} 412 :No text to send")
-            }
-            ErrorType::NoOrigin => {
-                write!(fmt, ":{SERVER_NAME} 409 :No origin specified")
-            }
-            ErrorType::UnknownCommand => {
-                write!(fmt, ":{SERVER_NAME} 421 :Unknown command")
-            }
-            ErrorType::NeedMoreParams => {
-                write!(fmt, ":{SERVER_NAME} 461 :Not enough parameters")
-            }
-            ErrorType::NoSuchNick => {
-                write!(fmt, ":{SERVER_NAME} 401 :No such nick/channel")
-            }
-            ErrorType::NoSuchChannel => {
-                write!(fmt, ":{SERVER_NAME} 403 :No such channel")
-            }
-            ErrorType::NickCollision => {
-                write!(fmt, ":{SERVER_NAME} 436 :Nickname collision")
+            ErrorType::NoNickNameGiven => write!(fmt, "No nickname given"),
+            // Typo is same as in RFC1459
+            ErrorType::ErroneousNickname => write!(fmt, "Erroneus nickname"),
+            ErrorType::NoRecipient => write!(fmt, "No recipient given"),
+            ErrorType::NoTextToSend => write!(fmt, "No text to send"),
+            ErrorType::NoOrigin => write!(fmt, "No origin specified"),
+            ErrorType::InvalidCapCmd => write!(fmt, "Invalid CAP command"),
+            ErrorType::InputTooLong => write!(fmt, "Input line was too long"),
+            ErrorType::UnknownCommand => write!(fmt, "Unknown command"),
+            ErrorType::NeedMoreParams => write!(fmt, "Not enough parameters"),
+            ErrorType::AlreadyRegistered => write!(fmt, "You may not reregister"),
+            ErrorType::NoSuchNick => write!(fmt, "No such nick/channel"),
+            ErrorType::NoSuchChannel => write!(fmt, "No such channel"),
+            ErrorType::CannotSendToChan => write!(fmt, "Cannot send to channel"),
+            ErrorType::TooManyTargets => write!(fmt, "Too many targets"),
+            ErrorType::UserNotInChannel => write!(fmt, "They aren't on that channel"),
+            ErrorType::NotOnChannel => write!(fmt, "You're not on that channel"),
+            ErrorType::NicknameInUse => write!(fmt, "Nickname is already in use"),
+            ErrorType::NickCollision => write!(fmt, "Nickname collision"),
+            ErrorType::NotRegistered => write!(fmt, "You have not registered"),
+            ErrorType::PasswdMismatch => write!(fmt, "Password incorrect"),
+            ErrorType::UnknownMode => write!(fmt, "is unknown mode char to me"),
+            ErrorType::BannedFromChan => write!(fmt, "Cannot join channel (+b)"),
+            ErrorType::BadChannelKey => write!(fmt, "Cannot join channel (+k)"),
+            ErrorType::ChanOPrivsNeeded => write!(fmt, "You're not channel operator"),
+            ErrorType::YoureBannedCreep => write!(fmt, "You are banned from this server"),
+            ErrorType::NoPrivileges => {
+                write!(fmt, "Permission Denied- You're not an IRC operator")
             }
+            ErrorType::NoOperHost => write!(fmt, "No O-lines for your host"),
+            ErrorType::UModeUnknownFlag => write!(fmt, "Unknown MODE flag"),
+            ErrorType::UsersDontMatch => write!(fmt, "Cant change mode for other users"),
+            ErrorType::SaslFail => write!(fmt, "SASL authentication failed"),
+            ErrorType::SaslTooLong => write!(fmt, "SASL message too long"),
+            ErrorType::SaslAborted => write!(fmt, "SASL authentication aborted"),
+            ErrorType::SaslAlready => write!(fmt, "You have already authenticated using SASL"),
         }
     }
 }
 
-/// Given an IRC command, this will split it up into component parts.
-/// Particularly, the prefix (optionally), then all space-separated args,
-/// then (optionally) the final argument.
-fn split_command(cmd: &str) -> Vec<&str> {
-    let stripped = cmd.strip_suffix("\r\n").unwrap_or(cmd);
+/// Numeric replies that are not errors.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ReplyType {
+    Welcome = 1,
+    ISupport = 5,
+    UModeIs = 221,
+    WhoisUser = 311,
+    WhoisServer = 312,
+    WhoisOperator = 313,
+    EndOfWho = 315,
+    EndOfWhois = 318,
+    WhoisChannels = 319,
+    WhoisAccount = 330,
+    ChannelModeIs = 324,
+    NoTopic = 331,
+    Topic = 332,
+    TopicWhoTime = 333,
+    WhoisBot = 335,
+    WhoReply = 352,
+    NamReply = 353,
+    EndOfNames = 366,
+    BanList = 367,
+    EndOfBanList = 368,
+    YoureOper = 381,
+    Rehashing = 382,
+    WhoisSecure = 671,
+    LoggedIn = 900,
+    SaslSuccess = 903,
+    SaslMechs = 908,
+}
 
-    match stripped.split_once(':') {
-        Some((before, after)) => {
-            let mut cmd_vec = before.split(' ').collect::<Vec<_>>();
+/// A numeric reply sent by the server to one client.
+/// Every numeric has the form `:server NNN <nick|*> <params> :text`, where
+/// `*` stands in for a client that has not chosen a nickname yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumericReply {
+    pub code: u16,
+    pub target: String,
+    pub params: Vec<String>,
+    pub text: String,
+}
 
-            if cmd_vec.last() == Some(&"") {
-                let _ = cmd_vec.pop();
-            }
+impl NumericReply {
+    pub fn new(code: u16, target: &str, params: &[&str], text: &str) -> Self {
+        NumericReply {
+            code,
+            target: target.to_string(),
+            params: params.iter().map(|param| param.to_string()).collect(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Builds the reply for `error`, using its standard text.
+    pub fn error(error: ErrorType, target: &str, params: &[&str]) -> Self {
+        Self::new(error as u16, target, params, &error.to_string())
+    }
+}
 
-            cmd_vec.push(after.trim_start());
-            cmd_vec
+impl std::fmt::Display for NumericReply {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let target = if self.target.is_empty() {
+            "*"
+        } else {
+            &self.target
+        };
+        write!(fmt, ":{SERVER_NAME} {:03} {target}", self.code)?;
+        for param in &self.params {
+            write!(fmt, " {param}")?;
         }
-        None => stripped.split(' ').collect::<Vec<_>>(),
+        write!(fmt, " :{}\r\n", self.text)
+    }
+}
+
+/// Writes `command` and its parameters, with `trailing` as the final `:`
+/// parameter so that it may contain spaces.
+fn write_command(
+    fmt: &mut std::fmt::Formatter<'_>,
+    command: &str,
+    params: &[&str],
+    trailing: Option<&str>,
+) -> Result<(), std::fmt::Error> {
+    write!(fmt, "{command}")?;
+    let (params, trailing) = match (trailing, params.split_last()) {
+        // Like `RawMessage`, write the last parameter as trailing whenever it
+        // could not be read back otherwise.
+        (None, Some((last, middle)))
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') =>
+        {
+            (middle, Some(*last))
+        }
+        _ => (params, trailing),
+    };
+    for param in params {
+        write!(fmt, " {param}")?;
+    }
+    match trailing {
+        Some(trailing) => write!(fmt, " :{trailing}"),
+        None => Ok(()),
     }
 }
 
@@ -112,7 +224,6 @@ impl TryFrom<String> for Nick {
     type Error = ErrorType;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        let value = value;
         if (1..10).contains(&value.len())
             && value.is_ascii()
             && value.chars().next().unwrap_or('!').is_alphabetic()
@@ -177,50 +288,71 @@ impl TryFrom<Vec<String>> for NickMsg {
     }
 }
 
-/// A message to join a channel.
-/// For example: `JOIN #channel\r\n`
+/// Splits a comma-separated parameter such as `#a,#b`, skipping empty items.
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+}
+
+/// A message to join channels, pairing each with the key at the same position.
+/// For example: `JOIN #a,#b key1\r\n`, or `JOIN 0\r\n` to leave every channel.
+/// Channel names are checked as each one is joined, so that one bad name
+/// does not stop the others.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct JoinMsg {
-    pub channel: Channel,
+pub enum JoinMsg {
+    Channels {
+        channels: Vec<Channel>,
+        keys: Vec<String>,
+    },
+    PartAll,
 }
 
 impl TryFrom<Vec<String>> for JoinMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(1)
-            .ok_or(ErrorType::NeedMoreParams)
-            .and_then(Channel::try_from)
-            .map(|channel| JoinMsg { channel })
+        let channels = value.get(1).ok_or(ErrorType::NeedMoreParams)?;
+        if channels == "0" {
+            return Ok(JoinMsg::PartAll);
+        }
+        Ok(JoinMsg::Channels {
+            channels: split_list(channels).map(Channel).collect(),
+            keys: value
+                .get(2)
+                .map(|keys| split_list(keys).collect())
+                .unwrap_or_default(),
+        })
     }
 }
 
-/// A message to leave a channel.
-/// For example: `PART #channel\r\n`
+/// A message to leave channels.
+/// For example: `PART #a,#b :Going home\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PartMsg {
-    pub channel: Channel,
+    pub channels: Vec<Channel>,
+    pub reason: Option<String>,
 }
 
 impl TryFrom<Vec<String>> for PartMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(1)
-            .ok_or(ErrorType::NeedMoreParams)
-            .and_then(Channel::try_from)
-            .map(|channel| PartMsg { channel })
+        Ok(PartMsg {
+            channels: split_list(value.get(1).ok_or(ErrorType::NeedMoreParams)?)
+                .map(Channel)
+                .collect(),
+            reason: value.into_iter().nth(2),
+        })
     }
 }
 
 /// A message to register a new user.
-// For example: `USER ignored ignored ignored :Thomas Kunc\r\n`
+// For example: `USER tfpk 0 * :Thomas Kunc\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UserMsg {
+    pub username: String,
     pub real_name: String,
 }
 
@@ -228,19 +360,20 @@ impl TryFrom<Vec<String>> for UserMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(4)
-            .ok_or(ErrorType::NeedMoreParams)
-            .map(|real_name| UserMsg { real_name })
+        let mut value = value.into_iter();
+        Ok(UserMsg {
+            username: value.nth(1).ok_or(ErrorType::NeedMoreParams)?,
+            // nth(2) here skips the mode and unused parameters.
+            real_name: value.nth(2).ok_or(ErrorType::NeedMoreParams)?,
+        })
     }
 }
 
-/// A private message.
-/// For example: `PRIVMSG tom :Hi Tom, how are you?\r\n`
+/// A private message to one or more users or channels.
+/// For example: `PRIVMSG tom,#team :Hi, how are you?\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PrivMsg {
-    pub target: Target,
+    pub targets: Vec<Target>,
     pub message: String,
 }
 
@@ -249,7 +382,9 @@ impl TryFrom<Vec<String>> for PrivMsg {
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
         Ok(PrivMsg {
-            target: Target::from(value.get(1).ok_or(ErrorType::NoRecipient)?.to_string()),
+            targets: split_list(value.get(1).ok_or(ErrorType::NoRecipient)?)
+                .map(Target::from)
+                .collect(),
             // skip(2) here skips the PRIVMSG instruction and target.
             message: value
                 .into_iter()
@@ -278,197 +413,821 @@ impl TryFrom<Vec<String>> for QuitMsg {
     }
 }
 
-/// A list of every possible message that can be sent.
+/// A request to become an IRC operator.
+/// For example: `OPER alice hunter2\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Message {
-    Nick(NickMsg),
-    User(UserMsg),
-    PrivMsg(PrivMsg),
-    Ping(String),
-    Join(JoinMsg),
-    Part(PartMsg),
-    Quit(QuitMsg),
+pub struct OperMsg {
+    pub name: String,
+    pub password: String,
 }
 
-/// To parse a message, construct this struct.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UnparsedMessage<'a> {
-    pub sender_nick: Nick,
-    pub message: &'a str,
+impl TryFrom<Vec<String>> for OperMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        Ok(OperMsg {
+            name: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            password: value.next().ok_or(ErrorType::NeedMoreParams)?,
+        })
+    }
 }
 
-/// After parsing an `UnparsedMessage`, this struct will be created.
+/// A web gateway passing on the address of the user it connects for, before
+/// registration.
+/// For example: `WEBIRC hunter2 kiwiirc user.example.com 192.0.2.1 :secure\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ParsedMessage {
-    pub sender_nick: Nick,
-    pub message: Message,
+pub struct WebircMsg {
+    pub password: String,
+    pub gateway: String,
+    pub hostname: String,
+    pub ip: String,
+    /// Flags and `key=value` options, such as `secure`.
+    pub options: Vec<String>,
 }
 
-impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
+impl WebircMsg {
+    /// Whether the user is connected to the gateway over TLS.
+    pub fn secure(&self) -> bool {
+        self.options.iter().any(|option| option == "secure")
+    }
+}
+
+impl TryFrom<Vec<String>> for WebircMsg {
     type Error = ErrorType;
-    fn try_from(value: UnparsedMessage<'a>) -> Result<Self, Self::Error> {
-        let command = split_command(value.message)
-            .into_iter()
-            .map(str::to_string)
-            .collect::<Vec<_>>();
-
-        let message = match command[0].as_str() {
-            "PING" => Ok(Message::Ping(
-                // Skip here ignores the "PING".
-                command
-                    .iter()
-                    .skip(1)
-                    .last()
-                    .ok_or(ErrorType::NoOrigin)?
-                    .to_string(),
-            )),
-            "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(command)?)),
-            "USER" => Ok(Message::User(UserMsg::try_from(command)?)),
-            "NICK" => Ok(Message::Nick(NickMsg::try_from(command)?)),
-            "JOIN" => Ok(Message::Join(JoinMsg::try_from(command)?)),
-            "PART" => Ok(Message::Part(PartMsg::try_from(command)?)),
-            "QUIT" => Ok(Message::Quit(QuitMsg::try_from(command)?)),
-            _ => Err(ErrorType::UnknownCommand),
-        }?;
-
-        Ok(ParsedMessage {
-            sender_nick: value.sender_nick,
-            message,
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        Ok(WebircMsg {
+            password: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            gateway: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            hostname: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            ip: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            options: value
+                .next()
+                .map(|options| options.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
         })
     }
 }
 
+/// An operator disconnecting a user.
+/// For example: `KILL tom :Flooding\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct PrivReply {
-    pub message: PrivMsg,
-    pub sender_nick: Nick,
+pub struct KillMsg {
+    pub target: String,
+    pub reason: String,
 }
 
+impl TryFrom<Vec<String>> for KillMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        Ok(KillMsg {
+            target: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            reason: value.next().ok_or(ErrorType::NeedMoreParams)?,
+        })
+    }
+}
+
+/// A K-line or D-line, lasting `minutes` or forever.
+/// For example: `KLINE 60 *@10.0.0.1 :Spamming\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct JoinReply {
-    pub message: JoinMsg,
-    pub sender_nick: Nick,
+pub struct BanMsg {
+    pub minutes: Option<i64>,
+    pub mask: String,
+    pub reason: Option<String>,
 }
 
+impl TryFrom<Vec<String>> for BanMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1).peekable();
+        let minutes = value.peek().and_then(|param| param.parse::<i64>().ok());
+        if minutes.is_some() {
+            value.next();
+        }
+        Ok(BanMsg {
+            minutes,
+            mask: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            reason: value.next(),
+        })
+    }
+}
+
+/// A query or change of modes.
+/// For example: `MODE tfpk +i\r\n` or `MODE #team +ov alice bob\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct PartReply {
-    pub message: PartMsg,
-    pub sender_nick: Nick,
+pub struct ModeMsg {
+    pub target: String,
+    pub modestring: Option<String>,
+    /// Arguments for the modes that take one, such as a nick for `+o`.
+    pub args: Vec<String>,
+}
+
+impl TryFrom<Vec<String>> for ModeMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        Ok(ModeMsg {
+            target: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            modestring: value.next(),
+            args: value.collect(),
+        })
+    }
 }
 
+/// A query or change of a channel's topic. An empty topic clears it.
+/// For example: `TOPIC #team :Release on Friday\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct QuitReply {
-    pub message: QuitMsg,
-    pub sender_nick: Nick,
+pub struct TopicMsg {
+    pub channel: String,
+    pub topic: Option<String>,
+}
+
+impl TryFrom<Vec<String>> for TopicMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        Ok(TopicMsg {
+            channel: value.next().ok_or(ErrorType::NeedMoreParams)?,
+            topic: value.next(),
+        })
+    }
 }
 
+/// A step of IRCv3 capability negotiation.
+/// For example: `CAP REQ :message-tags\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct WelcomeReply {
-    pub target_nick: Nick,
-    pub message: String,
+pub enum CapMsg {
+    /// `CAP LS [version]`: list the capabilities the server offers.
+    Ls(Option<u32>),
+    /// `CAP LIST`: list the capabilities the client has enabled.
+    List,
+    /// `CAP REQ`: enable, or with a leading `-` disable, capabilities.
+    Req(Vec<String>),
+    /// `CAP END`: negotiation is over and registration may finish.
+    End,
+    /// A line from the server, such as `CAP nick ACK :message-tags`.
+    /// `more` marks a line of a multi-line reply that is not the last.
+    Reply {
+        target: String,
+        subcommand: String,
+        more: bool,
+        caps: String,
+    },
 }
 
-/// Every possible reply to a message.
+impl TryFrom<Vec<String>> for CapMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let subcommand = value.next().ok_or(ErrorType::NeedMoreParams)?;
+        match subcommand.to_ascii_uppercase().as_str() {
+            "LS" => Ok(CapMsg::Ls(value.next().and_then(|v| v.parse().ok()))),
+            "LIST" => Ok(CapMsg::List),
+            "REQ" => {
+                let caps = value.next().ok_or(ErrorType::NeedMoreParams)?;
+                Ok(CapMsg::Req(
+                    caps.split_whitespace().map(str::to_string).collect(),
+                ))
+            }
+            "END" => Ok(CapMsg::End),
+            _ => Err(ErrorType::InvalidCapCmd),
+        }
+    }
+}
+
+/// The single parameter of `value`, or `error` if there is none.
+fn required_param(value: Vec<String>, error: ErrorType) -> Result<String, ErrorType> {
+    value.into_iter().nth(1).ok_or(error)
+}
+
+/// Every command the server understands.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Reply {
+pub enum Command {
+    Nick(NickMsg),
+    User(UserMsg),
+    PrivMsg(PrivMsg),
+    Notice(PrivMsg),
+    /// A message carrying only tags, such as a reaction.
+    TagMsg(Vec<Target>),
+    Cap(CapMsg),
+    /// One step of SASL: a mechanism name, a base64 chunk, `+` or `*`.
+    Authenticate(String),
+    Ping(String),
     Pong(String),
-    Welcome(WelcomeReply),
-    PrivMsg(PrivReply),
-    Join(JoinReply),
-    Part(PartReply),
-    Error(ErrorType),
-    Quit(QuitReply),
+    Join(JoinMsg),
+    Part(PartMsg),
+    Quit(QuitMsg),
+    Oper(OperMsg),
+    Webirc(WebircMsg),
+    Rehash,
+    Kill(KillMsg),
+    Wallops(String),
+    Globops(String),
+    KLine(BanMsg),
+    UnKLine(String),
+    DLine(BanMsg),
+    UnDLine(String),
+    Mode(ModeMsg),
+    Topic(TopicMsg),
+    Who(Option<String>),
+    Whois(String),
+    Names(Option<String>),
+    /// Sent by the server just before it closes a connection.
+    Error(String),
+    /// A command this server does not know, with its parameters.
+    Unknown {
+        command: String,
+        params: Vec<String>,
+    },
+}
+
+impl Command {
+    /// The command's verb, such as `PRIVMSG`.
+    pub fn name(&self) -> &str {
+        match self {
+            Command::Nick(_) => "NICK",
+            Command::User(_) => "USER",
+            Command::PrivMsg(_) => "PRIVMSG",
+            Command::Notice(_) => "NOTICE",
+            Command::TagMsg(_) => "TAGMSG",
+            Command::Cap(_) => "CAP",
+            Command::Authenticate(_) => "AUTHENTICATE",
+            Command::Ping(_) => "PING",
+            Command::Pong(_) => "PONG",
+            Command::Join(_) => "JOIN",
+            Command::Part(_) => "PART",
+            Command::Quit(_) => "QUIT",
+            Command::Oper(_) => "OPER",
+            Command::Webirc(_) => "WEBIRC",
+            Command::Rehash => "REHASH",
+            Command::Kill(_) => "KILL",
+            Command::Wallops(_) => "WALLOPS",
+            Command::Globops(_) => "GLOBOPS",
+            Command::KLine(_) => "KLINE",
+            Command::UnKLine(_) => "UNKLINE",
+            Command::DLine(_) => "DLINE",
+            Command::UnDLine(_) => "UNDLINE",
+            Command::Mode(_) => "MODE",
+            Command::Topic(_) => "TOPIC",
+            Command::Who(_) => "WHO",
+            Command::Whois(_) => "WHOIS",
+            Command::Names(_) => "NAMES",
+            Command::Error(_) => "ERROR",
+            Command::Unknown { command, .. } => command,
+        }
+    }
+
+    /// Whether a client may send `command` before it has registered.
+    pub fn allowed_before_registration(command: &str) -> bool {
+        matches!(
+            command,
+            "NICK" | "USER" | "CAP" | "AUTHENTICATE" | "PING" | "PONG" | "QUIT" | "WEBIRC"
+        )
+    }
 }
 
-impl std::fmt::Display for Reply {
+impl std::fmt::Display for Command {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let name = self.name();
+        let join_list = |items: Vec<String>| items.join(",");
+        match self {
+            Command::Nick(m) => write_command(fmt, name, &[&m.nick.0], None),
+            Command::User(m) => {
+                write_command(fmt, name, &[&m.username, "0", "*"], Some(&m.real_name))
+            }
+            Command::PrivMsg(m) | Command::Notice(m) => {
+                let targets = join_list(m.targets.iter().map(Target::to_string).collect());
+                write_command(fmt, name, &[&targets], Some(&m.message))
+            }
+            Command::TagMsg(targets) => {
+                let targets = join_list(targets.iter().map(Target::to_string).collect());
+                write_command(fmt, name, &[&targets], None)
+            }
+            Command::Cap(CapMsg::Ls(version)) => {
+                let version = version.map(|version| version.to_string());
+                let mut params = vec!["LS"];
+                params.extend(version.as_deref());
+                write_command(fmt, name, &params, None)
+            }
+            Command::Cap(CapMsg::List) => write_command(fmt, name, &["LIST"], None),
+            Command::Cap(CapMsg::Req(caps)) => {
+                write_command(fmt, name, &["REQ"], Some(&caps.join(" ")))
+            }
+            Command::Cap(CapMsg::End) => write_command(fmt, name, &["END"], None),
+            Command::Cap(CapMsg::Reply {
+                target,
+                subcommand,
+                more,
+                caps,
+            }) => {
+                let mut params = vec![target.as_str(), subcommand.as_str()];
+                if *more {
+                    params.push("*");
+                }
+                write_command(fmt, name, &params, Some(caps))
+            }
+            Command::Ping(origin) | Command::Pong(origin) => {
+                write_command(fmt, name, &[], Some(origin))
+            }
+            Command::Join(JoinMsg::PartAll) => write_command(fmt, name, &["0"], None),
+            Command::Join(JoinMsg::Channels { channels, keys }) => {
+                let channels = join_list(channels.iter().map(Channel::to_string).collect());
+                let keys = keys.join(",");
+                let params = if keys.is_empty() {
+                    vec![channels.as_str()]
+                } else {
+                    vec![channels.as_str(), keys.as_str()]
+                };
+                write_command(fmt, name, &params, None)
+            }
+            Command::Part(m) => {
+                let channels = join_list(m.channels.iter().map(Channel::to_string).collect());
+                write_command(fmt, name, &[&channels], m.reason.as_deref())
+            }
+            Command::Quit(m) => write_command(fmt, name, &[], m.message.as_deref()),
+            Command::Oper(m) => write_command(fmt, name, &[&m.name, &m.password], None),
+            Command::Webirc(m) => {
+                let params = [&m.password, &m.gateway, &m.hostname, &m.ip].map(String::as_str);
+                let options = m.options.join(" ");
+                let options = Some(options.as_str()).filter(|options| !options.is_empty());
+                write_command(fmt, name, &params, options)
+            }
+            Command::Kill(m) => write_command(fmt, name, &[&m.target], Some(&m.reason)),
+            Command::Wallops(text) | Command::Globops(text) | Command::Error(text) => {
+                write_command(fmt, name, &[], Some(text))
+            }
+            Command::KLine(m) | Command::DLine(m) => {
+                let minutes = m.minutes.map(|minutes| minutes.to_string());
+                let mut params = minutes.as_deref().into_iter().collect::<Vec<_>>();
+                params.push(&m.mask);
+                write_command(fmt, name, &params, m.reason.as_deref())
+            }
+            Command::UnKLine(mask)
+            | Command::UnDLine(mask)
+            | Command::Whois(mask)
+            | Command::Authenticate(mask) => write_command(fmt, name, &[mask], None),
+            Command::Mode(m) => {
+                let mut params = vec![m.target.as_str()];
+                params.extend(m.modestring.as_deref());
+                params.extend(m.args.iter().map(String::as_str));
+                write_command(fmt, name, &params, None)
+            }
+            Command::Topic(m) => write_command(fmt, name, &[&m.channel], m.topic.as_deref()),
+            Command::Who(mask) | Command::Names(mask) => write_command(
+                fmt,
+                name,
+                &mask.as_deref().into_iter().collect::<Vec<_>>(),
+                None,
+            ),
+            Command::Rehash => write_command(fmt, name, &[], None),
+            Command::Unknown { params, .. } => {
+                let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+                write_command(fmt, name, &params, None)
+            }
+        }
+    }
+}
+
+/// A line that could not be turned into a `Message`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    /// The line does not follow the IRC grammar.
+    Syntax(ParseError),
+    /// The command's parameters are missing or invalid.
+    Command {
+        /// The verb of the rejected line, such as `JOIN`.
+        command: String,
+        error: ErrorType,
+        /// The parameters of the numeric reply, such as the rejected nickname.
+        params: Vec<String>,
+    },
+}
+
+impl MessageError {
+    /// The verb of the rejected line, if it got far enough to have one.
+    pub fn command(&self) -> Option<&str> {
         match self {
-            Reply::Pong(p) => write!(fmt, "PONG :{p}\r\n"),
-            Reply::Welcome(r) => {
-                let nick = &r.target_nick;
-                let message = &r.message;
-                write!(fmt, ":{SERVER_NAME} 001 {nick} :{message}\r\n")
+            MessageError::Syntax(_) => None,
+            MessageError::Command { command, .. } => Some(command),
+        }
+    }
+
+    /// The numeric reply telling the client `target` about this error.
+    /// Lines that are not IRC at all get no reply.
+    pub fn reply(&self, target: &str) -> Option<NumericReply> {
+        match self {
+            MessageError::Syntax(_) => None,
+            MessageError::Command { error, params, .. } => {
+                let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+                Some(NumericReply::error(*error, target, &params))
             }
-            Reply::PrivMsg(r) => {
-                let nick = &r.message.target;
-                let message = &r.message.message;
-                let from = &r.sender_nick;
-                write!(fmt, ":{from} PRIVMSG {nick} :{message}\r\n")
+        }
+    }
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            MessageError::Syntax(error) => write!(fmt, "{error}"),
+            MessageError::Command { command, error, .. } => write!(fmt, "{command}: {error}"),
+        }
+    }
+}
+
+/// One IRC message: optional IRCv3 tags and source prefix, and a command.
+/// Parse client lines with `Message::parse`, and serialize messages for
+/// the wire with `Display`, which adds the trailing `\r\n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub tags: Vec<Tag>,
+    pub prefix: Option<String>,
+    pub command: Command,
+}
+
+impl Message {
+    /// A message with no tags or prefix, such as one the server sends about
+    /// the connection itself.
+    pub fn new(command: Command) -> Self {
+        Message {
+            tags: Vec::new(),
+            prefix: None,
+            command,
+        }
+    }
+
+    /// A message from `prefix`, such as a client's `nick!user@host`.
+    pub fn with_prefix(prefix: &str, command: Command) -> Self {
+        Message {
+            tags: Vec::new(),
+            prefix: Some(prefix.to_string()),
+            command,
+        }
+    }
+
+    /// Parses and validates one line.
+    pub fn parse(line: &str) -> Result<Message, MessageError> {
+        let raw = RawMessage::parse(line).map_err(MessageError::Syntax)?;
+        Message::try_from(raw)
+    }
+}
+
+impl TryFrom<RawMessage> for Message {
+    type Error = MessageError;
+
+    fn try_from(raw: RawMessage) -> Result<Self, Self::Error> {
+        let command_name = raw.command;
+        let mut command = vec![command_name.clone()];
+        command.extend(raw.params);
+
+        // Errors about a missing parameter name the command; others name the
+        // parameter that was rejected.
+        let to_error = |error: ErrorType, command: &[String]| {
+            let param = match error {
+                ErrorType::NeedMoreParams | ErrorType::NoRecipient | ErrorType::NoTextToSend => {
+                    Some(command_name.clone())
+                }
+                ErrorType::NoNickNameGiven | ErrorType::NoOrigin => None,
+                _ => command.get(1).cloned(),
+            };
+            MessageError::Command {
+                command: command_name.clone(),
+                error,
+                params: param.into_iter().collect(),
             }
-            Reply::Error(e) => {
-                write!(fmt, ":{SERVER_NAME} {e}\r\n")
+        };
+        let parsed = match command_name.as_str() {
+            "NICK" => NickMsg::try_from(command.clone()).map(Command::Nick),
+            "USER" => UserMsg::try_from(command.clone()).map(Command::User),
+            "PRIVMSG" => PrivMsg::try_from(command.clone()).map(Command::PrivMsg),
+            "NOTICE" => PrivMsg::try_from(command.clone()).map(Command::Notice),
+            "TAGMSG" => command
+                .get(1)
+                .map(|targets| Command::TagMsg(split_list(targets).map(Target::from).collect()))
+                .ok_or(ErrorType::NoRecipient),
+            "CAP" => CapMsg::try_from(command.clone()).map(Command::Cap),
+            "AUTHENTICATE" => required_param(command.clone(), ErrorType::NeedMoreParams)
+                .map(Command::Authenticate),
+            "PING" => required_param(command.clone(), ErrorType::NoOrigin).map(Command::Ping),
+            "PONG" => required_param(command.clone(), ErrorType::NoOrigin).map(Command::Pong),
+            "JOIN" => JoinMsg::try_from(command.clone()).map(Command::Join),
+            "PART" => PartMsg::try_from(command.clone()).map(Command::Part),
+            "QUIT" => QuitMsg::try_from(command.clone()).map(Command::Quit),
+            "OPER" => OperMsg::try_from(command.clone()).map(Command::Oper),
+            "WEBIRC" => WebircMsg::try_from(command.clone()).map(Command::Webirc),
+            "REHASH" => Ok(Command::Rehash),
+            "KILL" => KillMsg::try_from(command.clone()).map(Command::Kill),
+            "WALLOPS" => {
+                required_param(command.clone(), ErrorType::NeedMoreParams).map(Command::Wallops)
             }
-            Reply::Join(r) => {
-                let sender = &r.sender_nick;
-                let channel = &r.message.channel;
-                write!(fmt, ":{sender} JOIN {channel}\r\n")
+            "GLOBOPS" => {
+                required_param(command.clone(), ErrorType::NeedMoreParams).map(Command::Globops)
             }
-            Reply::Part(r) => {
-                let sender = &r.sender_nick;
-                let channel = &r.message.channel;
-                write!(fmt, ":{sender} PART {channel}\r\n")
+            "KLINE" => BanMsg::try_from(command.clone()).map(Command::KLine),
+            "UNKLINE" => {
+                required_param(command.clone(), ErrorType::NeedMoreParams).map(Command::UnKLine)
             }
-            Reply::Quit(r) => {
-                let sender = &r.sender_nick.to_string();
-                let message = &r.message.message.as_ref().unwrap_or(sender);
-                write!(fmt, ":{sender} QUIT :{message}\r\n")
+            "DLINE" => BanMsg::try_from(command.clone()).map(Command::DLine),
+            "UNDLINE" => {
+                required_param(command.clone(), ErrorType::NeedMoreParams).map(Command::UnDLine)
             }
+            "MODE" => ModeMsg::try_from(command.clone()).map(Command::Mode),
+            "TOPIC" => TopicMsg::try_from(command.clone()).map(Command::Topic),
+            "WHO" => Ok(Command::Who(command.get(1).cloned())),
+            // WHOIS may name a server before the nick; only the nick is used.
+            "WHOIS" => command
+                .get(1..)
+                .and_then(<[String]>::last)
+                .cloned()
+                .ok_or(ErrorType::NoNickNameGiven)
+                .map(Command::Whois),
+            "NAMES" => Ok(Command::Names(command.get(1).cloned())),
+            "ERROR" => Ok(Command::Error(command.get(1).cloned().unwrap_or_default())),
+            _ => Ok(Command::Unknown {
+                command: command_name.clone(),
+                params: command[1..].to_vec(),
+            }),
+        };
+
+        parsed
+            .map(|parsed| Message {
+                tags: raw.tags,
+                prefix: raw.prefix,
+                command: parsed,
+            })
+            .map_err(|error| to_error(error, &command))
+    }
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write_tags(fmt, &self.tags)?;
+        if let Some(prefix) = &self.prefix {
+            write!(fmt, ":{prefix} ")?;
         }
+        write!(fmt, "{}\r\n", self.command)
     }
 }
 
+#[cfg(test)]
 mod tests {
     #[allow(unused_imports)]
     use super::*;
+    use proptest::prelude::*;
+
+    #[allow(dead_code)]
+    fn parse(line: &str) -> Result<Command, MessageError> {
+        Message::parse(line).map(|message| message.command)
+    }
 
     #[test]
     fn test_ping() {
         assert_eq!(
-            ParsedMessage::try_from(UnparsedMessage {
-                message: "PING :host-name with space\r\n",
-                sender_nick: Nick("Person".to_string())
-            })
-            .unwrap()
-            .message,
-            Message::Ping("host-name with space".to_string())
+            parse("PING :host-name with space\r\n"),
+            Ok(Command::Ping("host-name with space".to_string()))
         )
     }
 
     #[test]
     fn test_privmsg() {
         assert_eq!(
-            ParsedMessage::try_from(UnparsedMessage {
-                message: "PRIVMSG tom :Hi Tom, how are you?\r\n",
-                sender_nick: Nick("Person".to_string())
-            })
-            .unwrap()
-            .message,
-            Message::PrivMsg(PrivMsg {
-                target: Target::User(Nick("tom".to_string())),
+            parse("PRIVMSG tom :Hi Tom, how are you?\r\n"),
+            Ok(Command::PrivMsg(PrivMsg {
+                targets: vec![Target::User(Nick("tom".to_string()))],
                 message: "Hi Tom, how are you?".to_string()
-            })
+            }))
+        );
+        assert_eq!(
+            parse("PRIVMSG tom,#team :hi\r\n"),
+            Ok(Command::PrivMsg(PrivMsg {
+                targets: vec![
+                    Target::User(Nick("tom".to_string())),
+                    Target::Channel(Channel("#team".to_string()))
+                ],
+                message: "hi".to_string()
+            }))
         )
     }
 
     #[test]
     fn test_nick() {
         assert_eq!(
-            ParsedMessage::try_from(UnparsedMessage {
-                message: "NICK tfpk\r\n",
-                sender_nick: Nick("Person".to_string())
-            })
-            .unwrap()
-            .message,
-            Message::Nick(NickMsg {
+            parse("NICK tfpk\r\n"),
+            Ok(Command::Nick(NickMsg {
                 nick: Nick("tfpk".to_string())
+            }))
+        );
+        assert_eq!(
+            parse("NICK tfpkasdfasdfasdf\r\n"),
+            Err(MessageError::Command {
+                command: "NICK".to_string(),
+                error: ErrorType::ErroneousNickname,
+                params: vec!["tfpkasdfasdfasdf".to_string()]
             })
         );
+    }
+
+    #[test]
+    fn test_cap() {
         assert_eq!(
-            ParsedMessage::try_from(UnparsedMessage {
-                message: "NICK tfpkasdfasdfasdf\r\n",
-                sender_nick: Nick("Person".to_string())
-            }),
-            Err(ErrorType::ErroneousNickname)
+            parse("CAP LS 302\r\n"),
+            Ok(Command::Cap(CapMsg::Ls(Some(302))))
+        );
+        assert_eq!(
+            parse("CAP REQ :message-tags -cap-notify\r\n"),
+            Ok(Command::Cap(CapMsg::Req(vec![
+                "message-tags".to_string(),
+                "-cap-notify".to_string()
+            ])))
+        );
+        assert_eq!(
+            parse("CAP FOO\r\n")
+                .unwrap_err()
+                .reply("")
+                .unwrap()
+                .to_string(),
+            ":iris-server 410 * FOO :Invalid CAP command\r\n"
+        );
+        let reply = Command::Cap(CapMsg::Reply {
+            target: "*".to_string(),
+            subcommand: "LS".to_string(),
+            more: true,
+            caps: "message-tags".to_string(),
+        });
+        assert_eq!(reply.to_string(), "CAP * LS * :message-tags");
+    }
+
+    #[test]
+    fn test_webirc() {
+        let Ok(Command::Webirc(webirc)) =
+            parse("WEBIRC hunter2 kiwiirc user.example.com 192.0.2.1 :secure remote-port=5555\r\n")
+        else {
+            panic!("WEBIRC did not parse");
+        };
+        assert_eq!(webirc.hostname, "user.example.com");
+        assert_eq!(webirc.ip, "192.0.2.1");
+        assert!(webirc.secure());
+        let Ok(Command::Webirc(webirc)) = parse("WEBIRC hunter2 kiwiirc host 2001:db8::1\r\n")
+        else {
+            panic!("WEBIRC did not parse");
+        };
+        assert!(!webirc.secure());
+        assert!(parse("WEBIRC hunter2 kiwiirc host\r\n").is_err());
+    }
+
+    #[test]
+    fn test_join_lists() {
+        assert_eq!(
+            parse("JOIN #a,#b key1\r\n"),
+            Ok(Command::Join(JoinMsg::Channels {
+                channels: vec![Channel("#a".to_string()), Channel("#b".to_string())],
+                keys: vec!["key1".to_string()]
+            }))
+        );
+        assert_eq!(parse("JOIN 0\r\n"), Ok(Command::Join(JoinMsg::PartAll)));
+        assert_eq!(
+            parse("PART #a,#b :Going home\r\n"),
+            Ok(Command::Part(PartMsg {
+                channels: vec![Channel("#a".to_string()), Channel("#b".to_string())],
+                reason: Some("Going home".to_string())
+            }))
+        );
+    }
+
+    #[test]
+    fn test_message_errors() {
+        assert_eq!(
+            parse("JOIN\r\n")
+                .unwrap_err()
+                .reply("tfpk")
+                .unwrap()
+                .to_string(),
+            ":iris-server 461 tfpk JOIN :Not enough parameters\r\n"
+        );
+        assert_eq!(
+            parse("NICK 1abc\r\n")
+                .unwrap_err()
+                .reply("")
+                .unwrap()
+                .to_string(),
+            ":iris-server 432 * 1abc :Erroneus nickname\r\n"
         );
+        assert_eq!(
+            parse("frobnicate x :y z\r\n"),
+            Ok(Command::Unknown {
+                command: "FROBNICATE".to_string(),
+                params: vec!["x".to_string(), "y z".to_string()]
+            })
+        );
+        assert_eq!(
+            parse("  \r\n"),
+            Err(MessageError::Syntax(ParseError::Empty))
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let line = ":tom!tom@localhost PRIVMSG #team :hi there\r\n";
+        let message = Message::parse(line).unwrap();
+        assert_eq!(message.prefix.as_deref(), Some("tom!tom@localhost"));
+        assert_eq!(message.to_string(), line);
+        assert_eq!(
+            Message::with_prefix(
+                "tom",
+                Command::Part(PartMsg {
+                    channels: vec![Channel("#team".to_string())],
+                    reason: None
+                })
+            )
+            .to_string(),
+            ":tom PART #team\r\n"
+        );
+    }
+
+    #[test]
+    fn test_numeric_reply() {
+        assert_eq!(
+            NumericReply::error(ErrorType::NeedMoreParams, "tfpk", &["JOIN"]).to_string(),
+            ":iris-server 461 tfpk JOIN :Not enough parameters\r\n"
+        );
+        assert_eq!(
+            NumericReply::error(ErrorType::ErroneousNickname, "", &["1abc"]).to_string(),
+            ":iris-server 432 * 1abc :Erroneus nickname\r\n"
+        );
+    }
+
+    /// Lines with a known verb or an unknown one, and parameters that may
+    /// or may not suit it.
+    fn line() -> impl Strategy<Value = String> {
+        let verb = prop_oneof![
+            proptest::sample::select(vec![
+                "NICK",
+                "USER",
+                "PRIVMSG",
+                "NOTICE",
+                "TAGMSG",
+                "CAP",
+                "AUTHENTICATE",
+                "PING",
+                "PONG",
+                "JOIN",
+                "PART",
+                "QUIT",
+                "OPER",
+                "WEBIRC",
+                "REHASH",
+                "KILL",
+                "WALLOPS",
+                "GLOBOPS",
+                "KLINE",
+                "UNKLINE",
+                "DLINE",
+                "UNDLINE",
+                "MODE",
+                "TOPIC",
+                "WHO",
+                "WHOIS",
+                "NAMES",
+                "ERROR",
+            ])
+            .prop_map(String::from),
+            "[A-Z]{1,8}",
+        ];
+        let middle = "[^ :\0\r\n][^ \0\r\n]{0,8}|#[a-z]{1,4}|[0-9]{1,3}|[+-][a-z]{1,3}";
+        (
+            proptest::option::of("[a-z]{1,8}!u@h"),
+            verb,
+            proptest::collection::vec(middle, 0..6),
+            proptest::option::of("[^\0\r\n]{0,16}"),
+        )
+            .prop_map(|(prefix, verb, params, trailing)| {
+                let mut line = prefix
+                    .map(|prefix| format!(":{prefix} "))
+                    .unwrap_or_default();
+                line.push_str(&verb);
+                for param in params {
+                    line.push_str(&format!(" {param}"));
+                }
+                if let Some(trailing) = trailing {
+                    line.push_str(&format!(" :{trailing}"));
+                }
+                line
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_message_round_trip(line in line()) {
+            if let Ok(message) = Message::parse(&line) {
+                prop_assert_eq!(Message::parse(&message.to_string()), Ok(message));
+            }
+        }
     }
 }
@@ -0,0 +1,251 @@
+// src/lib/websocket.rs
+//! IRC over WebSocket, as the IRCv3 WebSocket spec describes it: every
+//! frame carries one IRC line, without its CRLF.
+//!
+//! `WebSocketLines` turns frames back into the CRLF-terminated byte stream
+//! that `ConnectionRead` and `ConnectionWrite` expect, so a WebSocket client
+//! goes through the same pipeline as one on a plain socket.
+use futures_core::Stream as _;
+use futures_sink::Sink;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Lines travel in text frames, and must be UTF-8.
+pub const TEXT_PROTOCOL: &str = "text.ircv3.net";
+/// Lines travel in binary frames, in whatever encoding the client uses.
+pub const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+/// Room for the longest line with tags. A longer frame ends the connection.
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+/// The kind of frame lines are sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Text,
+    Binary,
+}
+
+/// The first subprotocol the client offers that the server speaks, if any.
+fn choose_protocol(request: &Request) -> Option<&'static str> {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|offered| match offered.trim() {
+            TEXT_PROTOCOL => Some(TEXT_PROTOCOL),
+            BINARY_PROTOCOL => Some(BINARY_PROTOCOL),
+            _ => None,
+        })
+}
+
+/// Performs the WebSocket handshake on `stream`. Clients that ask for no
+/// subprotocol get text frames, which is what browsers handle best.
+pub async fn accept<S>(stream: S) -> Result<WebSocketLines<S>, tungstenite::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut protocol = None;
+    // The error type is tungstenite's to choose.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| {
+        protocol = choose_protocol(request);
+        if let Some(protocol) = protocol {
+            let value = HeaderValue::from_static(protocol);
+            response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+        }
+        Ok::<_, ErrorResponse>(response)
+    };
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(MAX_FRAME_LEN))
+        .max_frame_size(Some(MAX_FRAME_LEN));
+    let inner =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config)).await?;
+    let kind = match protocol {
+        Some(BINARY_PROTOCOL) => FrameKind::Binary,
+        _ => FrameKind::Text,
+    };
+    Ok(WebSocketLines::new(inner, kind))
+}
+
+/// A WebSocket connection read and written as a stream of CRLF-terminated
+/// lines.
+pub struct WebSocketLines<S> {
+    inner: WebSocketStream<S>,
+    kind: FrameKind,
+    /// Lines received but not read yet, each ending in CRLF.
+    received: Vec<u8>,
+    /// Bytes written but not sent yet, since they do not end in a line
+    /// break or were not flushed.
+    unsent: Vec<u8>,
+}
+
+impl<S> WebSocketLines<S> {
+    pub fn new(inner: WebSocketStream<S>, kind: FrameKind) -> Self {
+        Self {
+            inner,
+            kind,
+            received: Vec::new(),
+            unsent: Vec::new(),
+        }
+    }
+
+    /// Queues the payload of one frame to be read as a line. A frame with a
+    /// line break or NUL inside it would smuggle in a second line, so it
+    /// ends the connection.
+    fn receive(&mut self, payload: &[u8]) -> io::Result<()> {
+        // Frames should not end in a line break, but some clients add one.
+        let line = trim_line_break(payload);
+        if line
+            .iter()
+            .any(|&byte| matches!(byte, b'\r' | b'\n' | b'\0'))
+        {
+            let err = "WebSocket frame holds a line break or NUL";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        if !line.is_empty() {
+            self.received.extend_from_slice(line);
+            self.received.extend_from_slice(b"\r\n");
+        }
+        Ok(())
+    }
+
+    /// A frame for one line written by the server.
+    fn frame(&self, line: &[u8]) -> Message {
+        match self.kind {
+            FrameKind::Text => Message::text(String::from_utf8_lossy(line).into_owned()),
+            FrameKind::Binary => Message::binary(line.to_vec()),
+        }
+    }
+}
+
+fn trim_line_break(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|&byte| byte != b'\r' && byte != b'\n')
+        .map_or(0, |last| last + 1);
+    &line[..end]
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketLines<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.received.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => this.receive(text.as_bytes())?,
+                Some(Ok(Message::Binary(data))) => this.receive(&data)?,
+                // Reading nothing is how the end of the stream is reported.
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // Pings are answered by the WebSocket itself.
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+            }
+        }
+        let len = buf.remaining().min(this.received.len());
+        buf.put_slice(&this.received[..len]);
+        this.received.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketLines<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.unsent.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Sends every complete line as a frame of its own.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while let Some(end) = this.unsent.iter().position(|&byte| byte == b'\n') {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(io::Error::other)?;
+            let line = this.unsent.drain(..=end).collect::<Vec<_>>();
+            let frame = this.frame(trim_line_break(&line));
+            Pin::new(&mut this.inner)
+                .start_send(frame)
+                .map_err(io::Error::other)?;
+        }
+        Pin::new(&mut this.inner)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    #[tokio::test]
+    async fn test_frames_are_lines() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut server = WebSocketLines::new(server, FrameKind::Binary);
+
+        let text = Message::text("NICK a\r\n");
+        Pin::new(&mut client).start_send(text).unwrap();
+        let binary = Message::binary(b"NICK b".to_vec());
+        Pin::new(&mut client).start_send(binary).unwrap();
+        std::future::poll_fn(|cx| Pin::new(&mut client).poll_flush(cx))
+            .await
+            .unwrap();
+        let mut read = [0; 16];
+        server.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"NICK a\r\nNICK b\r\n");
+
+        server.write_all(b"PING :x\r\nPI").await.unwrap();
+        server.flush().await.unwrap();
+        let frame = std::future::poll_fn(|cx| Pin::new(&mut client).poll_next(cx)).await;
+        assert_eq!(
+            frame.unwrap().unwrap(),
+            Message::binary(b"PING :x".to_vec())
+        );
+        assert_eq!(server.unsent, b"PI");
+    }
+
+    #[tokio::test]
+    async fn test_frames_with_several_lines_are_refused() {
+        for payload in ["PRIVMSG a :b\r\nQUIT", "NICK a\nQUIT\n", "NICK a\0b"] {
+            let (client, server) = tokio::io::duplex(1024);
+            let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+            let mut server = WebSocketLines::new(server, FrameKind::Text);
+
+            Pin::new(&mut client)
+                .start_send(Message::text(payload))
+                .unwrap();
+            std::future::poll_fn(|cx| Pin::new(&mut client).poll_flush(cx))
+                .await
+                .unwrap();
+            let mut read = [0; 16];
+            let err = server.read(&mut read).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -5,23 +5,108 @@ use crate::arguments::Arguments;
 use crate::client_loop::handle_client_loop;
 use crate::user_input::spawn_user_input_thread;
 use clap::Parser;
-use iris_lib::{connect::ConnectionManager, ircs::IrcServer, types::SERVER_NAME};
-use std::net::IpAddr;
+use iris_lib::{
+    config::Config,
+    connect::ConnectionManager,
+    ircs::{bans::valid_cidr, IrcServer},
+    password::hash_password,
+    storage::{FileStorage, SharedStorage, State, Storage},
+    tls::TlsContext,
+    types::SERVER_NAME,
+};
+use std::net::SocketAddr;
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::thread;
 use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() {
     let arguments = Arguments::parse();
-    println!(
-        "Launching {} at {}:{}",
-        SERVER_NAME, arguments.ip_address, arguments.port
-    );
+    if arguments.mkpasswd {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password).unwrap();
+        println!("{}", hash_password(password.trim_end_matches(['\r', '\n'])));
+        return;
+    }
 
-    let connection_manager = ConnectionManager::launch(arguments.ip_address, arguments.port).await;
-    let irc_server = IrcServer::new();
+    let config = match &arguments.config {
+        Some(path) => Config::load(path).unwrap_or_else(|err| {
+            eprintln!("{}: {err}", path.display());
+            std::process::exit(1);
+        }),
+        None => Config::default(),
+    };
+
+    println!("Launching {}", SERVER_NAME);
+
+    let (state, storage) = match &config.data_dir {
+        Some(dir) => {
+            let mut storage = FileStorage::new(dir);
+            let state = storage.load().unwrap_or_else(|err| {
+                eprintln!("{}: {err}", dir.display());
+                std::process::exit(1);
+            });
+            let storage: SharedStorage = Arc::new(std::sync::Mutex::new(storage));
+            (state, Some(storage))
+        }
+        None => (State::default(), None),
+    };
+
+    let tls = config.tls.as_ref().map(|tls_config| {
+        let tls = TlsContext::load(tls_config).unwrap_or_else(|err| {
+            eprintln!("{}: {err}", tls_config.cert.display());
+            std::process::exit(1);
+        });
+        Arc::new(tls)
+    });
+    for gateway in &config.webirc_gateways {
+        if !gateway.hosts.iter().all(|mask| valid_cidr(mask)) {
+            eprintln!("WebIRC gateway {} has an invalid host", gateway.name);
+            std::process::exit(1);
+        }
+    }
+    let listeners = config.listeners(arguments.ip_address, arguments.port);
+    #[cfg(unix)]
+    let unix_listeners = config.unix_listeners.clone();
+
+    let mut irc_server = IrcServer::with_storage(config, arguments.config, state, storage);
+    let mut connection_manager = ConnectionManager::new(irc_server.bans());
+    for listener in &listeners {
+        let address = SocketAddr::new(listener.address, listener.port);
+        if listener.proxy
+            && (listener.trusted_proxies.is_empty()
+                || !listener.trusted_proxies.iter().all(|mask| valid_cidr(mask)))
+        {
+            eprintln!("listener {address} uses PROXY, but trusted_proxies is empty or invalid");
+            std::process::exit(1);
+        }
+        let tls = match (listener.tls, &tls) {
+            (false, _) => None,
+            (true, Some(tls)) => Some(Arc::clone(tls)),
+            (true, None) => {
+                eprintln!("listener {address} uses TLS, but there is no [tls] section");
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = connection_manager.listen(listener, tls) {
+            eprintln!("failed to listen on {address}: {err}");
+            std::process::exit(1);
+        }
+        let kind = if listener.tls { "TLS" } else { "plaintext" };
+        println!("Accepting {kind} connections at {address}");
+    }
+    #[cfg(unix)]
+    for listener in &unix_listeners {
+        let path = listener.path.display();
+        if let Err(err) = connection_manager.listen_unix(listener) {
+            eprintln!("failed to listen on {path}: {err}");
+            std::process::exit(1);
+        }
+        println!("Accepting local connections at {path}");
+    }
+    if let Some(tls) = tls {
+        irc_server.set_tls(tls);
+    }
 
     let shared_connection_manager = Arc::new(Mutex::new(connection_manager));
     let shared_irc_server = Arc::new(Mutex::new(irc_server));
@@ -1,4 +1,4 @@
-use std::sync::mpsc::{self, Sender};
+use std::sync::mpsc::Sender;
 use std::thread;
 
 pub fn spawn_user_input_thread(tx: Sender<String>) -> thread::JoinHandle<()> {